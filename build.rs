@@ -0,0 +1,16 @@
+use std::env::var;
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/memory/seh.c");
+
+    // The SEH wrappers need the MSVC __try/__except extension
+    if var("CARGO_CFG_TARGET_ENV").as_deref() != Ok("msvc") {
+        return;
+    }
+
+    cc::Build::new()
+        .file("src/memory/seh.c")
+        .flag("/kernel")
+        .flag("/GS-")
+        .compile("wduseh");
+}
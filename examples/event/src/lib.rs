@@ -18,10 +18,10 @@ use win_drvutils_rs::{
     encode_ioctl,
     io::{
         create::WduCreate,
-        device::{WduDevice, WduDeviceChars, WduDeviceError, WduDeviceType},
+        device::{WduDevice, WduDeviceChars, WduDeviceError, WduDeviceType, WduIoMethod},
         device_control::WduDeviceControl,
         file_obj::WduFileObject,
-        irp::{WduIoStatus, WduIrp},
+        irp::{WduIoStatus, WduIrp, WduIrpDisposition},
     },
     memory::pool::SimpleAlloc,
     strings::unicode::{str::WduUnicodeStr, WduUnicodeError},
@@ -30,11 +30,12 @@ use win_drvutils_rs::{
 };
 
 use windows_sys::{
-    Wdk::{Foundation::DRIVER_OBJECT, Storage::FileSystem::DO_BUFFERED_IO},
+    Wdk::Foundation::DRIVER_OBJECT,
     Win32::{
         Foundation::{
-            HANDLE, NTSTATUS, STATUS_CANCELLED, STATUS_INVALID_PARAMETER, STATUS_NOT_IMPLEMENTED,
-            STATUS_PENDING, STATUS_SUCCESS, STATUS_UNSUCCESSFUL, UNICODE_STRING,
+            HANDLE, NTSTATUS, STATUS_CANCELLED, STATUS_INSUFFICIENT_RESOURCES,
+            STATUS_INVALID_PARAMETER, STATUS_NOT_IMPLEMENTED, STATUS_PENDING, STATUS_SUCCESS,
+            STATUS_UNSUCCESSFUL, UNICODE_STRING,
         },
         System::Ioctl::{FILE_ANY_ACCESS, FILE_DEVICE_UNKNOWN, METHOD_BUFFERED},
     },
@@ -165,15 +166,16 @@ struct FileContext {
     file_rundown: WduRemoveLock,
 }
 
+#[derive(Default)]
 struct DeviceExtension {
     event_queue: EventQueue,
-    queue_lock: WduSpinLock,
+    queue_lock: WduSpinLock, // Default will initialize the spinlock!
 }
 
 #[no_mangle]
 pub extern "system" fn DriverEntry(
     driver_object: *mut DRIVER_OBJECT,
-    _registry_path: *const UNICODE_STRING,
+    registry_path: *const UNICODE_STRING,
 ) -> NTSTATUS {
     KernelLogger::init(LevelFilter::Info).expect("Failed to initialize logger");
 
@@ -181,7 +183,7 @@ pub extern "system" fn DriverEntry(
         GLOBAL.init();
     }
 
-    if let Err(err) = init(driver_object) {
+    if let Err(err) = init(driver_object, registry_path) {
         error!("Error initializing {:?}", err);
         return STATUS_UNSUCCESSFUL;
     }
@@ -189,7 +191,10 @@ pub extern "system" fn DriverEntry(
     STATUS_SUCCESS
 }
 
-fn init(driver_object: *mut DRIVER_OBJECT) -> EventResult<()> {
+fn init(
+    driver_object: *mut DRIVER_OBJECT,
+    registry_path: *const UNICODE_STRING,
+) -> EventResult<()> {
     let device_name = WduUnicodeStr::from_slice(DEVICE_NAME_UTF16.as_slice());
 
     let fileobj = FileObjDispatch::default()
@@ -198,16 +203,16 @@ fn init(driver_object: *mut DRIVER_OBJECT) -> EventResult<()> {
         .cleanup_irp(cleanup);
     let io = IoDispath::default().ioctl_irp(device_control);
 
-    let wdu_driver = WduDriver::new(driver_object)
+    let wdu_driver = WduDriver::new(driver_object, registry_path)
         .unload(driver_unload)
         .file_object(fileobj)
         .io(io)
         .build()?;
 
-    // Mutable so we can set the flag DO_BUFFERED_IO
-    let mut wdu_device = WduDevice::default()
+    let wdu_device = WduDevice::default()
         .device_type(WduDeviceType::Unknown)
         .characteristics(WduDeviceChars::SecureOpen)
+        .io_method(WduIoMethod::Buffered)
         .build::<DeviceExtension>(&wdu_driver, Some(&device_name))?;
 
     let win32_name = WduUnicodeStr::from_slice(WIN32_NAME_UTF16.as_slice());
@@ -217,19 +222,19 @@ fn init(driver_object: *mut DRIVER_OBJECT) -> EventResult<()> {
         wdu_device.delete();
     }
 
-    // Set-up device extension
-    let extension: &mut DeviceExtension = wdu_device.extension_as_mut_ref();
-    extension.queue_lock = WduSpinLock::default(); // Default will initialize the spinlock!
-    extension.event_queue = Vec::default();
-
-    wdu_device.set_flags(DO_BUFFERED_IO);
-
     Ok(())
 }
 
+// The device is built with a DeviceExtension, the event queue is only touched holding queue_lock
+fn device_extension(device: &WduDevice) -> *mut DeviceExtension {
+    device
+        .extension_ptr()
+        .expect("Device without DeviceExtension")
+}
+
 fn driver_unload(driver: &WduDriver) {
     let dev_obj = driver.device();
-    let extension: DeviceExtension = dev_obj.extension();
+    let extension = unsafe { &mut *device_extension(&dev_obj) };
 
     if !extension.event_queue.is_empty() {
         assert!(false, "Event Queue is not empty");
@@ -244,7 +249,11 @@ fn driver_unload(driver: &WduDriver) {
     dev_obj.delete();
 }
 
-fn create(_device: &WduDevice, request: &mut WduIrp, mut req_data: WduCreate) {
+fn create(
+    _device: &WduDevice,
+    request: &mut WduIrp,
+    req_data: WduCreate,
+) -> WduIrpDisposition {
     info!("==> EventCreate");
     let io_status = WduIoStatus::success_no_info();
 
@@ -257,32 +266,34 @@ fn create(_device: &WduDevice, request: &mut WduIrp, mut req_data: WduCreate) {
         file_rundown: remove_lock,
     };
 
-    // Make sure nobody is using the FsContext scratch area.
-    assert!(req_data.file_object().context::<()>().is_none());
-    req_data.file_object_mut().set_context(file_ctx);
+    // Dropped by the library once the close IRP is completed
+    if let Err(err) = req_data.file_object().allocate_context(file_ctx) {
+        error!("Error allocating file context. {:?}", err);
+        return request.complete(WduIoStatus::new_with_status(STATUS_INSUFFICIENT_RESOURCES));
+    }
 
-    request.complete(io_status);
+    request.complete(io_status)
 }
 
-fn close(_device: &WduDevice, request: &mut WduIrp, file_obj: WduFileObject) {
+fn close(_device: &WduDevice, request: &mut WduIrp, file_obj: WduFileObject) -> WduIrpDisposition {
     info!("==> EventClose");
 
     let io_status = WduIoStatus::success_no_info();
 
-    if let Some(_ctx) = file_obj.context::<FileContext>() {
-        info!("Freeing FsContext");
-    } // If we had anything allocated inside FileContext it would be dropped here :)
+    if file_obj.typed_context::<FileContext>().is_some() {
+        info!("FileContext will be dropped once the IRP is completed");
+    }
 
-    request.complete(io_status);
+    request.complete(io_status)
 }
 
-fn cleanup(device: &WduDevice, request: &mut WduIrp, file_obj: WduFileObject) {
+fn cleanup(device: &WduDevice, request: &mut WduIrp, file_obj: WduFileObject) -> WduIrpDisposition {
     info!("==> EventCleanup");
 
-    let extension: &mut DeviceExtension = device.extension_as_mut_ref();
+    let extension = unsafe { &mut *device_extension(device) };
 
     assert!(file_obj.is_valid());
-    let fs_ctx: &mut FileContext = file_obj.context_as_mut_ref().unwrap();
+    let fs_ctx = unsafe { file_obj.typed_context_mut::<FileContext>() }.unwrap();
 
     let tag = Some(request.as_ptr() as usize);
     let status = fs_ctx.file_rundown.acquire(tag);
@@ -329,14 +340,14 @@ fn cleanup(device: &WduDevice, request: &mut WduIrp, file_obj: WduFileObject) {
     });
 
     let io_status = WduIoStatus::success_no_info();
-    request.complete(io_status);
+    request.complete(io_status)
 }
 
 fn device_control(
     device: &WduDevice,
     request: &mut WduIrp,
     req_data: WduDeviceControl,
-) -> NTSTATUS {
+) -> WduIrpDisposition {
     info!("==> EventDispatchIoControl");
     // Init io_status to STATUS_UNSUCCESSFUL and Information == 0;
     let mut io_status = WduIoStatus::new();
@@ -345,11 +356,10 @@ fn device_control(
     assert!(fo.is_valid());
 
     // Should be safe to directly uwnrap
-    let fs_ctx: &mut FileContext = fo.context_as_mut_ref().unwrap();
+    let fs_ctx = unsafe { fo.typed_context_mut::<FileContext>() }.unwrap();
 
     if fs_ctx.file_rundown.acquire(Some(request.as_ptr() as usize)) != STATUS_SUCCESS {
-        request.complete(io_status);
-        return STATUS_UNSUCCESSFUL;
+        return request.complete(io_status);
     }
 
     let status = match req_data.ioctl() {
@@ -366,14 +376,16 @@ fn device_control(
         }
     };
 
-    if status != STATUS_PENDING {
+    let disposition = if status == STATUS_PENDING {
+        WduIrpDisposition::Pending
+    } else {
         io_status.set_status(status);
-        request.complete(io_status);
-    }
+        request.complete(io_status)
+    };
 
     fs_ctx.file_rundown.release();
 
-    status
+    disposition
 }
 
 fn handle_register_event(
@@ -408,14 +420,14 @@ fn irp_notification(
     register_event: &RegisterEvent,
 ) -> EventResult<NTSTATUS> {
     info!("\tRegisterIrpBasedNotification");
-    let extension: &mut DeviceExtension = device.extension_as_mut_ref();
+    let extension = unsafe { &mut *device_extension(device) };
 
     // Set cancel_rtn here so we have the routine when we clone. I don't like this very much :(
     irp.set_cancel_rtn(Some(cancel_routine));
 
     let data = NotifyData{
         ty: NotifyType::IrpBased(irp.clone()),
-        extension: device.extension_as_mut_ptr(),
+        extension: extension as *mut _,
     };
 
     let mut record = Box::new(NotifyRecord {
@@ -459,7 +471,7 @@ fn event_notification(
     register_event: &RegisterEvent,
 ) -> EventResult<NTSTATUS> {
     info!("\ttRegisterEventBasedNotification");
-    let extension: &mut DeviceExtension = device.extension_as_mut_ref();
+    let extension = unsafe { &mut *device_extension(device) };
 
     let access_mask = 0x00100000 | 0x0002;
 
@@ -467,7 +479,7 @@ fn event_notification(
 
     let data = NotifyData{
         ty: NotifyType::EventBased(event),
-        extension: device.extension_as_mut_ptr(),
+        extension: extension as *mut _,
     };
 
     let mut record = Box::new(NotifyRecord {
@@ -498,7 +510,7 @@ fn event_notification(
 
 fn cancel_routine(device: &WduDevice, irp: &mut WduIrp) {
     info!("==>EventCancelRoutine irp {:?}", irp.as_ptr());
-    let dev_ext: &mut DeviceExtension = device.extension_as_mut_ref();
+    let dev_ext = unsafe { &mut *device_extension(device) };
     irp.release_cancel_lock();
 
     dev_ext.queue_lock.acquire();
@@ -16,15 +16,16 @@ use win_drvutils_rs::{
         WduCallbackError,
     },
     common::{
+        child::WduChildError,
         driver::{FileObjDispatch, IoDispath, WduDriver, WduDriverError},
         process::WduProcess,
     },
     encode_ioctl,
     io::{
         create::WduCreate,
-        device::{WduDevice, WduDeviceChars, WduDeviceError, WduDeviceType},
+        device::{WduDevice, WduDeviceChars, WduDeviceError, WduDeviceType, WduSymbolicLink},
         device_control::WduDeviceControl,
-        irp::{WduIoStatus, WduIrp},
+        irp::{WduIoStatus, WduIrp, WduIrpDisposition},
         file_obj::WduFileObject
     },
     memory::pool::SimpleAlloc,
@@ -88,6 +89,12 @@ impl From<WduDriverError> for ObCallbackError {
     }
 }
 
+impl From<WduChildError> for ObCallbackError {
+    fn from(_error: WduChildError) -> Self {
+        ObCallbackError::DriverError
+    }
+}
+
 impl From<WduCallbackError> for ObCallbackError {
     fn from(_error: WduCallbackError) -> Self {
         ObCallbackError::CallbackError
@@ -102,7 +109,6 @@ impl From<WduUnicodeError> for ObCallbackError {
 
 // Global variables. We don't have much of an option other than store certain variables as mut in
 // the global state.
-static mut PROCESS_CB: WduPsCallback = WduPsCallback::const_new();
 static mut PROTECT: WduGuardedMtx<Protect> =
     WduGuardedMtx::const_new(WduGuardedMutex::const_new(), Protect::const_new());
 
@@ -163,7 +169,7 @@ unsafe extern "system" fn process_notify_cb(
 #[no_mangle]
 pub extern "system" fn DriverEntry(
     driver_object: *mut DRIVER_OBJECT,
-    _registry_path: *const UNICODE_STRING,
+    registry_path: *const UNICODE_STRING,
 ) -> NTSTATUS {
     KernelLogger::init(LevelFilter::Info).expect("Failed to initialize logger");
 
@@ -177,7 +183,7 @@ pub extern "system" fn DriverEntry(
 
     info!("Callback version {:#X}", WduObCallback::version());
 
-    if let Err(err) = init(driver_object) {
+    if let Err(err) = init(driver_object, registry_path) {
         error!("Error initializing {:?}", err);
         return STATUS_UNSUCCESSFUL;
     }
@@ -185,7 +191,10 @@ pub extern "system" fn DriverEntry(
     STATUS_SUCCESS
 }
 
-fn init(driver_object: *mut DRIVER_OBJECT) -> ObCallbackResult<()> {
+fn init(
+    driver_object: *mut DRIVER_OBJECT,
+    registry_path: *const UNICODE_STRING,
+) -> ObCallbackResult<()> {
     let device_name = WduUnicodeStr::from_slice(DEVICE_NAME_UTF16.as_slice());
     let win32_name = WduUnicodeStr::from_slice(WIN32_NAME_UTF16.as_slice());
 
@@ -197,7 +206,7 @@ fn init(driver_object: *mut DRIVER_OBJECT) -> ObCallbackResult<()> {
 
     let io = IoDispath::default().ioctl_irp(device_control);
 
-    let wdu_driver = WduDriver::new(driver_object)
+    let wdu_driver = WduDriver::new(driver_object, registry_path)
         .unload(driver_unload)
         .file_object(fileobj)
         .io(io)
@@ -208,33 +217,33 @@ fn init(driver_object: *mut DRIVER_OBJECT) -> ObCallbackResult<()> {
         .characteristics(WduDeviceChars::SecureOpen)
         .build::<()>(&wdu_driver, Some(&device_name))?; // Zero Sized type for the Device Extension
 
-    if let Err(err) = wdu_device.symbolic_name(&device_name, &win32_name) {
-        error!("Error creating symbolic name. {:?}", err);
-        wdu_device.delete();
+    // Children of the driver are torn down in reverse order on unload, before the device is
+    // deleted: first the process callback, then the symbolic link.
+    match WduSymbolicLink::create(&device_name, &win32_name) {
+        Ok(link) => {
+            wdu_driver.add_child(link)?;
+        }
+        Err(err) => {
+            error!("Error creating symbolic name. {:?}", err);
+            wdu_device.delete();
+            return Ok(());
+        }
     }
 
-    if let Err(err) =
-        unsafe { PROCESS_CB.register(PsCallbackVersion::NotifyRoutineEx2(process_notify_cb)) }
-    {
-        error!("Error registering PsNotifyRoutine. {:?}", err);
-
-        // Cleanup, ignore errors
-        let _ = WduDevice::delete_symbolic_name(&win32_name);
-        wdu_device.delete();
+    let mut process_cb = WduPsCallback::default();
+    match process_cb.register(PsCallbackVersion::NotifyRoutineEx2(process_notify_cb)) {
+        Ok(_) => {
+            wdu_driver.add_child(process_cb)?;
+        }
+        Err(err) => error!("Error registering PsNotifyRoutine. {:?}", err),
     }
 
     Ok(())
 }
 
-fn driver_unload(driver: &WduDriver) {
-    let dev_obj = driver.device();
-    let win32_name = WduUnicodeStr::from_slice(WIN32_NAME_UTF16.as_slice());
-
+fn driver_unload(_driver: &WduDriver) {
     // Unsafe required due to static mut.
     unsafe {
-        // ignore error
-        let _ = PROCESS_CB.unregister();
-
         // Acquire Guarded Mutex
         let mut lock = PROTECT.lock();
         if lock.is_cb_installed() {
@@ -244,29 +253,25 @@ fn driver_unload(driver: &WduDriver) {
         }
     }
 
-    if let Err(err) = WduDevice::delete_symbolic_name(&win32_name) {
-        error!("Error creating deleting symbolic name. {:?}", err);
-    }
-
-    dev_obj.delete();
+    // The process callback, the symbolic link and the device are torn down by the library
 }
 
-fn create(_device: &WduDevice, request: &mut WduIrp, _req_data: WduCreate) {
+fn create(_device: &WduDevice, request: &mut WduIrp, _req_data: WduCreate) -> WduIrpDisposition {
     let io_status = WduIoStatus::success_no_info();
-    request.complete(io_status);
+    request.complete(io_status)
 }
 
-fn close(_device: &WduDevice, request: &mut WduIrp, _file_obj: WduFileObject) {
+fn close(_device: &WduDevice, request: &mut WduIrp, _file_obj: WduFileObject) -> WduIrpDisposition {
     let io_status = WduIoStatus::success_no_info();
-    request.complete(io_status);
+    request.complete(io_status)
 }
 
-fn cleanup(_device: &WduDevice, request: &mut WduIrp, _file_obj: WduFileObject) {
+fn cleanup(_device: &WduDevice, request: &mut WduIrp, _file_obj: WduFileObject) -> WduIrpDisposition {
     let io_status = WduIoStatus::success_no_info();
-    request.complete(io_status);
+    request.complete(io_status)
 }
 
-fn device_control(_device: &WduDevice, request: &mut WduIrp, req_data: WduDeviceControl) -> NTSTATUS {
+fn device_control(_device: &WduDevice, request: &mut WduIrp, req_data: WduDeviceControl) -> WduIrpDisposition {
     // Init io_status with STATUS_UNSUCCESSFUL;
     let mut io_status = WduIoStatus::new();
     let ioctl = req_data.ioctl();
@@ -281,9 +286,7 @@ fn device_control(_device: &WduDevice, request: &mut WduIrp, req_data: WduDevice
     };
 
     io_status.set_status(status);
-    request.complete(io_status);
-
-    status
+    request.complete(io_status)
 }
 
 fn protect_name(req_data: &WduDeviceControl) ->  ObCallbackResult<NTSTATUS> {
@@ -9,21 +9,25 @@ use log::{error, info, warn, LevelFilter};
 use widestring::{utf16str, Utf16Str};
 
 use win_drvutils_rs::{
-    common::driver::{FileObjDispatch, IoDispath, WduDriver, WduDriverError},
+    common::{
+        child::WduChildError,
+        driver::{FileObjDispatch, IoDispath, WduDriver, WduDriverError},
+        guid::WduGuid,
+    },
     encode_ioctl,
     io::{
         create::WduCreate,
-        device::{WduDevice, WduDeviceChars, WduDeviceError, WduDeviceType},
+        device::{WduDevice, WduDeviceChars, WduDeviceError, WduDeviceType, WduSymbolicLink},
         device_control::WduDeviceControl,
-        irp::{WduIoStatus, WduIrp},
+        irp::{WduIoStatus, WduIrp, WduIrpDisposition},
         file_obj::WduFileObject
     },
     memory::{
         pool::SimpleAlloc,
-        mdl::{LockOperation, PagePriority, WduMdl, WduMdlError}
+        mdl::WduMdlError
     },
+    security::sddl::WduSddl,
     strings::unicode::str::WduUnicodeStr,
-    ProcessorMode,
 };
 
 use windows_sys::{
@@ -39,6 +43,9 @@ use windows_sys::{
     }
 };
 
+// Class of the device in the registry, where the SDDL can be overridden
+const SIOCTL_CLASS_GUID: WduGuid = WduGuid::new("5d006e1a-2631-466c-b8a0-32fd498e4424");
+
 #[global_allocator]
 static mut GLOBAL: SimpleAlloc = SimpleAlloc::const_new();
 
@@ -63,6 +70,7 @@ pub enum SioctlError {
     DriverError,
     DeviceError,
     MdlError,
+    UserBufferError,
 }
 
 pub type SioctlResult<T> = Result<T, SioctlError>;
@@ -80,6 +88,12 @@ impl From<WduDriverError> for SioctlError {
     }
 }
 
+impl From<WduChildError> for SioctlError {
+    fn from(_error: WduChildError) -> Self {
+        SioctlError::DriverError
+    }
+}
+
 impl From<WduMdlError> for SioctlError {
     fn from(_error: WduMdlError) -> Self {
         SioctlError::MdlError
@@ -88,6 +102,7 @@ impl From<WduMdlError> for SioctlError {
 
 const DEVICE_NAME_UTF16: &Utf16Str = utf16str!(r"\Device\SIOCTL");
 const WIN32_NAME_UTF16: &Utf16Str = utf16str!(r"\DosDevices\IoctlTest");
+const VERBOSE_UTF16: &Utf16Str = utf16str!("Verbose");
 
 const SIOCTL_TYPE: u32 = 40000;
 const IOCTL_SIOCTL_METHOD_IN_DIRECT: u32 =
@@ -102,7 +117,7 @@ const IOCTL_SIOCTL_METHOD_NEITHER: u32 =
 #[no_mangle]
 pub extern "system" fn DriverEntry(
     driver_object: *mut DRIVER_OBJECT,
-    _registry_path: *const UNICODE_STRING,
+    registry_path: *const UNICODE_STRING,
 ) -> NTSTATUS {
     KernelLogger::init(LevelFilter::Info).expect("Failed to initialize logger");
 
@@ -110,7 +125,7 @@ pub extern "system" fn DriverEntry(
         GLOBAL.init();
     }
 
-    if let Err(err) = init(driver_object) {
+    if let Err(err) = init(driver_object, registry_path) {
         error!("Error initializing {:?}", err);
         return STATUS_UNSUCCESSFUL;
     }
@@ -118,7 +133,10 @@ pub extern "system" fn DriverEntry(
     STATUS_SUCCESS
 }
 
-fn init(driver_object: *mut DRIVER_OBJECT) -> SioctlResult<()> {
+fn init(
+    driver_object: *mut DRIVER_OBJECT,
+    registry_path: *const UNICODE_STRING,
+) -> SioctlResult<()> {
     let device_name = WduUnicodeStr::from_slice(DEVICE_NAME_UTF16.as_slice());
     let win32_name = WduUnicodeStr::from_slice(WIN32_NAME_UTF16.as_slice());
 
@@ -127,47 +145,65 @@ fn init(driver_object: *mut DRIVER_OBJECT) -> SioctlResult<()> {
         .close_irp(close);
     let io = IoDispath::default().ioctl_irp(device_control);
 
-    let wdu_driver = WduDriver::new(driver_object)
+    let wdu_driver = WduDriver::new(driver_object, registry_path)
         .unload(driver_unload)
         .file_object(fileobj)
         .io(io)
         .build()?;
 
+    // Optional REG_DWORD under the Parameters key of the service
+    let verbose = wdu_driver
+        .parameters()
+        .and_then(|params| params.query(&WduUnicodeStr::from_slice(VERBOSE_UTF16.as_slice())))
+        .unwrap_or(false);
+    if verbose {
+        log::set_max_level(LevelFilter::Trace);
+    }
+
     let wdu_device = WduDevice::default()
         .device_type(WduDeviceType::Unknown)
         .characteristics(WduDeviceChars::SecureOpen)
+        .secure(WduSddl::SYS_ALL_ADM_ALL, SIOCTL_CLASS_GUID)
         .build::<()>(&wdu_driver, Some(&device_name))?; // Zero Sized type for the Device Extension
 
-    if let Err(err) = wdu_device.symbolic_name(&device_name, &win32_name) {
-        error!("Error creating symbolic name. {:?}", err);
-        wdu_device.delete();
+    // Children of the driver are torn down on unload, before the device is deleted
+    match WduSymbolicLink::create(&device_name, &win32_name) {
+        Ok(link) => {
+            wdu_driver.add_child(link)?;
+        }
+        Err(err) => {
+            error!("Error creating symbolic name. {:?}", err);
+            wdu_device.delete();
+        }
     }
 
     Ok(())
 }
 
-fn driver_unload(driver: &WduDriver) {
-    let dev_obj = driver.device();
-    let win32_name = WduUnicodeStr::from_slice(WIN32_NAME_UTF16.as_slice());
-
-    if let Err(err) = WduDevice::delete_symbolic_name(&win32_name) {
-        error!("Error deleting symbolic name. {:?}", err);
-    }
-
-    dev_obj.delete();
+fn driver_unload(_driver: &WduDriver) {
+    // The library deletes the symbolic link and the device once this returns
+    info!("Unloading");
 }
 
-fn create(_device: &WduDevice, request: &mut WduIrp, _req_data: WduCreate) {
+fn create(_device: &WduDevice, request: &mut WduIrp, _req_data: WduCreate) -> WduIrpDisposition {
     let io_status = WduIoStatus::success_no_info();
-    request.complete(io_status);
+    request.complete(io_status)
 }
 
-fn close(_device: &WduDevice, request: &mut WduIrp, _file_object: WduFileObject) {
+fn close(
+    _device: &WduDevice,
+    request: &mut WduIrp,
+    _file_object: WduFileObject,
+) -> WduIrpDisposition {
     let io_status = WduIoStatus::success_no_info();
-    request.complete(io_status);
+    request.complete(io_status)
 }
 
-fn device_control(_device: &WduDevice, request: &mut WduIrp, req_data: WduDeviceControl) -> NTSTATUS {
+fn device_control(
+    _device: &WduDevice,
+    request: &mut WduIrp,
+    mut req_data: WduDeviceControl,
+) -> WduIrpDisposition {
     let data = "String from Rust Device Driver!";
 
     // Init io_status to STATUS_SUCCES and Information == 0;
@@ -179,9 +215,7 @@ fn device_control(_device: &WduDevice, request: &mut WduIrp, req_data: WduDevice
 
     if in_buf_size == 0 || out_buf_size == 0 {
         io_status.set_status(STATUS_INVALID_PARAMETER);
-        request.complete(io_status);
-
-        return STATUS_INVALID_PARAMETER;
+        return request.complete(io_status);
     }
 
     // WduDeviceControl knows from where to retrieve the buffer so we can do this here regardless
@@ -191,9 +225,7 @@ fn device_control(_device: &WduDevice, request: &mut WduIrp, req_data: WduDevice
 
     if out_buf.is_null() {
         io_status.set_status(STATUS_INSUFFICIENT_RESOURCES);
-        request.complete(io_status);
-
-        return STATUS_INSUFFICIENT_RESOURCES;
+        return request.complete(io_status);
     }
 
     let status = match ioctl {
@@ -201,28 +233,29 @@ fn device_control(_device: &WduDevice, request: &mut WduIrp, req_data: WduDevice
         // in our case we can reuse the code since WduDeviceControl already knows from where to
         // retrieve the data
         IOCTL_SIOCTL_METHOD_BUFFERED | IOCTL_SIOCTL_METHOD_OUT_DIRECT => {
-            let in_data: &[u8] = unsafe { slice::from_raw_parts(in_buf as *const _, in_buf_size) };
-
-            // info!("Data from user: {}", core::str::from_utf8(in_data).unwrap());
-            info!("Data from user: {:?}", in_data);
-
-            unsafe {
-                out_buf.copy_from(data.as_ptr() as *const _, data.len());
+            // With METHOD_BUFFERED writing the output overwrites the input, read it first
+            match req_data.input_slice() {
+                Ok(in_data) => info!("Data from user: {:?}", in_data),
+                Err(err) => {
+                    io_status.set_status(err.status());
+                    return request.complete(io_status);
+                }
             }
 
-            let out_data: &[u8] =
-                unsafe { slice::from_raw_parts(out_buf as *const _, out_buf_size) };
-
-            info!("Data to user: {:?}", out_data);
-
-            let written_bytes = if out_buf_size < data.len() {
-                out_buf_size
-            } else {
-                data.len()
+            let mut writer = match req_data.output_writer() {
+                Ok(writer) => writer,
+                Err(err) => {
+                    io_status.set_status(err.status());
+                    return request.complete(io_status);
+                }
             };
 
-            io_status.set_info(written_bytes);
-            STATUS_SUCCESS
+            // The writer doesn't go past the output buffer, truncate the string if needed
+            let len = data.len().min(writer.remaining());
+            let _ = writer.write(&data.as_bytes()[..len]);
+
+            info!("Data to user: {:?}", &data.as_bytes()[..len]);
+            return writer.complete(request);
         }
         IOCTL_SIOCTL_METHOD_IN_DIRECT => {
             let in_data: &[u8] = unsafe { slice::from_raw_parts(in_buf as *const _, in_buf_size) };
@@ -257,66 +290,28 @@ fn device_control(_device: &WduDevice, request: &mut WduIrp, req_data: WduDevice
     };
 
     io_status.set_status(status);
-    request.complete(io_status);
-
-    return status;
+    request.complete(io_status)
 }
 
 fn method_neither(req_data: &WduDeviceControl) -> SioctlResult<usize> {
-    // ******* REMARK **********
-    // At the moment not try/catch block alike to probe the buffers so this buffering method is
-    // very unsafe. Adding the code just to demonstrate how to work with MDLs using the
-    // win-drvutils-rs, but this still requies research & work.
+    // METHOD_NEITHER buffers are user addresses, WduUserBuffer validates them and copies the data
+    // in and out so an invalid address fails the request instead of crashing the system.
     let data = "String from Rust Device Driver (Method Neither)!";
 
-    {
-        let mut in_mdl = WduMdl::allocate(
-            req_data.input_buffer(),
-            req_data.input_buffer_size() as u32,
-            false,
-            None,
-        )?;
-
-        // probe_and_lock doesn't implement any try/catch mechanism at the moment, this is very unsafe.
-        in_mdl.probe_and_lock(ProcessorMode::UserMode, LockOperation::IoReadAccess);
-        let in_buf = in_mdl.get_system_addr(PagePriority::Normal | PagePriority::MdlMappingNoExecute);
+    let in_buf = req_data
+        .user_input::<u8>()
+        .map_err(|_| SioctlError::UserBufferError)?;
+    let in_data = in_buf
+        .capture()
+        .map_err(|_| SioctlError::UserBufferError)?;
 
-        if in_buf.is_null() {
-            return Err(SioctlError::MdlError);
-        }
-
-        let in_data: &[u8] =
-            unsafe { slice::from_raw_parts(in_buf as *const _, in_mdl.byte_count() as usize) };
-
-        info!("Data from User (SystemAddress): {:?}", in_data);
-    } // On Drop MDL will be unlocked and freed
-
-    {
-        let mut out_mdl = WduMdl::allocate(
-            req_data.output_buffer(),
-            req_data.output_buffer_size() as u32,
-            false,
-            None,
-        )?;
-
-        // probe_and_lock doesn't implement any try/catch mechanism at the moment, this is very unsafe.
-        out_mdl.probe_and_lock(ProcessorMode::UserMode, LockOperation::IoWriteAccess);
-        let out_buf = out_mdl.get_system_addr(PagePriority::Normal | PagePriority::MdlMappingNoExecute);
+    info!("Data from User: {:?}", in_data);
 
-        if out_buf.is_null() {
-            return Err(SioctlError::MdlError);
-        }
-
-        unsafe {
-            out_buf.copy_from(data.as_ptr() as *const _, data.len());
-        }
+    let out_buf = req_data
+        .user_output::<u8>()
+        .map_err(|_| SioctlError::UserBufferError)?;
 
-        let written_bytes = if out_mdl.byte_count() < data.len() {
-            out_mdl.byte_count()
-        } else {
-            data.len()
-        };
-
-        Ok(written_bytes)
-    }
+    out_buf
+        .write_slice(data.as_bytes())
+        .map_err(|_| SioctlError::UserBufferError)
 }
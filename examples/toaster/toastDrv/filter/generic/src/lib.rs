@@ -10,16 +10,74 @@ use wdk_alloc::WDKAllocator;
 #[global_allocator]
 static GLOBAL_ALLOCATOR: WDKAllocator = WDKAllocator;
 
-use wdk_sys::{
-    DRIVER_OBJECT,
-    NTSTATUS,
-    PCUNICODE_STRING,
+use win_drvutils_rs::{
+    common::driver::{PnpDispatch, PowerDispatch, WduDriver, WduUnhandledIrp},
+    io::{
+        device::{WduDevice, WduDeviceFlags, WduDeviceType},
+        irp::WduIrp,
+        pnp::WduPnpIrp,
+    },
+};
+use windows_sys::{
+    Wdk::{Foundation::DRIVER_OBJECT, System::SystemServices::FILE_REMOVABLE_MEDIA},
+    Win32::Foundation::{NTSTATUS, STATUS_SUCCESS, STATUS_UNSUCCESSFUL, UNICODE_STRING},
 };
 
-#[export_name = "DriverEntry"] // WDF expects a symbol with the name DriverEntry
+// Generic upper/lower filter for the toaster stack. Every IRP is passed down untouched, PnP and
+// power are handled by the library defaults which already take care of start, removal and
+// detaching from the stack.
+#[export_name = "DriverEntry"]
 pub unsafe extern "system" fn driver_entry(
-    driver: &mut DRIVER_OBJECT,
-    registry_path: PCUNICODE_STRING,
+    driver: *mut DRIVER_OBJECT,
+    registry_path: *const UNICODE_STRING,
 ) -> NTSTATUS {
-    0
-}
\ No newline at end of file
+    let pnp = PnpDispatch::default().start_device_irp(start_device);
+
+    let wdu_driver = WduDriver::new(driver, registry_path)
+        .device_add(add_device)
+        .pnp(pnp)
+        .power(PowerDispatch::default())
+        .unhandled_irp(WduUnhandledIrp::PassDown)
+        .build();
+
+    if wdu_driver.is_err() {
+        return STATUS_UNSUCCESSFUL;
+    }
+
+    STATUS_SUCCESS
+}
+
+fn add_device(driver: &WduDriver, pdo: &WduDevice) -> NTSTATUS {
+    let mut filter = match WduDevice::default()
+        .device_type(WduDeviceType::Unknown)
+        .clear_initializing(false)
+        .build::<()>(driver, None)
+    {
+        Ok(device) => device,
+        Err(_) => return STATUS_UNSUCCESSFUL,
+    };
+
+    if filter.attach_to_stack(pdo).is_err() {
+        filter.delete();
+        return STATUS_UNSUCCESSFUL;
+    }
+
+    filter.inherit_from_lower();
+    filter.clear_flags(WduDeviceFlags::DeviceInitializing);
+
+    STATUS_SUCCESS
+}
+
+// Called once the lower drivers started the device.
+fn start_device(device: &WduDevice, irp: &mut WduIrp, _pnp_irp: &mut WduPnpIrp) -> NTSTATUS {
+    // The function driver might set FILE_REMOVABLE_MEDIA once started, propagate it so the
+    // filter device matches the lower one.
+    if let Some(lower) = device.lower_device() {
+        unsafe {
+            let removable = (*lower.device()).Characteristics & FILE_REMOVABLE_MEDIA;
+            (*device.device_as_mut()).Characteristics |= removable;
+        }
+    }
+
+    irp.io_status().status()
+}
@@ -10,16 +10,156 @@ use wdk_alloc::WDKAllocator;
 #[global_allocator]
 static GLOBAL_ALLOCATOR: WDKAllocator = WDKAllocator;
 
-use wdk_sys::{
-    DRIVER_OBJECT,
-    NTSTATUS,
-    PCUNICODE_STRING,
+use widestring::{utf16str, Utf16Str};
+use win_drvutils_rs::{
+    common::driver::{
+        FileObjDispatch, PnpDispatch, PowerDispatch, WduDriver, WduUnhandledIrp,
+    },
+    io::{
+        create::WduCreate,
+        device::{WduDevice, WduDeviceChars, WduDeviceFlags, WduDeviceType},
+        file_obj::WduFileObject,
+        irp::{WduIoStatus, WduIrp, WduIrpDisposition},
+        pnp::WduPnpIrp,
+    },
+    strings::unicode::str::WduUnicodeStr,
+    sync::mutex::WduFastMutex,
 };
+use windows_sys::{
+    Wdk::Foundation::DRIVER_OBJECT,
+    Win32::Foundation::{NTSTATUS, STATUS_SUCCESS, STATUS_UNSUCCESSFUL, UNICODE_STRING},
+};
+
+// Same filter as the generic one plus a control device so applications can talk to the filter
+// without going through the toaster stack (sideband communication). The control device is
+// created with the first filter instance and deleted with the last one.
+
+const CONTROL_NAME_UTF16: &Utf16Str = utf16str!(r"\Device\ToasterFilter");
+const CONTROL_LINK_UTF16: &Utf16Str = utf16str!(r"\DosDevices\ToasterFilter");
+
+// Guards the instance count and the control device, AddDevice and RemoveDevice of different
+// devices can run concurrently.
+static mut CONTROL_LOCK: WduFastMutex = WduFastMutex::const_new();
+static mut INSTANCES: usize = 0;
+static mut CONTROL_DEVICE: Option<WduDevice> = None;
 
-#[export_name = "DriverEntry"] // WDF expects a symbol with the name DriverEntry
+#[export_name = "DriverEntry"]
 pub unsafe extern "system" fn driver_entry(
-    driver: &mut DRIVER_OBJECT,
-    registry_path: PCUNICODE_STRING,
+    driver: *mut DRIVER_OBJECT,
+    registry_path: *const UNICODE_STRING,
 ) -> NTSTATUS {
-    0
-}
\ No newline at end of file
+    CONTROL_LOCK.init();
+
+    let fileobj = FileObjDispatch::default()
+        .create_irp(create)
+        .close_irp(close_cleanup)
+        .cleanup_irp(close_cleanup);
+    let pnp = PnpDispatch::default().remove_device_irp(remove_device);
+
+    let wdu_driver = WduDriver::new(driver, registry_path)
+        .device_add(add_device)
+        .file_object(fileobj)
+        .pnp(pnp)
+        .power(PowerDispatch::default())
+        .unhandled_irp(WduUnhandledIrp::PassDown)
+        .build();
+
+    if wdu_driver.is_err() {
+        return STATUS_UNSUCCESSFUL;
+    }
+
+    STATUS_SUCCESS
+}
+
+fn add_device(driver: &WduDriver, pdo: &WduDevice) -> NTSTATUS {
+    let mut filter = match WduDevice::default()
+        .device_type(WduDeviceType::Unknown)
+        .clear_initializing(false)
+        .build::<()>(driver, None)
+    {
+        Ok(device) => device,
+        Err(_) => return STATUS_UNSUCCESSFUL,
+    };
+
+    if filter.attach_to_stack(pdo).is_err() {
+        filter.delete();
+        return STATUS_UNSUCCESSFUL;
+    }
+
+    filter.inherit_from_lower();
+    filter.clear_flags(WduDeviceFlags::DeviceInitializing);
+
+    // Failing to create the control device is not fatal, the filter keeps working
+    let _ = create_control_device(driver);
+
+    STATUS_SUCCESS
+}
+
+fn create_control_device(driver: &WduDriver) -> NTSTATUS {
+    let control_name = WduUnicodeStr::from_slice(CONTROL_NAME_UTF16.as_slice());
+    let control_link = WduUnicodeStr::from_slice(CONTROL_LINK_UTF16.as_slice());
+
+    unsafe {
+        CONTROL_LOCK.acquire();
+        INSTANCES += 1;
+
+        if INSTANCES == 1 {
+            let control = WduDevice::default()
+                .device_type(WduDeviceType::Unknown)
+                .characteristics(WduDeviceChars::SecureOpen)
+                .build::<()>(driver, Some(&control_name));
+
+            if let Ok(control) = control {
+                if control.symbolic_name(&control_name, &control_link).is_ok() {
+                    CONTROL_DEVICE = Some(control);
+                } else {
+                    control.delete();
+                }
+            }
+        }
+
+        CONTROL_LOCK.release();
+    }
+
+    STATUS_SUCCESS
+}
+
+fn delete_control_device() {
+    let control_link = WduUnicodeStr::from_slice(CONTROL_LINK_UTF16.as_slice());
+
+    unsafe {
+        CONTROL_LOCK.acquire();
+        INSTANCES -= 1;
+
+        if INSTANCES == 0 {
+            if let Some(control) = CONTROL_DEVICE.take() {
+                let _ = WduDevice::delete_symbolic_name(&control_link);
+                control.delete();
+            }
+        }
+
+        CONTROL_LOCK.release();
+    }
+}
+
+// The library passes the IRP down, detaches and deletes the filter once this returns.
+fn remove_device(_device: &WduDevice, _irp: &mut WduIrp, _pnp_irp: &mut WduPnpIrp) -> NTSTATUS {
+    delete_control_device();
+    STATUS_SUCCESS
+}
+
+// File object IRPs sent to the toaster stack go down, the ones sent to the control device are
+// completed here. The control device is the only device without a lower device.
+fn create(device: &WduDevice, irp: &mut WduIrp, _create: WduCreate) -> WduIrpDisposition {
+    match device.lower_device() {
+        Some(lower) => irp.pass_through(&lower),
+        None => irp.complete(WduIoStatus::success_no_info()),
+    }
+}
+
+fn close_cleanup(device: &WduDevice, irp: &mut WduIrp, _file: WduFileObject) -> WduIrpDisposition {
+    match device.lower_device() {
+        Some(lower) => irp.pass_through(&lower),
+        None => irp.complete(WduIoStatus::success_no_info()),
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     callbacks::{WduCallbackError, WduCallbackResult},
-    common::obj_attr::WduObjectAttributes,
+    common::{child::WduChild, obj_attr::WduObjectAttributes},
 };
 use core::{
     borrow::BorrowMut,
@@ -30,7 +30,7 @@ type CallbackFunction = unsafe extern "system" fn(*const c_void, *mut c_void, *m
 // link and define a new prototype for the function.
 // Keeping redefined prototypes in the `nt` modules to avoid confusion.
 mod nt {
-    #[link(name = "ntoskrnl")]
+    #[cfg_attr(not(test), link(name = "ntoskrnl"))]
     extern "system" {
         pub(crate) fn ExRegisterCallback(
             callbackobject: super::PCALLBACK_OBJECT,
@@ -148,10 +148,15 @@ impl<'a, T: 'a> WduCallbackObject<'a, T> {
     }
 
     pub fn unregister(&mut self) {
+        if self.handle.is_null() {
+            return;
+        }
+
         unsafe {
             ExUnregisterCallback(*self.handle);
-            self.cb = None;
         }
+        self.handle = CallbackHandle::default();
+        self.cb = None;
     }
 
     unsafe extern "system" fn cb_internal(
@@ -174,3 +179,9 @@ impl<'a, T: 'a> WduCallbackObject<'a, T> {
         }
     }
 }
+
+impl<'a, T> WduChild for WduCallbackObject<'a, T> {
+    fn teardown(&mut self) {
+        self.unregister();
+    }
+}
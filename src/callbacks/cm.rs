@@ -1,5 +1,6 @@
 use crate::{
     callbacks::{WduCallbackError, WduCallbackResult},
+    common::child::WduChild,
     strings::unicode::string::WduUnicodeString,
 };
 use core::{borrow::BorrowMut, ffi::c_void};
@@ -114,3 +115,11 @@ impl WduCmCallback {
         }
     }
 }
+
+impl WduChild for WduCmCallback {
+    fn teardown(&mut self) {
+        if self.cookie != 0 {
+            let _ = self.unregister();
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use crate::callbacks::{WduCallbackError, WduCallbackResult};
+use crate::{
+    callbacks::{WduCallbackError, WduCallbackResult},
+    common::child::WduChild,
+};
 use windows_sys::{
     Wdk::System::SystemServices::{
         PsRemoveLoadImageNotifyRoutine, PsSetLoadImageNotifyRoutine, PLOAD_IMAGE_NOTIFY_ROUTINE,
@@ -55,3 +58,9 @@ impl WduImageCallback {
         Ok(())
     }
 }
+
+impl WduChild for WduImageCallback {
+    fn teardown(&mut self) {
+        let _ = self.unregister();
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{
     callbacks::{WduCallbackError, WduCallbackResult},
+    common::child::WduChild,
     nt,
     strings::unicode::{
         string::WduUnicodeString,
@@ -437,6 +438,14 @@ impl<T> WduObCallback<T> {
     }
 }
 
+impl<T> WduChild for WduObCallback<T> {
+    fn teardown(&mut self) {
+        if !self.handle.is_null() {
+            let _ = self.unregister();
+        }
+    }
+}
+
 impl WduObCallback<()> {
     pub fn version() -> u16 {
         unsafe { ObGetFilterVersion() }
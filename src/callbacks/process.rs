@@ -1,6 +1,6 @@
 use crate::{
     callbacks::{WduCallbackError, WduCallbackResult},
-    common::process::WduProcess,
+    common::{child::WduChild, process::WduProcess},
     io::file_obj::WduFileObject,
     WduUnicodeStr,
 };
@@ -69,7 +69,7 @@ pub type PsNotifyRoutineEx =
 // let's override the windows-sys with our own prototype and we will define our own PCREATE_PROCESS_NOTIFY_ROUTINE_EX
 // with our own newtypes.
 mod nt {
-    #[link(name = "ntoskrnl")]
+    #[cfg_attr(not(test), link(name = "ntoskrnl"))]
     extern "system" {
         pub(crate) fn PsSetCreateProcessNotifyRoutineEx(
             notify_routine: super::PsNotifyRoutineEx,
@@ -151,3 +151,10 @@ impl WduPsCallback {
         Ok(())
     }
 }
+
+// Unregistering waits for the callbacks in flight
+impl WduChild for WduPsCallback {
+    fn teardown(&mut self) {
+        let _ = self.unregister();
+    }
+}
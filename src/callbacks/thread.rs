@@ -1,4 +1,7 @@
-use crate::callbacks::{WduCallbackError, WduCallbackResult};
+use crate::{
+    callbacks::{WduCallbackError, WduCallbackResult},
+    common::child::WduChild,
+};
 use windows_sys::{
     Wdk::System::SystemServices::{
         PsRemoveCreateThreadNotifyRoutine, PsSetCreateThreadNotifyRoutine,
@@ -70,3 +73,9 @@ impl WduThCallback {
         Ok(())
     }
 }
+
+impl WduChild for WduThCallback {
+    fn teardown(&mut self) {
+        let _ = self.unregister();
+    }
+}
@@ -0,0 +1,127 @@
+//! WDF-style object hierarchy. Objects added as children of the [WduDriver] or of a
+//! [WduDevice] are torn down in reverse order of creation when their parent goes away.
+//!
+//! [WduDriver]: crate::common::driver::WduDriver
+//! [WduDevice]: crate::io::device::WduDevice
+use crate::sync::spinlock::WduSpinLock;
+use alloc::boxed::Box;
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use snafu::Snafu;
+
+#[derive(Debug, Snafu, Clone, Copy, PartialEq, Eq)]
+pub enum WduChildError {
+    #[snafu(display("Unable to allocate the child"))]
+    AllocationError,
+    #[snafu(display("The object can't have children"))]
+    Unsupported,
+}
+
+pub type WduChildResult<T> = Result<T, WduChildError>;
+
+/// Object that can be owned by a parent. Called at PASSIVE_LEVEL when the parent goes away, must
+/// not return while the object still has work in flight (e.g. a running DPC or callback).
+pub trait WduChild {
+    fn teardown(&mut self);
+}
+
+struct ChildNode<T: ?Sized> {
+    next: ChildNodes,
+    child: T,
+}
+
+type ChildNodes = Option<Box<ChildNode<dyn WduChild>>>;
+
+// Children of a driver or device, the parent keeps a pointer to the list which is only
+// allocated when the first child is added. Children can be added from any thread, the list is
+// protected by a spin lock same as the context lists.
+pub(crate) struct WduChildList {
+    lock: UnsafeCell<WduSpinLock>,
+    // Newest first, the boxes keep the children in place
+    head: UnsafeCell<ChildNodes>,
+}
+
+impl WduChildList {
+    fn locked<R>(&self, f: impl FnOnce(&mut ChildNodes) -> R) -> R {
+        let lock = self.lock.get();
+
+        unsafe {
+            (*lock).acquire();
+            let result = f(&mut *self.head.get());
+            (*lock).release();
+            result
+        }
+    }
+
+    // SAFETY: Same as add.
+    unsafe fn get_or_alloc<'a>(list: *mut *mut WduChildList) -> &'a WduChildList {
+        let list = AtomicPtr::from_ptr(list);
+
+        let children = list.load(Ordering::Acquire);
+        if !children.is_null() {
+            return &*children;
+        }
+
+        let mut new = Box::new(WduChildList {
+            lock: UnsafeCell::new(WduSpinLock::new()),
+            head: UnsafeCell::new(None),
+        });
+        new.lock.get_mut().init();
+
+        let new = Box::into_raw(new);
+        match list.compare_exchange(
+            core::ptr::null_mut(),
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => &*new,
+            Err(current) => {
+                // Another thread added the first child
+                drop(Box::from_raw(new));
+                &*current
+            }
+        }
+    }
+
+    // SAFETY: `list` points to the pointer owned by the parent, either null or a list allocated
+    // by `add`. The returned reference can't outlive the parent.
+    pub(crate) unsafe fn add<'a, T: WduChild + 'static>(
+        list: *mut *mut WduChildList,
+        child: T,
+    ) -> WduChildResult<&'a T> {
+        let children = Self::get_or_alloc(list);
+
+        // Allocated before taking the lock, objects like timers can't move once initialized
+        let node = Box::new(ChildNode { next: None, child });
+        let child: *const T = &node.child;
+        let mut node: Box<ChildNode<dyn WduChild>> = node;
+
+        children.locked(|head| {
+            node.next = head.take();
+            *head = Some(node);
+        });
+
+        Ok(&*child)
+    }
+
+    // SAFETY: Same as add, no reference returned by add can be alive.
+    pub(crate) unsafe fn teardown(list: *mut *mut WduChildList) {
+        let children = core::mem::replace(&mut *list, core::ptr::null_mut());
+        if !children.is_null() {
+            drop(Box::from_raw(children));
+        }
+    }
+}
+
+impl Drop for WduChildList {
+    fn drop(&mut self) {
+        let mut next = self.head.get_mut().take();
+        while let Some(mut node) = next {
+            node.child.teardown();
+            next = node.next.take();
+        }
+    }
+}
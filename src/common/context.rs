@@ -0,0 +1,248 @@
+use crate::sync::spinlock::WduSpinLock;
+use alloc::boxed::Box;
+use core::{
+    any::TypeId,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use snafu::Snafu;
+
+#[derive(Debug, Snafu, Clone, Copy, PartialEq, Eq)]
+pub enum WduContextError {
+    #[snafu(display("A context of the same type is already attached to the object"))]
+    AlreadyAttached,
+    #[snafu(display("Unable to allocate the context"))]
+    AllocationError,
+    #[snafu(display("The object can't hold contexts"))]
+    Unsupported,
+}
+
+pub type WduContextResult<T> = Result<T, WduContextError>;
+
+/// Called with the context right before it's dropped, when the object that owns it goes away.
+pub type WduContextCleanup<T> = fn(&mut T);
+
+trait ContextSlot {
+    fn context_type(&self) -> TypeId;
+    fn context(&self) -> *mut u8;
+    fn cleanup(&mut self);
+}
+
+struct Slot<T> {
+    context: UnsafeCell<T>,
+    cleanup: Option<WduContextCleanup<T>>,
+}
+
+impl<T: 'static> ContextSlot for Slot<T> {
+    fn context_type(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn context(&self) -> *mut u8 {
+        self.context.get().cast()
+    }
+
+    fn cleanup(&mut self) {
+        if let Some(cleanup) = self.cleanup.take() {
+            cleanup(self.context.get_mut());
+        }
+    }
+}
+
+struct Node<S: ?Sized> {
+    next: Option<Box<Node<dyn ContextSlot>>>,
+    slot: S,
+}
+
+type NodeList = Option<Box<Node<dyn ContextSlot>>>;
+
+/// Contexts attached to a library object, at most one per type. Each object keeps a pointer to
+/// its list, which is only allocated when the first context is attached.
+///
+/// Contexts can be attached and looked up from any thread, the list is protected by a spin lock
+/// and contexts don't move once attached.
+pub(crate) struct WduContextList {
+    lock: UnsafeCell<WduSpinLock>,
+    // Newest first
+    head: UnsafeCell<NodeList>,
+}
+
+impl WduContextList {
+    fn locked<R>(&self, f: impl FnOnce(&mut NodeList) -> R) -> R {
+        let lock = self.lock.get();
+
+        unsafe {
+            (*lock).acquire();
+            let result = f(&mut *self.head.get());
+            (*lock).release();
+            result
+        }
+    }
+
+    fn find(head: &NodeList, context_type: TypeId) -> Option<*mut u8> {
+        let mut node = head.as_deref();
+        while let Some(current) = node {
+            if current.slot.context_type() == context_type {
+                return Some(current.slot.context());
+            }
+            node = current.next.as_deref();
+        }
+
+        None
+    }
+
+    // SAFETY: Same as attach.
+    unsafe fn get_or_alloc<'a>(list: *mut *mut WduContextList) -> &'a WduContextList {
+        let list = AtomicPtr::from_ptr(list);
+
+        let contexts = list.load(Ordering::Acquire);
+        if !contexts.is_null() {
+            return &*contexts;
+        }
+
+        let mut new = Box::new(WduContextList {
+            lock: UnsafeCell::new(WduSpinLock::new()),
+            head: UnsafeCell::new(None),
+        });
+        new.lock.get_mut().init();
+
+        let new = Box::into_raw(new);
+        match list.compare_exchange(
+            core::ptr::null_mut(),
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => &*new,
+            Err(current) => {
+                // Another thread attached the first context
+                drop(Box::from_raw(new));
+                &*current
+            }
+        }
+    }
+
+    // SAFETY: `list` points to the pointer owned by the object, either null or a list allocated
+    // by `attach`.
+    pub(crate) unsafe fn attach<T: Send + Sync + 'static>(
+        list: *mut *mut WduContextList,
+        context: T,
+        cleanup: Option<WduContextCleanup<T>>,
+    ) -> WduContextResult<()> {
+        let contexts = Self::get_or_alloc(list);
+
+        // Allocated before taking the lock
+        let mut node: Box<Node<dyn ContextSlot>> = Box::new(Node {
+            next: None,
+            slot: Slot {
+                context: UnsafeCell::new(context),
+                cleanup,
+            },
+        });
+
+        let rejected = contexts.locked(|head| {
+            if Self::find(head, TypeId::of::<T>()).is_some() {
+                return Some(node);
+            }
+
+            node.next = head.take();
+            *head = Some(node);
+            None
+        });
+
+        // The rejected context is dropped once the lock is released
+        match rejected {
+            Some(_) => Err(WduContextError::AlreadyAttached),
+            None => Ok(()),
+        }
+    }
+
+    // SAFETY: Same as attach, the context can't be used once the object goes away.
+    pub(crate) unsafe fn get<T: 'static>(list: *mut *mut WduContextList) -> Option<*mut T> {
+        let contexts = AtomicPtr::from_ptr(list).load(Ordering::Acquire);
+        if contexts.is_null() {
+            return None;
+        }
+
+        (*contexts)
+            .locked(|head| Self::find(head, TypeId::of::<T>()))
+            .map(|context| context.cast())
+    }
+
+    // Runs the cleanup callbacks in reverse attach order and frees the list.
+    //
+    // SAFETY: Same as attach, no reference returned by get can be alive.
+    pub(crate) unsafe fn free(list: *mut *mut WduContextList) {
+        let contexts = core::mem::replace(&mut *list, core::ptr::null_mut());
+        if !contexts.is_null() {
+            drop(Box::from_raw(contexts));
+        }
+    }
+}
+
+impl Drop for WduContextList {
+    fn drop(&mut self) {
+        let mut next = self.head.get_mut().take();
+        while let Some(mut node) = next {
+            node.slot.cleanup();
+            next = node.next.take();
+        }
+    }
+}
+
+/// Implements the typed context methods for a library object. The object must provide
+/// `fn context_list(&self) -> Option<*mut *mut WduContextList>` returning the location of its
+/// list pointer, or None if the object can't hold contexts.
+macro_rules! typed_context {
+    ($type_name:ident $(<$param:ident>)?) => {
+        impl$(<$param>)? $type_name$(<$param>)? {
+            /// Attaches `context` to the object, at most one context per type. Contexts are
+            /// dropped when the object goes away, wherever that happens, hence the bounds.
+            ///
+            /// Contexts can be attached and retrieved concurrently at IRQL <= DISPATCH_LEVEL.
+            pub fn allocate_context<T: Send + Sync + 'static>(
+                &self,
+                context: T,
+            ) -> $crate::common::context::WduContextResult<()> {
+                let list = self
+                    .context_list()
+                    .ok_or($crate::common::context::WduContextError::Unsupported)?;
+                unsafe { $crate::common::context::WduContextList::attach(list, context, None) }
+            }
+
+            /// Same as [allocate_context](Self::allocate_context), `cleanup` is called with the
+            /// context before it's dropped.
+            pub fn allocate_context_with_cleanup<T: Send + Sync + 'static>(
+                &self,
+                context: T,
+                cleanup: $crate::common::context::WduContextCleanup<T>,
+            ) -> $crate::common::context::WduContextResult<()> {
+                let list = self
+                    .context_list()
+                    .ok_or($crate::common::context::WduContextError::Unsupported)?;
+                unsafe {
+                    $crate::common::context::WduContextList::attach(list, context, Some(cleanup))
+                }
+            }
+
+            /// Context of type `T` attached to the object, if any.
+            pub fn typed_context<T: Send + Sync + 'static>(&self) -> Option<&T> {
+                let list = self.context_list()?;
+                unsafe { $crate::common::context::WduContextList::get::<T>(list).map(|ctx| &*ctx) }
+            }
+
+            /// Mutable context of type `T` attached to the object, if any.
+            ///
+            /// # Safety
+            /// The caller must make sure no other reference to the context is alive while the
+            /// returned one is used.
+            #[allow(clippy::mut_from_ref)]
+            pub unsafe fn typed_context_mut<T: Send + Sync + 'static>(&self) -> Option<&mut T> {
+                let list = self.context_list()?;
+                $crate::common::context::WduContextList::get::<T>(list).map(|ctx| &mut *ctx)
+            }
+        }
+    };
+}
+
+pub(crate) use typed_context;
@@ -1,21 +1,41 @@
-use crate::io::{
-    create::WduCreate,
-    device::WduDevice,
-    device_control::WduDeviceControl,
-    file_obj::WduFileObject,
-    irp::{MajorFunction, WduIrp, WduPnpIrp, WduPowerIrp},
+use crate::{
+    common::{
+        child::{WduChild, WduChildError, WduChildList, WduChildResult},
+        context::{typed_context, WduContextList},
+    },
+    io::{
+        create::WduCreate,
+        device::WduDevice,
+        device_control::WduDeviceControl,
+        file_obj::WduFileObject,
+        ioctl::WduIoctlRouter,
+        irp::{MajorFunction, WduIrp, WduIrpDisposition},
+        pnp::WduPnpIrp,
+        power::WduPowerIrp,
+        read_write::WduReadWrite,
+    },
+    nt_success,
+    registry::reg::{WduRegKey, WduRegistryError, WduRegistryResult},
+    strings::unicode::{
+        str::WduUnicodeStr,
+        string::{CopyFlags, WduUnicodeString},
+        WduUnicodeError,
+    },
 };
-use core::ffi::c_void;
+use alloc::boxed::Box;
+use core::{cell::Cell, ffi::c_void};
 use snafu::Snafu;
+use widestring::{utf16str, Utf16Str};
 use windows_sys::{
     Wdk::{
         Foundation::{DEVICE_OBJECT, DRIVER_OBJECT, IRP},
         System::SystemServices::{
             IoAllocateDriverObjectExtension, IoGetDriverObjectExtension, IRP_MJ_CLEANUP,
-            IRP_MJ_CLOSE, IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL, IRP_MJ_READ, IRP_MJ_WRITE,
+            IRP_MJ_CLOSE, IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL, IRP_MJ_PNP, IRP_MJ_POWER,
+            IRP_MJ_READ, IRP_MJ_WRITE,
         },
     },
-    Win32::Foundation::{NTSTATUS, STATUS_SUCCESS},
+    Win32::Foundation::{NTSTATUS, STATUS_INVALID_DEVICE_REQUEST, STATUS_SUCCESS, UNICODE_STRING},
 };
 
 #[derive(Debug, Snafu)]
@@ -24,99 +44,684 @@ pub enum WduDriverError {
     DriverExtAllocFailed,
     #[snafu(display("WduDriver already initialized"))]
     AlreadyInit,
+    #[snafu(display("Unable to copy the registry path"))]
+    RegistryPath { source: WduUnicodeError },
 }
 
 pub type WduDriverResult<T> = Result<T, WduDriverError>;
 
-pub struct WduDriver {
+/// Driver object wrapper. `C` is a driver-wide context, handlers registered with the `_with`
+/// methods of the dispatch builders receive it along with the usual arguments. The context is
+/// dropped once the driver unloads.
+// repr(C) and no generic fields so the layout is the same for every context, the state is only
+// accessed through a WduDriver<C> of the type it was created with.
+#[repr(C)]
+pub struct WduDriver<C = ()> {
     init: bool,
     driver: *mut DRIVER_OBJECT,
-    // registry_path: WduUnicodeString, TODO
-    add_device: Option<WduAddDevice>,
-    unload: Option<WduDriverUnload>,
-    io: IoDispath,
-    fileobj: FileObjDispatch,
+    // Only valid during DriverEntry, build copies it into the state
+    registry_path: *const UNICODE_STRING,
+    // Shared by every copy of WduDriver, freed on unload
+    state: *mut DriverState<C>,
+    // Only used from the copy in the driver extension
+    contexts: Cell<*mut WduContextList>,
+    children: Cell<*mut WduChildList>,
+}
+
+// Handlers and driver context. The copy of WduDriver in the driver extension outlives the one
+// built in DriverEntry, so neither of them can own it.
+struct DriverState<C> {
+    context: C,
+    registry_path: WduUnicodeString,
+    add_device: Option<AddDeviceFn<C>>,
+    unload: Option<UnloadFn<C>>,
+    io: IoDispath<C>,
+    fileobj: FileObjDispatch<C>,
+    pnp: PnpDispatch<C>,
+    power: PowerDispatch<C>,
+    irp: [Option<IrpFn<C>>; MajorFunction::ALL.len()],
+    unhandled: WduUnhandledIrp,
 }
 
-pub type WduAddDevice = fn(&WduDriver) -> NTSTATUS;
-pub type WduDriverUnload = fn(&WduDriver) -> ();
+type AddDeviceFn<C> = Box<dyn Fn(&WduDriver<C>, &WduDevice) -> NTSTATUS + Send + Sync>;
+type UnloadFn<C> = Box<dyn Fn(&WduDriver<C>) + Send + Sync>;
+pub(crate) type DispatchFn<C, T> =
+    Box<dyn Fn(&C, &WduDevice, &mut WduIrp, T) -> WduIrpDisposition + Send + Sync>;
+type PnpFn<C> = Box<dyn Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync>;
+type PowerFn<C> =
+    Box<dyn Fn(&C, &WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS + Send + Sync>;
+type IrpFn<C> = Box<dyn Fn(&C, &WduDevice, &mut WduIrp) -> WduIrpDisposition + Send + Sync>;
+
+/// AddDevice routine. Receives the PDO created by the bus driver.
+pub type WduAddDevice<C = ()> = fn(&WduDriver<C>, &WduDevice) -> NTSTATUS;
+pub type WduDriverUnload<C = ()> = fn(&WduDriver<C>) -> ();
 
 // FileObject dispatch function definitions
-pub type WduCreateDispatch = fn(&WduDevice, &mut WduIrp, WduCreate);
-pub type WduCloseCleanupDispatch = fn(&WduDevice, &mut WduIrp, WduFileObject);
+pub type WduCreateDispatch = fn(&WduDevice, &mut WduIrp, WduCreate) -> WduIrpDisposition;
+pub type WduCloseCleanupDispatch = fn(&WduDevice, &mut WduIrp, WduFileObject) -> WduIrpDisposition;
+
+// I/O dispatch function definitions
+pub type WduReadWriteDispatch = fn(&WduDevice, &mut WduIrp, WduReadWrite) -> WduIrpDisposition;
+pub type WduIoctlDispatch = fn(&WduDevice, &mut WduIrp, WduDeviceControl) -> WduIrpDisposition;
+
+// Power Dispatch function definitions. As with PnP, the handler must not complete nor forward the
+// IRP.
+pub type WduPowerDispatch = fn(&WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS;
+
+// PNP Dispatch function definitions. The handler must not complete nor forward the IRP, the
+// library will do it based on the returned status.
+pub type WduPnpDispatch = fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS;
 
-// I/O dispatch function definitions (TODO: Check if we really need the NTSTATUS)
-pub type WduReadWriteDispatch = fn(&WduDevice, &mut WduIrp, usize) -> NTSTATUS;
-pub type WduIoctlDispatch = fn(&WduDevice, &mut WduIrp, WduDeviceControl) -> NTSTATUS;
+// Generic dispatch for any major function without a typed dispatch
+pub type WduIrpDispatch = fn(&WduDevice, &mut WduIrp) -> WduIrpDisposition;
 
-// Power Dispatch function definitions
-pub type WduPowerDispatch = fn(&WduDevice, &mut WduIrp, WduPowerIrp) -> NTSTATUS;
+/// What the library does with IRPs the driver didn't register a handler for.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum WduUnhandledIrp {
+    /// Complete the IRP with STATUS_INVALID_DEVICE_REQUEST
+    #[default]
+    Complete,
+    /// Pass the IRP down to the lower device. Meant for filter drivers, devices without a lower
+    /// device complete the IRP with STATUS_INVALID_DEVICE_REQUEST.
+    PassDown,
+}
+
+const UNHANDLED: WduIrpDisposition = WduIrpDisposition::Completed(STATUS_INVALID_DEVICE_REQUEST);
 
-// PNP Dispatch function definitions
-pub type WduPnpDispatch = fn(&WduDevice, &mut WduIrp, WduPnpIrp) -> NTSTATUS;
+const PARAMETERS_KEY: &Utf16Str = utf16str!("Parameters");
 
-const WKR_DRIVER_ID: *mut c_void = WduDriver::get_wdu_driver as *mut c_void;
+const WKR_DRIVER_ID: *mut c_void = is_wdu_driver as *mut c_void;
 
-#[derive(Default)]
-pub struct FileObjDispatch {
-    create: Option<WduCreateDispatch>,
-    close: Option<WduCloseCleanupDispatch>,
-    cleanup: Option<WduCloseCleanupDispatch>,
+// Whether `driver` was built by the library, regardless of its context type
+pub(crate) fn is_wdu_driver(driver: *const DRIVER_OBJECT) -> bool {
+    unsafe { !IoGetDriverObjectExtension(driver, WKR_DRIVER_ID).is_null() }
+}
+
+pub struct FileObjDispatch<C = ()> {
+    create: Option<DispatchFn<C, WduCreate>>,
+    close: Option<DispatchFn<C, WduFileObject>>,
+    cleanup: Option<DispatchFn<C, WduFileObject>>,
+}
+
+impl<C> Default for FileObjDispatch<C> {
+    fn default() -> Self {
+        Self {
+            create: None,
+            close: None,
+            cleanup: None,
+        }
+    }
 }
 
-impl FileObjDispatch {
-    pub fn create_irp(mut self, create: WduCreateDispatch) -> Self {
-        self.create = Some(create);
+impl<C: 'static> FileObjDispatch<C> {
+    pub fn create_irp<F>(self, create: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, WduCreate) -> WduIrpDisposition + Send + Sync + 'static,
+    {
+        self.create_irp_with(move |_, device, irp, params| create(device, irp, params))
+    }
+
+    pub fn create_irp_with<F>(mut self, create: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, WduCreate) -> WduIrpDisposition + Send + Sync + 'static,
+    {
+        self.create = Some(Box::new(create));
         self
     }
 
-    pub fn close_irp(mut self, close: WduCloseCleanupDispatch) -> Self {
-        self.close = Some(close);
+    pub fn close_irp<F>(self, close: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, WduFileObject) -> WduIrpDisposition + Send + Sync + 'static,
+    {
+        self.close_irp_with(move |_, device, irp, file_object| close(device, irp, file_object))
+    }
+
+    pub fn close_irp_with<F>(mut self, close: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, WduFileObject) -> WduIrpDisposition
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.close = Some(Box::new(close));
         self
     }
 
-    pub fn cleanup_irp(mut self, cleanup: WduCloseCleanupDispatch) -> Self {
-        self.cleanup = Some(cleanup);
+    pub fn cleanup_irp<F>(self, cleanup: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, WduFileObject) -> WduIrpDisposition + Send + Sync + 'static,
+    {
+        self.cleanup_irp_with(move |_, device, irp, file_object| cleanup(device, irp, file_object))
+    }
+
+    pub fn cleanup_irp_with<F>(mut self, cleanup: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, WduFileObject) -> WduIrpDisposition
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.cleanup = Some(Box::new(cleanup));
         self
     }
 }
 
-#[derive(Default)]
-pub struct IoDispath {
-    read: Option<WduReadWriteDispatch>,
-    write: Option<WduReadWriteDispatch>,
-    ioctl: Option<WduIoctlDispatch>,
+impl<C> FileObjDispatch<C> {
+    fn handles(&self, major: MajorFunction) -> bool {
+        match major {
+            MajorFunction::Create => self.create.is_some(),
+            MajorFunction::Close => self.close.is_some(),
+            MajorFunction::Cleanup => self.cleanup.is_some(),
+            _ => false,
+        }
+    }
+}
+
+pub struct IoDispath<C = ()> {
+    read: Option<DispatchFn<C, WduReadWrite>>,
+    write: Option<DispatchFn<C, WduReadWrite>>,
+    ioctl: Option<DispatchFn<C, WduDeviceControl>>,
+    router: Option<WduIoctlRouter<C>>,
 }
 
-impl IoDispath {
-    pub fn read_irp(mut self, read: WduReadWriteDispatch) -> Self {
-        self.read = Some(read);
+impl<C> Default for IoDispath<C> {
+    fn default() -> Self {
+        Self {
+            read: None,
+            write: None,
+            ioctl: None,
+            router: None,
+        }
+    }
+}
+
+impl<C: 'static> IoDispath<C> {
+    pub fn read_irp<F>(self, read: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, WduReadWrite) -> WduIrpDisposition + Send + Sync + 'static,
+    {
+        self.read_irp_with(move |_, device, irp, params| read(device, irp, params))
+    }
+
+    pub fn read_irp_with<F>(mut self, read: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, WduReadWrite) -> WduIrpDisposition
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.read = Some(Box::new(read));
         self
     }
 
-    pub fn write_irp(mut self, write: WduReadWriteDispatch) -> Self {
-        self.write = Some(write);
+    pub fn write_irp<F>(self, write: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, WduReadWrite) -> WduIrpDisposition + Send + Sync + 'static,
+    {
+        self.write_irp_with(move |_, device, irp, params| write(device, irp, params))
+    }
+
+    pub fn write_irp_with<F>(mut self, write: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, WduReadWrite) -> WduIrpDisposition
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.write = Some(Box::new(write));
         self
     }
 
-    pub fn ioctl_irp(mut self, ioct: WduIoctlDispatch) -> Self {
-        self.ioctl = Some(ioct);
+    pub fn ioctl_irp<F>(self, ioctl: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, WduDeviceControl) -> WduIrpDisposition
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.ioctl_irp_with(move |_, device, irp, params| ioctl(device, irp, params))
+    }
+
+    pub fn ioctl_irp_with<F>(mut self, ioctl: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, WduDeviceControl) -> WduIrpDisposition
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.ioctl = Some(Box::new(ioctl));
+        self
+    }
+
+    /// Routes device control IRPs by code. IOCTLs without a route fall back to the
+    /// [ioctl_irp](Self::ioctl_irp) handler. The router is only freed if the driver registers an
+    /// [unload](WduDriver::unload) routine.
+    pub fn ioctl_router(mut self, router: WduIoctlRouter<C>) -> Self {
+        self.router = Some(router);
         self
     }
 }
 
-impl WduDriver {
-    pub fn new(driver: *mut DRIVER_OBJECT) -> Self {
+impl<C> IoDispath<C> {
+    fn handles(&self, major: MajorFunction) -> bool {
+        match major {
+            MajorFunction::Read => self.read.is_some(),
+            MajorFunction::Write => self.write.is_some(),
+            MajorFunction::DeviceControl => self.ioctl.is_some() || self.router.is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// PnP minor function handlers.
+///
+/// Minor functions without a handler are passed down to the lower device. If there's no lower
+/// device (e.g. PDOs) the IRP is completed without modifying its status.
+///
+/// For `StartDevice` and `QueryCapabilities` the IRP is first sent synchronously to the lower
+/// device and the handler is only called if the lower device succeeded. For the rest of minor
+/// functions the handler is called first and the IRP is passed down only if the handler
+/// succeeded, otherwise it's completed with the status returned by the handler.
+pub struct PnpDispatch<C = ()> {
+    start: Option<PnpFn<C>>,
+    query_stop: Option<PnpFn<C>>,
+    stop: Option<PnpFn<C>>,
+    cancel_stop: Option<PnpFn<C>>,
+    query_remove: Option<PnpFn<C>>,
+    remove: Option<PnpFn<C>>,
+    cancel_remove: Option<PnpFn<C>>,
+    surprise_removal: Option<PnpFn<C>>,
+    query_capabilities: Option<PnpFn<C>>,
+    query_relations: Option<PnpFn<C>>,
+    other: Option<PnpFn<C>>,
+}
+
+impl<C> Default for PnpDispatch<C> {
+    fn default() -> Self {
         Self {
-            driver,
+            start: None,
+            query_stop: None,
+            stop: None,
+            cancel_stop: None,
+            query_remove: None,
+            remove: None,
+            cancel_remove: None,
+            surprise_removal: None,
+            query_capabilities: None,
+            query_relations: None,
+            other: None,
+        }
+    }
+}
+
+impl<C: 'static> PnpDispatch<C> {
+    pub fn start_device_irp<F>(self, start: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.start_device_irp_with(move |_, device, irp, minor| start(device, irp, minor))
+    }
+
+    pub fn start_device_irp_with<F>(mut self, start: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.start = Some(Box::new(start));
+        self
+    }
+
+    pub fn query_stop_irp<F>(self, query_stop: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_stop_irp_with(move |_, device, irp, minor| query_stop(device, irp, minor))
+    }
+
+    pub fn query_stop_irp_with<F>(mut self, query_stop: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_stop = Some(Box::new(query_stop));
+        self
+    }
+
+    pub fn stop_device_irp<F>(self, stop: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.stop_device_irp_with(move |_, device, irp, minor| stop(device, irp, minor))
+    }
+
+    pub fn stop_device_irp_with<F>(mut self, stop: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.stop = Some(Box::new(stop));
+        self
+    }
+
+    pub fn cancel_stop_irp<F>(self, cancel_stop: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.cancel_stop_irp_with(move |_, device, irp, minor| cancel_stop(device, irp, minor))
+    }
+
+    pub fn cancel_stop_irp_with<F>(mut self, cancel_stop: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.cancel_stop = Some(Box::new(cancel_stop));
+        self
+    }
+
+    pub fn query_remove_irp<F>(self, query_remove: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_remove_irp_with(move |_, device, irp, minor| query_remove(device, irp, minor))
+    }
+
+    pub fn query_remove_irp_with<F>(mut self, query_remove: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_remove = Some(Box::new(query_remove));
+        self
+    }
+
+    /// Removal can't fail, the status returned by the handler is ignored. Once the handler
+    /// returns, the library passes the IRP down, waits for the IRPs still being dispatched to
+    /// the device, detaches from the lower device and deletes the device.
+    pub fn remove_device_irp<F>(self, remove: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.remove_device_irp_with(move |_, device, irp, minor| remove(device, irp, minor))
+    }
+
+    pub fn remove_device_irp_with<F>(mut self, remove: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.remove = Some(Box::new(remove));
+        self
+    }
+
+    pub fn cancel_remove_irp<F>(self, cancel_remove: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.cancel_remove_irp_with(move |_, device, irp, minor| cancel_remove(device, irp, minor))
+    }
+
+    pub fn cancel_remove_irp_with<F>(mut self, cancel_remove: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.cancel_remove = Some(Box::new(cancel_remove));
+        self
+    }
+
+    pub fn surprise_removal_irp<F>(self, surprise_removal: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.surprise_removal_irp_with(move |_, device, irp, minor| {
+            surprise_removal(device, irp, minor)
+        })
+    }
+
+    pub fn surprise_removal_irp_with<F>(mut self, surprise_removal: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.surprise_removal = Some(Box::new(surprise_removal));
+        self
+    }
+
+    pub fn query_capabilities_irp<F>(self, query_capabilities: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_capabilities_irp_with(move |_, device, irp, minor| {
+            query_capabilities(device, irp, minor)
+        })
+    }
+
+    pub fn query_capabilities_irp_with<F>(mut self, query_capabilities: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_capabilities = Some(Box::new(query_capabilities));
+        self
+    }
+
+    pub fn query_relations_irp<F>(self, query_relations: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_relations_irp_with(move |_, device, irp, minor| {
+            query_relations(device, irp, minor)
+        })
+    }
+
+    pub fn query_relations_irp_with<F>(mut self, query_relations: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_relations = Some(Box::new(query_relations));
+        self
+    }
+
+    /// Handler for any minor function not covered by the rest of handlers
+    pub fn other_irp<F>(self, other: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.other_irp_with(move |_, device, irp, minor| other(device, irp, minor))
+    }
+
+    pub fn other_irp_with<F>(mut self, other: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPnpIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.other = Some(Box::new(other));
+        self
+    }
+}
+
+impl<C> PnpDispatch<C> {
+    fn handler(&self, pnp_irp: &WduPnpIrp) -> Option<&PnpFn<C>> {
+        match pnp_irp {
+            WduPnpIrp::StartDevice { .. } => self.start.as_ref(),
+            WduPnpIrp::QueryStopDevice => self.query_stop.as_ref(),
+            WduPnpIrp::StopDevice => self.stop.as_ref(),
+            WduPnpIrp::CancelStopDevice => self.cancel_stop.as_ref(),
+            WduPnpIrp::QueryRemoveDevice => self.query_remove.as_ref(),
+            WduPnpIrp::RemoveDevice => self.remove.as_ref(),
+            WduPnpIrp::CancelRemoveDevice => self.cancel_remove.as_ref(),
+            WduPnpIrp::SurpriseRemoval => self.surprise_removal.as_ref(),
+            WduPnpIrp::QueryCapabilities(_) => self.query_capabilities.as_ref(),
+            WduPnpIrp::QueryDeviceRelations(_) => self.query_relations.as_ref(),
+            WduPnpIrp::Other(_) => self.other.as_ref(),
+        }
+    }
+}
+
+/// Power minor function handlers.
+///
+/// If the handler succeeds, or there's no handler, the IRP is passed down to the lower device.
+/// Devices without a lower device complete the IRP. Unhandled `SetPower` and `QueryPower` IRPs
+/// are succeeded, the rest of minor functions are passed down without modifying their status.
+///
+/// The library doesn't call `PoSetPowerState` on behalf of the driver, the `SetPower` handler is
+/// expected to call [WduDevice::set_power_state] when the device state changes.
+pub struct PowerDispatch<C = ()> {
+    set_power: Option<PowerFn<C>>,
+    query_power: Option<PowerFn<C>>,
+    wait_wake: Option<PowerFn<C>>,
+    other: Option<PowerFn<C>>,
+}
+
+impl<C> Default for PowerDispatch<C> {
+    fn default() -> Self {
+        Self {
+            set_power: None,
+            query_power: None,
+            wait_wake: None,
+            other: None,
+        }
+    }
+}
+
+impl<C: 'static> PowerDispatch<C> {
+    pub fn set_power_irp<F>(self, set_power: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.set_power_irp_with(move |_, device, irp, minor| set_power(device, irp, minor))
+    }
+
+    pub fn set_power_irp_with<F>(mut self, set_power: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.set_power = Some(Box::new(set_power));
+        self
+    }
+
+    pub fn query_power_irp<F>(self, query_power: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_power_irp_with(move |_, device, irp, minor| query_power(device, irp, minor))
+    }
+
+    pub fn query_power_irp_with<F>(mut self, query_power: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.query_power = Some(Box::new(query_power));
+        self
+    }
+
+    pub fn wait_wake_irp<F>(self, wait_wake: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.wait_wake_irp_with(move |_, device, irp, minor| wait_wake(device, irp, minor))
+    }
+
+    pub fn wait_wake_irp_with<F>(mut self, wait_wake: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.wait_wake = Some(Box::new(wait_wake));
+        self
+    }
+
+    /// Handler for any minor function not covered by the rest of handlers
+    pub fn other_irp<F>(self, other: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.other_irp_with(move |_, device, irp, minor| other(device, irp, minor))
+    }
+
+    pub fn other_irp_with<F>(mut self, other: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, &mut WduPowerIrp) -> NTSTATUS + Send + Sync + 'static,
+    {
+        self.other = Some(Box::new(other));
+        self
+    }
+}
+
+impl<C> PowerDispatch<C> {
+    fn handler(&self, power_irp: &WduPowerIrp) -> Option<&PowerFn<C>> {
+        match power_irp {
+            WduPowerIrp::SetPower { .. } => self.set_power.as_ref(),
+            WduPowerIrp::QueryPower { .. } => self.query_power.as_ref(),
+            WduPowerIrp::WaitWake(_) => self.wait_wake.as_ref(),
+            WduPowerIrp::PowerSequence | WduPowerIrp::Other(_) => self.other.as_ref(),
+        }
+    }
+}
+
+impl WduDriver {
+    /// `registry_path` is the one received in DriverEntry, it's copied when the driver is built.
+    pub fn new(driver: *mut DRIVER_OBJECT, registry_path: *const UNICODE_STRING) -> Self {
+        Self::with_context(driver, registry_path, ())
+    }
+}
+
+impl<C> WduDriver<C> {
+    /// Creates the driver with `context` as driver context, see [context](Self::context). Handlers
+    /// run concurrently on any processor, hence the bounds on the context.
+    pub fn with_context(
+        driver: *mut DRIVER_OBJECT,
+        registry_path: *const UNICODE_STRING,
+        context: C,
+    ) -> Self
+    where
+        C: Send + Sync,
+    {
+        let state = DriverState {
+            context,
+            registry_path: WduUnicodeString::default(),
             add_device: None,
             unload: None,
-            init: false,
             io: IoDispath::default(),
             fileobj: FileObjDispatch::default(),
+            pnp: PnpDispatch::default(),
+            power: PowerDispatch::default(),
+            irp: core::array::from_fn(|_| None),
+            unhandled: WduUnhandledIrp::default(),
+        };
+
+        Self {
+            driver,
+            init: false,
+            registry_path,
+            state: Box::into_raw(Box::new(state)),
+            contexts: Cell::new(core::ptr::null_mut()),
+            children: Cell::new(core::ptr::null_mut()),
         }
     }
 
+    /// Handlers can run concurrently, use interior mutability for state that changes after
+    /// DriverEntry.
+    pub fn context(&self) -> &C {
+        &self.state().context
+    }
+
+    /// Service key of the driver, e.g.
+    /// `\Registry\Machine\System\CurrentControlSet\Services\Foo`. Empty until the driver is
+    /// built.
+    pub fn registry_path(&self) -> &WduUnicodeString {
+        &self.state().registry_path
+    }
+
+    /// Opens the `Parameters` subkey of the service key, where drivers usually keep their
+    /// configuration.
+    pub fn parameters(&self) -> WduRegistryResult<WduRegKey> {
+        if self.registry_path().is_empty() {
+            return Err(WduRegistryError::NotFound);
+        }
+
+        let service = WduRegKey::open(&WduUnicodeStr::from_slice(self.registry_path().as_slice()))?;
+        service.open_subkey(&WduUnicodeStr::from_slice(PARAMETERS_KEY.as_slice()))
+    }
+
+    fn state(&self) -> &DriverState<C> {
+        unsafe { &*self.state }
+    }
+
+    fn state_mut(&mut self) -> &mut DriverState<C> {
+        unsafe { &mut *self.state }
+    }
+
     pub(crate) fn as_ptr(&self) -> *const DRIVER_OBJECT {
         self.driver as *const _
     }
@@ -127,7 +732,10 @@ impl WduDriver {
         WduDevice::wrap_device(dev_obj)
     }
 
-    pub fn device_add(mut self, device_add: WduAddDevice) -> Self {
+    pub fn device_add<F>(mut self, device_add: F) -> Self
+    where
+        F: Fn(&WduDriver<C>, &WduDevice) -> NTSTATUS + Send + Sync + 'static,
+    {
         unsafe {
             let ext = (*self.driver).DriverExtension;
             if !ext.is_null() {
@@ -136,21 +744,24 @@ impl WduDriver {
             }
         }
 
-        self.add_device = Some(device_add);
+        self.state_mut().add_device = Some(Box::new(device_add));
         self
     }
 
-    pub fn unload(mut self, driver_unload: WduDriverUnload) -> Self {
+    pub fn unload<F>(mut self, driver_unload: F) -> Self
+    where
+        F: Fn(&WduDriver<C>) + Send + Sync + 'static,
+    {
         unsafe {
             let pfn = Self::driver_unload as *mut u8;
             (*self.driver).DriverUnload = Some(core::mem::transmute_copy(&pfn));
         }
 
-        self.unload = Some(driver_unload);
+        self.state_mut().unload = Some(Box::new(driver_unload));
         self
     }
 
-    pub fn file_object(mut self, dispatch_rtns: FileObjDispatch) -> Self {
+    pub fn file_object(mut self, dispatch_rtns: FileObjDispatch<C>) -> Self {
         let file_obj_mj = [IRP_MJ_CREATE, IRP_MJ_CLOSE, IRP_MJ_CLEANUP];
 
         let pfn = Self::dispatch_handler as *mut u8;
@@ -161,11 +772,11 @@ impl WduDriver {
             }
         }
 
-        self.fileobj = dispatch_rtns;
+        self.state_mut().fileobj = dispatch_rtns;
         self
     }
 
-    pub fn io(mut self, dispatch_rtns: IoDispath) -> Self {
+    pub fn io(mut self, dispatch_rtns: IoDispath<C>) -> Self {
         let io_mj = [IRP_MJ_READ, IRP_MJ_WRITE, IRP_MJ_DEVICE_CONTROL];
 
         let pfn = Self::dispatch_handler as *mut u8;
@@ -175,16 +786,80 @@ impl WduDriver {
             }
         }
 
-        self.io = dispatch_rtns;
+        self.state_mut().io = dispatch_rtns;
+        self
+    }
+
+    pub fn power(mut self, dispatch_rtns: PowerDispatch<C>) -> Self {
+        let pfn = Self::dispatch_handler as *mut u8;
+        unsafe {
+            (*self.driver).MajorFunction[IRP_MJ_POWER as usize] =
+                Some(core::mem::transmute_copy(&pfn));
+        }
+
+        self.state_mut().power = dispatch_rtns;
+        self
+    }
+
+    pub fn pnp(mut self, dispatch_rtns: PnpDispatch<C>) -> Self {
+        let pfn = Self::dispatch_handler as *mut u8;
+        unsafe {
+            (*self.driver).MajorFunction[IRP_MJ_PNP as usize] =
+                Some(core::mem::transmute_copy(&pfn));
+        }
+
+        self.state_mut().pnp = dispatch_rtns;
         self
     }
 
-    pub fn power(mut self) -> Self {
-        todo!()
+    /// Registers a handler for a major function without a typed dispatch. Handlers registered
+    /// with [file_object](Self::file_object) or [io](Self::io) take precedence, power and PnP
+    /// IRPs are always routed to [power](Self::power) and [pnp](Self::pnp).
+    pub fn major_function<F>(self, major: MajorFunction, dispatch: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp) -> WduIrpDisposition + Send + Sync + 'static,
+    {
+        self.major_function_with(major, move |_, device, irp| dispatch(device, irp))
+    }
+
+    pub fn major_function_with<F>(mut self, major: MajorFunction, dispatch: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp) -> WduIrpDisposition + Send + Sync + 'static,
+    {
+        let pfn = Self::dispatch_handler as *mut u8;
+        unsafe {
+            (*self.driver).MajorFunction[major.index()] = Some(core::mem::transmute_copy(&pfn));
+        }
+
+        self.state_mut().irp[major.index()] = Some(Box::new(dispatch));
+        self
     }
 
-    pub fn pnp(mut self) -> Self {
-        todo!()
+    /// Routes `major` through the library so devices can deliver it to the
+    /// [WduIoQueue](crate::io::queue::WduIoQueue) set with
+    /// [WduDevice::set_queue](crate::io::device::WduDevice::set_queue). IRPs sent to devices
+    /// without a queue follow the usual handlers.
+    pub fn io_queue(self, major: MajorFunction) -> Self {
+        let pfn = Self::dispatch_handler as *mut u8;
+        unsafe {
+            (*self.driver).MajorFunction[major.index()] = Some(core::mem::transmute_copy(&pfn));
+        }
+
+        self
+    }
+
+    /// Sets the policy for IRPs without a handler. Every major function is routed through the
+    /// library so the policy also applies to major functions that were never registered.
+    pub fn unhandled_irp(mut self, policy: WduUnhandledIrp) -> Self {
+        let pfn = Self::dispatch_handler as *mut u8;
+        for major in MajorFunction::ALL {
+            unsafe {
+                (*self.driver).MajorFunction[major.index()] = Some(core::mem::transmute_copy(&pfn));
+            }
+        }
+
+        self.state_mut().unhandled = policy;
+        self
     }
 
     pub fn build(mut self) -> WduDriverResult<Self> {
@@ -192,6 +867,10 @@ impl WduDriver {
             return Err(WduDriverError::AlreadyInit);
         }
 
+        self.state_mut().registry_path = WduUnicodeString::wrap(self.registry_path)
+            .duplicate(CopyFlags::DestNullTerminated)
+            .map_err(|source| WduDriverError::RegistryPath { source })?;
+
         unsafe {
             self.alloc_ext()?;
         }
@@ -199,57 +878,240 @@ impl WduDriver {
         Ok(self)
     }
 
-    // TODO: Pass WduDevice, check if filter, etc...
-    fn add_device_internal(&self, device: &WduDevice) -> NTSTATUS {
-        self.add_device
-            .map_or_else(|| STATUS_SUCCESS, |add_device| add_device(self))
+    // TODO: Check if filter, etc...
+    fn add_device_internal(&self, pdo: &WduDevice) -> NTSTATUS {
+        self.state()
+            .add_device
+            .as_ref()
+            .map_or_else(|| STATUS_SUCCESS, |add_device| add_device(self, pdo))
     }
 
+    // Children, contexts and the driver context are released after the unload routine so it can
+    // still use them
     fn unload_internal(&self) {
-        self.unload.map(|unload| unload(self));
+        if let Some(unload) = &self.state().unload {
+            unload(self);
+        }
+
+        unsafe {
+            WduChildList::teardown(self.children.as_ptr());
+            self.delete_devices();
+            WduContextList::free(self.contexts.as_ptr());
+            drop(Box::from_raw(self.state));
+        }
+    }
+
+    // Devices are implicit children of the driver, delete the ones still alive. New devices are
+    // inserted at the head of the list, so this goes in reverse order of creation.
+    unsafe fn delete_devices(&self) {
+        let mut device = (*self.driver).DeviceObject;
+        while !device.is_null() {
+            let next = (*device).NextDevice;
+
+            let wdu_device = WduDevice::wrap_device(device);
+            wdu_device.detach();
+            wdu_device.delete();
+
+            device = next;
+        }
     }
 
-    fn fo_dispatch(&self, device: &WduDevice, irp: &mut WduIrp) {
-        match irp.major() {
-            MajorFunction::Create => self.fileobj.create.map(|pfn| {
+    fn fo_dispatch(&self, device: &WduDevice, irp: &mut WduIrp) -> NTSTATUS {
+        let (fileobj, ctx) = (&self.state().fileobj, self.context());
+        let disposition = match irp.major() {
+            MajorFunction::Create => fileobj.create.as_ref().map(|pfn| {
                 let create = unsafe { WduCreate::new(irp) };
-                pfn(device, irp, create)
+                pfn(ctx, device, irp, create)
             }),
-            MajorFunction::Close => self.fileobj.close.map(|pfn| {
+            MajorFunction::Close => fileobj.close.as_ref().map(|pfn| {
                 let file_object = irp.file_object();
-                pfn(device, irp, file_object)
+                pfn(ctx, device, irp, file_object)
             }),
-            MajorFunction::Cleanup => self.fileobj.cleanup.map(|pfn| {
+            MajorFunction::Cleanup => fileobj.cleanup.as_ref().map(|pfn| {
                 let file_object = irp.file_object();
-                pfn(device, irp, file_object)
+                pfn(ctx, device, irp, file_object)
             }),
             _ => unreachable!("Invalid FileObject dispatch"),
         };
+
+        irp.finish(disposition.unwrap_or(UNHANDLED))
     }
 
     fn io_dispatch(&self, device: &WduDevice, irp: &mut WduIrp) -> NTSTATUS {
-        let status = match irp.major() {
-            MajorFunction::Read => self.io.read.map(|pfn| pfn(device, irp, 0)),
-            MajorFunction::Write => self.io.write.map(|pfn| pfn(device, irp, 0)),
-            MajorFunction::DeviceControl => self.io.ioctl.map(|pfn| {
-                let ioctl = unsafe { WduDeviceControl::new(irp) };
-                pfn(device, irp, ioctl)
+        let (io, ctx) = (&self.state().io, self.context());
+        let disposition = match irp.major() {
+            MajorFunction::Read => io.read.as_ref().map(|pfn| {
+                let read = unsafe { WduReadWrite::new(device, irp) };
+                pfn(ctx, device, irp, read)
             }),
+            MajorFunction::Write => io.write.as_ref().map(|pfn| {
+                let write = unsafe { WduReadWrite::new(device, irp) };
+                pfn(ctx, device, irp, write)
+            }),
+            MajorFunction::DeviceControl => {
+                let ioctl = unsafe { WduDeviceControl::new(irp) };
+
+                match &io.router {
+                    Some(router) if router.handles(&ioctl) => {
+                        router.dispatch(ctx, device, irp, ioctl)
+                    }
+                    _ => io.ioctl.as_ref().map(|pfn| pfn(ctx, device, irp, ioctl)),
+                }
+            }
             _ => unreachable!("Invalid I/O dispatch"),
         };
 
-        status.map_or_else(|| STATUS_SUCCESS, |status| status)
+        irp.finish(disposition.unwrap_or(UNHANDLED))
     }
 
     fn power_dispatch(&self, device: &WduDevice, irp: &mut WduIrp) -> NTSTATUS {
-        todo!()
+        let mut power_irp = unsafe { WduPowerIrp::new(&irp.current_stack_location()) };
+        let lower = device.lower_device();
+
+        let handler = self.state().power.handler(&power_irp);
+
+        let mut io_status = irp.io_status();
+        match handler {
+            Some(pfn) => io_status.set_status(pfn(self.context(), device, irp, &mut power_irp)),
+            None => {
+                if let WduPowerIrp::SetPower { .. } | WduPowerIrp::QueryPower { .. } = power_irp {
+                    io_status.set_status(STATUS_SUCCESS);
+                }
+            }
+        }
+
+        // Failed IRPs are completed, unhandled ones are always passed down
+        let status = io_status.status();
+        match lower {
+            Some(lower) if handler.is_none() || nt_success(status) => {
+                irp.set_io_status(io_status);
+                let disposition = irp.pass_through(&lower);
+                irp.finish(disposition)
+            }
+            _ => {
+                irp.start_next_power_irp();
+                irp.complete(io_status);
+                status
+            }
+        }
+    }
+
+    // Releases the remove lock acquired by the dispatch handler, IRP_MN_REMOVE_DEVICE waits for
+    // the IRPs still being dispatched and deletes the device
+    fn pnp_dispatch(&self, device: &WduDevice, irp: &mut WduIrp) -> NTSTATUS {
+        let mut pnp_irp = unsafe { WduPnpIrp::new(&irp.current_stack_location()) };
+        let status = self.pnp_dispatch_locked(device, irp, &mut pnp_irp);
+
+        if let WduPnpIrp::RemoveDevice = pnp_irp {
+            device.release_remove_lock_and_wait();
+            device.detach();
+            device.delete();
+        } else {
+            device.release_remove_lock();
+        }
+
+        status
+    }
+
+    fn pnp_dispatch_locked(
+        &self,
+        device: &WduDevice,
+        irp: &mut WduIrp,
+        pnp_irp: &mut WduPnpIrp,
+    ) -> NTSTATUS {
+        let handler = self.state().pnp.handler(pnp_irp);
+        let lower = device.lower_device();
+
+        if pnp_irp.lower_first() {
+            if let Some(lower) = &lower {
+                let status = irp.forward_and_wait(lower);
+                if !nt_success(status) {
+                    let mut io_status = irp.io_status();
+                    io_status.set_status(status);
+                    irp.complete(io_status);
+                    return status;
+                }
+            }
+
+            let status = match handler {
+                Some(pfn) => pfn(self.context(), device, irp, pnp_irp),
+                None => irp.io_status().status(),
+            };
+
+            if nt_success(status) {
+                device.update_pnp_state(pnp_irp);
+            }
+
+            let mut io_status = irp.io_status();
+            io_status.set_status(status);
+            irp.complete(io_status);
+
+            return status;
+        }
+
+        // Unhandled state changing minors succeed, the rest are passed down untouched
+        let status = match handler {
+            Some(pfn) => pfn(self.context(), device, irp, pnp_irp),
+            None => {
+                if let WduPnpIrp::QueryDeviceRelations(_) | WduPnpIrp::Other(_) = pnp_irp {
+                    return self.pnp_pass_down(irp, lower.as_ref());
+                }
+                STATUS_SUCCESS
+            }
+        };
+
+        // IRP_MN_REMOVE_DEVICE can't fail, the device goes away anyway
+        let status = match pnp_irp {
+            WduPnpIrp::RemoveDevice => STATUS_SUCCESS,
+            _ => status,
+        };
+
+        if !nt_success(status) {
+            let mut io_status = irp.io_status();
+            io_status.set_status(status);
+            irp.complete(io_status);
+            return status;
+        }
+
+        device.update_pnp_state(pnp_irp);
+
+        let mut io_status = irp.io_status();
+        io_status.set_status(status);
+        irp.set_io_status(io_status);
+
+        self.pnp_pass_down(irp, lower.as_ref())
     }
 
-    fn pnp_dispatch(&self) -> NTSTATUS {
-        todo!()
+    fn unhandled_dispatch(&self, device: &WduDevice, irp: &mut WduIrp) -> NTSTATUS {
+        let state = self.state();
+        if let Some(pfn) = &state.irp[irp.major().index()] {
+            let disposition = pfn(&state.context, device, irp);
+            return irp.finish(disposition);
+        }
+
+        let disposition = match (state.unhandled, device.lower_device()) {
+            (WduUnhandledIrp::PassDown, Some(lower)) => irp.pass_through(&lower),
+            _ => UNHANDLED,
+        };
+
+        irp.finish(disposition)
+    }
+
+    fn pnp_pass_down(&self, irp: &mut WduIrp, lower: Option<&WduDevice>) -> NTSTATUS {
+        match lower {
+            Some(lower) => {
+                let disposition = irp.pass_through(lower);
+                irp.finish(disposition)
+            }
+            None => {
+                let io_status = irp.io_status();
+                let status = io_status.status();
+                irp.complete(io_status);
+                status
+            }
+        }
     }
 
-    #[no_mangle]
     unsafe extern "system" fn driver_unload(driver: *const DRIVER_OBJECT) {
         let wdu_driver = Self::get_wdu_driver(driver);
         if wdu_driver.is_null() {
@@ -260,7 +1122,6 @@ impl WduDriver {
         (*wdu_driver).unload_internal();
     }
 
-    #[no_mangle]
     unsafe extern "system" fn add_device(
         driver: *const DRIVER_OBJECT,
         pdo: *const DEVICE_OBJECT,
@@ -277,7 +1138,6 @@ impl WduDriver {
         (*wdu_driver).add_device_internal(&wdu_device)
     }
 
-    #[no_mangle]
     unsafe extern "system" fn dispatch_handler(
         device: *const DEVICE_OBJECT,
         irp: *const IRP,
@@ -285,12 +1145,13 @@ impl WduDriver {
         let mut status = STATUS_SUCCESS;
 
         let mut wdu_irp = WduIrp::wrap(irp);
+        wdu_irp.reset_contexts();
 
         // Wrap DO into WduDevice and retrieve WduDevice from DeviceExtension
         let wdu_device = WduDevice::get_wdu_device(device);
 
         // Get DRIVER_OBJECT from DO to obtain WduDriver from DriverObject Extension
-        let wdu_driver = WduDriver::get_wdu_driver(wdu_device.get_driver());
+        let wdu_driver = Self::get_wdu_driver(wdu_device.get_driver());
 
         if wdu_driver.is_null() {
             // TODO: log error but don't fail operation, if we are exeucting this handler it
@@ -298,18 +1159,42 @@ impl WduDriver {
             return status;
         }
 
-        if wdu_irp.is_fileobj() {
-            (*wdu_driver).fo_dispatch(&wdu_device, &mut wdu_irp);
-        } else if wdu_irp.is_io() {
-            status = (*wdu_driver).io_dispatch(&wdu_device, &mut wdu_irp);
+        // Fails once the device is being removed
+        let lock_status = wdu_device.acquire_remove_lock();
+        if !nt_success(lock_status) {
+            let mut io_status = wdu_irp.io_status();
+            io_status.set_status(lock_status);
+            wdu_irp.complete(io_status);
+            return lock_status;
+        }
+
+        // The PnP dispatch releases the lock itself
+        if wdu_irp.is_pnp() {
+            return (*wdu_driver).pnp_dispatch(wdu_device, &mut wdu_irp);
+        }
+
+        // Queues set in the device take precedence over the driver handlers
+        let queue = if wdu_irp.is_power() {
+            None
+        } else {
+            wdu_device.queue_for(&wdu_irp)
+        };
+
+        let major = wdu_irp.major();
+        if let Some(queue) = queue {
+            let disposition = queue.dispatch(&mut wdu_irp);
+            status = wdu_irp.finish(disposition);
+        } else if wdu_irp.is_fileobj() && (*wdu_driver).state().fileobj.handles(major) {
+            status = (*wdu_driver).fo_dispatch(wdu_device, &mut wdu_irp);
+        } else if wdu_irp.is_io() && (*wdu_driver).state().io.handles(major) {
+            status = (*wdu_driver).io_dispatch(wdu_device, &mut wdu_irp);
         } else if wdu_irp.is_power() {
-            status = (*wdu_driver).power_dispatch(&wdu_device, &mut wdu_irp);
-        } else if wdu_irp.is_pnp() {
-            status = (*wdu_driver).pnp_dispatch();
+            status = (*wdu_driver).power_dispatch(wdu_device, &mut wdu_irp);
         } else {
-            unreachable!("Unknown operation. Device: {:?} - IRP: {:?}", device, irp)
+            status = (*wdu_driver).unhandled_dispatch(wdu_device, &mut wdu_irp);
         };
 
+        wdu_device.release_remove_lock();
         status
     }
 
@@ -318,7 +1203,7 @@ impl WduDriver {
         let status = IoAllocateDriverObjectExtension(
             self.as_ptr(),
             WKR_DRIVER_ID,
-            core::mem::size_of::<WduDriver>() as u32,
+            core::mem::size_of::<Self>() as u32,
             &mut ext as *mut _ as *mut _,
         );
 
@@ -333,15 +1218,45 @@ impl WduDriver {
             // WduDriver after DriverEntry while keeping a copy stored in the DRIVER_OBJECT
             // extension. This will be freed by the OS when the Driver unloads.
             ext.copy_from(
-                self as *const WduDriver as *const _,
-                core::mem::size_of::<WduDriver>(),
+                self as *const Self as *const _,
+                core::mem::size_of::<Self>(),
             );
         }
 
         Ok(())
     }
 
-    fn get_wdu_driver(driver: *const DRIVER_OBJECT) -> *const WduDriver {
+    /// Makes `child` a child of the driver and returns it in its final location. Once the
+    /// [unload](Self::unload) routine returns the children are torn down in reverse order of
+    /// creation, followed by the devices the driver didn't delete.
+    ///
+    /// Children can only be added once the driver is built, usually from DriverEntry.
+    pub fn add_child<T: WduChild + 'static>(&self, child: T) -> WduChildResult<&T> {
+        let driver = Self::get_wdu_driver(self.driver);
+        if driver.is_null() {
+            return Err(WduChildError::Unsupported);
+        }
+
+        unsafe { WduChildList::add((*driver).children.as_ptr(), child) }
+    }
+
+    // Contexts live in the copy stored in the driver extension, so they can only be attached
+    // once the driver is built. Contexts are only freed if the driver registers an unload
+    // routine.
+    fn context_list(&self) -> Option<*mut *mut WduContextList> {
+        let driver = Self::get_wdu_driver(self.driver);
+        if driver.is_null() {
+            return None;
+        }
+
+        Some(unsafe { (*driver).contexts.as_ptr() })
+    }
+
+    // The extension must have been built by a WduDriver<C>, see is_wdu_driver for any other
+    // driver
+    fn get_wdu_driver(driver: *const DRIVER_OBJECT) -> *const Self {
         unsafe { IoGetDriverObjectExtension(driver, WKR_DRIVER_ID) as *const _ }
     }
 }
+
+typed_context!(WduDriver<C>);
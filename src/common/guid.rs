@@ -0,0 +1,186 @@
+use core::fmt;
+use snafu::Snafu;
+use windows_sys::core::GUID;
+
+#[derive(Debug, Snafu, Clone, Copy, PartialEq, Eq)]
+pub enum WduGuidError {
+    #[snafu(display("GUID string of {length} characters, expected 36 or 38 with braces"))]
+    InvalidLength { length: usize },
+    #[snafu(display("Invalid character at offset {offset}"))]
+    InvalidCharacter { offset: usize },
+}
+
+pub type WduGuidResult<T> = Result<T, WduGuidError>;
+
+// Offsets of the dashes in `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`
+const DASHES: [usize; 4] = [8, 13, 18, 23];
+
+/// GUID with the same layout as [GUID]. Can be parsed from a string literal at compile time:
+///
+/// ```ignore
+/// const GUID_DEVINTERFACE_TOASTER: WduGuid = WduGuid::new("781EF630-72B2-11d2-B852-00C04FAD5171");
+/// ```
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct WduGuid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+impl WduGuid {
+    /// Panics if the string is not valid, meant for constants.
+    pub const fn new(guid: &str) -> Self {
+        match Self::parse(guid) {
+            Ok(guid) => guid,
+            Err(_) => panic!("Invalid GUID string"),
+        }
+    }
+
+    /// Parses `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`, with or without braces. Hex digits can be
+    /// in either case.
+    pub const fn parse(guid: &str) -> WduGuidResult<Self> {
+        let bytes = guid.as_bytes();
+        let start = match bytes.len() {
+            36 => 0,
+            38 if bytes[0] == b'{' && bytes[37] == b'}' => 1,
+            38 if bytes[0] != b'{' => return Err(WduGuidError::InvalidCharacter { offset: 0 }),
+            38 => return Err(WduGuidError::InvalidCharacter { offset: 37 }),
+            length => return Err(WduGuidError::InvalidLength { length }),
+        };
+
+        let mut value: u128 = 0;
+        let mut i = 0;
+        while i < 36 {
+            let offset = start + i;
+            let c = bytes[offset];
+
+            if i == DASHES[0] || i == DASHES[1] || i == DASHES[2] || i == DASHES[3] {
+                if c != b'-' {
+                    return Err(WduGuidError::InvalidCharacter { offset });
+                }
+            } else {
+                let digit = match c {
+                    b'0'..=b'9' => c - b'0',
+                    b'a'..=b'f' => c - b'a' + 10,
+                    b'A'..=b'F' => c - b'A' + 10,
+                    _ => return Err(WduGuidError::InvalidCharacter { offset }),
+                };
+                value = value << 4 | digit as u128;
+            }
+
+            i += 1;
+        }
+
+        Ok(Self::from_u128(value))
+    }
+
+    pub const fn from_u128(guid: u128) -> Self {
+        WduGuid {
+            data1: (guid >> 96) as u32,
+            data2: (guid >> 80) as u16,
+            data3: (guid >> 64) as u16,
+            data4: (guid as u64).to_be_bytes(),
+        }
+    }
+
+    pub const fn to_u128(&self) -> u128 {
+        (self.data1 as u128) << 96
+            | (self.data2 as u128) << 80
+            | (self.data3 as u128) << 64
+            | u64::from_be_bytes(self.data4) as u128
+    }
+
+    pub fn as_ptr(&self) -> *const GUID {
+        self as *const Self as *const _
+    }
+}
+
+impl From<GUID> for WduGuid {
+    fn from(guid: GUID) -> Self {
+        WduGuid {
+            data1: guid.data1,
+            data2: guid.data2,
+            data3: guid.data3,
+            data4: guid.data4,
+        }
+    }
+}
+
+impl From<WduGuid> for GUID {
+    fn from(guid: WduGuid) -> Self {
+        GUID {
+            data1: guid.data1,
+            data2: guid.data2,
+            data3: guid.data3,
+            data4: guid.data4,
+        }
+    }
+}
+
+/// Registry format, same as RtlStringFromGUID: `{XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX}`.
+impl fmt::Display for WduGuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = &self.data4;
+        write!(
+            f,
+            "{{{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}}}",
+            self.data1, self.data2, self.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+        )
+    }
+}
+
+impl fmt::Debug for WduGuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WduGuid({self})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    const GUID_DEVINTERFACE_TOASTER: WduGuid = WduGuid::new("781EF630-72B2-11d2-B852-00C04FAD5171");
+
+    #[test]
+    fn format_and_parse() {
+        assert_eq!(
+            GUID_DEVINTERFACE_TOASTER,
+            WduGuid::from_u128(0x781ef630_72b2_11d2_b852_00c04fad5171)
+        );
+        assert_eq!(
+            GUID_DEVINTERFACE_TOASTER.to_string(),
+            "{781EF630-72B2-11D2-B852-00C04FAD5171}"
+        );
+        assert_eq!(
+            WduGuid::parse("{781ef630-72b2-11d2-b852-00c04fad5171}"),
+            Ok(GUID_DEVINTERFACE_TOASTER)
+        );
+        assert_eq!(
+            WduGuid::default().to_string(),
+            "{00000000-0000-0000-0000-000000000000}"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            WduGuid::parse("781EF630-72B2-11d2-B852"),
+            Err(WduGuidError::InvalidLength { length: 23 })
+        );
+        assert_eq!(
+            WduGuid::parse("781EF630-72B2-11d2-B852-00C04FAD517G"),
+            Err(WduGuidError::InvalidCharacter { offset: 35 })
+        );
+        assert_eq!(
+            WduGuid::parse("781EF630+72B2-11d2-B852-00C04FAD5171"),
+            Err(WduGuidError::InvalidCharacter { offset: 8 })
+        );
+        assert_eq!(
+            WduGuid::parse("{781EF630-72B2-11d2-B852-00C04FAD5171)"),
+            Err(WduGuidError::InvalidCharacter { offset: 37 })
+        );
+    }
+}
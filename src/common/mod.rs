@@ -1,5 +1,8 @@
+pub mod child;
+pub mod context;
 pub mod dpc;
 pub mod driver;
+pub mod guid;
 pub mod obj_attr;
 pub mod process;
 pub mod thread;
@@ -1,4 +1,7 @@
-use crate::{inner_getters_value, strings::unicode::string::WduUnicodeString};
+use crate::{
+    inner_getters_value, security::descriptor::WduSecurityDescriptorBuffer,
+    strings::unicode::string::WduUnicodeString,
+};
 use windows_sys::{
     Wdk::Foundation::OBJECT_ATTRIBUTES,
     Win32::{
@@ -67,8 +70,10 @@ impl WduObjectAttributes {
         self
     }
 
-    pub fn security_descriptor(self) -> Self {
-        todo!()
+    /// The attributes only hold a pointer to `descriptor`, it must outlive them.
+    pub fn security_descriptor(mut self, descriptor: &WduSecurityDescriptorBuffer) -> Self {
+        self.obj_attr.SecurityDescriptor = descriptor.as_ptr();
+        self
     }
 
     pub fn build(mut self) -> Self {
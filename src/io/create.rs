@@ -1,4 +1,4 @@
-use crate::io::{file_obj::WduFileObject, irp::WduIrp};
+use crate::io::{file_obj::WduFileObject, irp::WduIrp, stack::WduStackParameters};
 
 pub struct WduCreate {
     // TODO
@@ -11,20 +11,27 @@ pub struct WduCreate {
 
 impl WduCreate {
     pub(crate) unsafe fn new(irp: &WduIrp) -> Self {
-        let current_stack = WduIrp::current_stack(irp.as_ptr());
-        let create_params = (*current_stack).Parameters.Create;
+        let WduStackParameters::Create {
+            security_context,
+            file_attributes,
+            share_access,
+            ..
+        } = irp.current_stack_location().parameters()
+        else {
+            unreachable!("WduCreate from a non create IRP");
+        };
 
-        let desired_access = if create_params.SecurityContext.is_null() {
+        let desired_access = if security_context.is_null() {
             0
         } else {
-            (*create_params.SecurityContext).DesiredAccess
+            (*security_context).DesiredAccess
         };
 
         WduCreate {
             desired_access,
             file_object: irp.original_file_object(),
-            file_attributes: create_params.FileAttributes,
-            share_access: create_params.ShareAccess,
+            file_attributes,
+            share_access,
         }
     }
 
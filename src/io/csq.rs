@@ -0,0 +1,284 @@
+//! Cancel-safe IRP queue on top of the system IO_CSQ.
+use crate::io::irp::{WduIoStatus, WduIrp, WduIrpDisposition};
+use crate::sync::spinlock::WduSpinLock;
+use alloc::boxed::Box;
+use core::{cell::UnsafeCell, ffi::c_void, marker::PhantomData, mem::MaybeUninit};
+use snafu::Snafu;
+use windows_sys::{
+    Wdk::{
+        Foundation::IRP,
+        System::SystemServices::{IoCsqInitializeEx, IoCsqInsertIrpEx, IoCsqRemoveNextIrp, IO_CSQ},
+    },
+    Win32::{
+        Foundation::{NTSTATUS, STATUS_CANCELLED, STATUS_SUCCESS},
+        System::Kernel::LIST_ENTRY,
+    },
+};
+
+#[derive(Debug, Snafu)]
+pub enum WduCsqError {
+    #[snafu(display("Unable to initialize the queue. Status {status}"))]
+    InitError { status: NTSTATUS },
+    #[snafu(display("Unable to queue the IRP. Status {status}"))]
+    InsertError { status: NTSTATUS },
+}
+
+pub type WduCsqResult<T> = Result<T, WduCsqError>;
+
+/// Called when a queued IRP is cancelled. Receives the context the IRP was queued with and must
+/// complete the IRP.
+pub type WduCsqCancelRoutine<T> = fn(&mut WduIrp, T);
+
+// IoCsqInsertIrp(Ex) keeps the IO_CSQ_IRP_CONTEXT (or the IO_CSQ) in DriverContext[3], the typed
+// context of the IRP goes in the previous one.
+const CONTEXT_INDEX: usize = 2;
+
+/// Queue of pending IRPs whose cancellation is synchronized by the I/O manager. Each IRP is
+/// queued along with a context of type `T` which is returned when the IRP leaves the queue.
+///
+/// Same as the other synchronization objects, the queue must be initialized with
+/// [init](WduCancelSafeQueue::init) once it's in its final location (e.g. the device extension),
+/// since the system keeps pointers to it.
+///
+/// IRPs are linked through `Tail.Overlay.ListEntry` and use DriverContext\[2\] & \[3\] while
+/// queued. Don't use [WduIrp::set_cancel_rtn] on them.
+#[repr(C)]
+pub struct WduCancelSafeQueue<T> {
+    // Must be the first member, callbacks only receive a pointer to the IO_CSQ
+    csq: UnsafeCell<IO_CSQ>,
+    lock: UnsafeCell<WduSpinLock>,
+    head: UnsafeCell<LIST_ENTRY>,
+    cancel: Option<WduCsqCancelRoutine<T>>,
+    _context: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for WduCancelSafeQueue<T> {}
+unsafe impl<T: Send> Sync for WduCancelSafeQueue<T> {}
+
+impl<T> Default for WduCancelSafeQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> WduCancelSafeQueue<T> {
+    pub fn new() -> Self {
+        WduCancelSafeQueue {
+            csq: UnsafeCell::new(unsafe { core::mem::zeroed() }),
+            lock: UnsafeCell::new(WduSpinLock::new()),
+            head: UnsafeCell::new(unsafe { core::mem::zeroed() }),
+            cancel: None,
+            _context: PhantomData,
+        }
+    }
+
+    /// Initializes the queue in place. Cancelled IRPs are passed to `cancel`, if `None` they are
+    /// completed with STATUS_CANCELLED.
+    pub fn init(&mut self, cancel: Option<WduCsqCancelRoutine<T>>) -> WduCsqResult<()> {
+        self.cancel = cancel;
+        self.lock.get_mut().init();
+
+        let head = self.head.get();
+        unsafe {
+            (*head).Flink = head;
+            (*head).Blink = head;
+        }
+
+        let status = unsafe {
+            IoCsqInitializeEx(
+                self.csq.get(),
+                Some(core::mem::transmute_copy(&(Self::insert_irp as *mut u8))),
+                Some(core::mem::transmute_copy(&(Self::remove_irp as *mut u8))),
+                Some(core::mem::transmute_copy(&(Self::peek_next_irp as *mut u8))),
+                Some(core::mem::transmute_copy(&(Self::acquire_lock as *mut u8))),
+                Some(core::mem::transmute_copy(&(Self::release_lock as *mut u8))),
+                Some(core::mem::transmute_copy(
+                    &(Self::complete_canceled_irp as *mut u8),
+                )),
+            )
+        };
+
+        if status != STATUS_SUCCESS {
+            return Err(WduCsqError::InitError { status });
+        }
+
+        Ok(())
+    }
+
+    /// Marks the IRP pending and queues it with `context`. If the IRP gets cancelled right away
+    /// it's completed before this returns, either way the dispatch handler must return the
+    /// returned disposition without touching the IRP again.
+    pub fn insert(&self, irp: &mut WduIrp, context: T) -> WduCsqResult<WduIrpDisposition> {
+        let context = Box::into_raw(Box::new(context));
+
+        unsafe {
+            Self::set_context(irp.as_ptr(), context);
+
+            let status = IoCsqInsertIrpEx(
+                self.csq.get(),
+                irp.as_mut_ptr(),
+                core::ptr::null_mut(),
+                core::ptr::null(),
+            );
+
+            if status != STATUS_SUCCESS {
+                Self::set_context(irp.as_ptr(), core::ptr::null_mut());
+                drop(Box::from_raw(context));
+                return Err(WduCsqError::InsertError { status });
+            }
+        }
+
+        // IoCsqInsertIrpEx already marked it pending and the IRP might be cancelled by now, only
+        // keep the WduIrp in sync
+        irp.set_pending_state();
+        Ok(WduIrpDisposition::Pending)
+    }
+
+    /// Removes the first IRP of the queue. The caller owns the IRP and must complete it.
+    pub fn remove_next(&self) -> Option<(WduIrp, T)> {
+        self.remove(core::ptr::null())
+    }
+
+    /// Removes the first IRP for which `predicate` returns true. The predicate is called with
+    /// the queue lock held, at DISPATCH_LEVEL.
+    pub fn remove_next_if<F>(&self, predicate: F) -> Option<(WduIrp, T)>
+    where
+        F: Fn(&WduIrp, &T) -> bool,
+    {
+        let predicate: &dyn Fn(&WduIrp, &T) -> bool = &predicate;
+        self.remove(&predicate as *const _ as *const c_void)
+    }
+
+    /// Removes every IRP of the queue and completes it with `io_status`. Usually on cleanup or
+    /// removal of the device.
+    pub fn complete_all(&self, io_status: WduIoStatus) {
+        while let Some((mut irp, _)) = self.remove_next() {
+            irp.complete(io_status);
+        }
+    }
+
+    fn remove(&self, peek_context: *const c_void) -> Option<(WduIrp, T)> {
+        let irp = unsafe { IoCsqRemoveNextIrp(self.csq.get(), peek_context) };
+
+        if irp.is_null() {
+            return None;
+        }
+
+        let context = unsafe { Self::take_context(irp) };
+        Some((WduIrp::wrap(irp), context))
+    }
+
+    unsafe fn set_context(irp: *const IRP, context: *mut T) {
+        (*(irp as *mut IRP))
+            .Tail
+            .Overlay
+            .Anonymous1
+            .Anonymous
+            .DriverContext[CONTEXT_INDEX] = context as _;
+    }
+
+    unsafe fn take_context(irp: *const IRP) -> T {
+        let context = (*irp).Tail.Overlay.Anonymous1.Anonymous.DriverContext[CONTEXT_INDEX];
+        Self::set_context(irp, core::ptr::null_mut());
+
+        *Box::from_raw(context as *mut T)
+    }
+
+    unsafe fn context<'a>(irp: *const IRP) -> &'a T {
+        &*((*irp).Tail.Overlay.Anonymous1.Anonymous.DriverContext[CONTEXT_INDEX] as *const T)
+    }
+
+    unsafe fn from_csq<'a>(csq: *const IO_CSQ) -> &'a Self {
+        &*(csq as *const Self)
+    }
+
+    unsafe fn list_entry(irp: *const IRP) -> *mut LIST_ENTRY {
+        core::ptr::addr_of!((*irp).Tail.Overlay.Anonymous2.ListEntry) as *mut _
+    }
+
+    // CONTAINING_RECORD(entry, IRP, Tail.Overlay.ListEntry)
+    unsafe fn irp_from_entry(entry: *const LIST_ENTRY) -> *mut IRP {
+        let base = MaybeUninit::<IRP>::uninit();
+        let offset = Self::list_entry(base.as_ptr()) as usize - base.as_ptr() as usize;
+
+        (entry as usize - offset) as *mut IRP
+    }
+
+    unsafe extern "system" fn insert_irp(
+        csq: *const IO_CSQ,
+        irp: *const IRP,
+        _insert_context: *const c_void,
+    ) -> NTSTATUS {
+        let head = Self::from_csq(csq).head.get();
+        let entry = Self::list_entry(irp);
+
+        // InsertTailList
+        let tail = (*head).Blink;
+        (*entry).Flink = head;
+        (*entry).Blink = tail;
+        (*tail).Flink = entry;
+        (*head).Blink = entry;
+
+        STATUS_SUCCESS
+    }
+
+    unsafe extern "system" fn remove_irp(_csq: *const IO_CSQ, irp: *const IRP) {
+        // RemoveEntryList
+        let entry = Self::list_entry(irp);
+        let flink = (*entry).Flink;
+        let blink = (*entry).Blink;
+        (*blink).Flink = flink;
+        (*flink).Blink = blink;
+    }
+
+    unsafe extern "system" fn peek_next_irp(
+        csq: *const IO_CSQ,
+        irp: *const IRP,
+        peek_context: *const c_void,
+    ) -> *mut IRP {
+        let head = Self::from_csq(csq).head.get();
+        let mut entry = if irp.is_null() {
+            (*head).Flink
+        } else {
+            (*Self::list_entry(irp)).Flink
+        };
+
+        while entry != head {
+            let next_irp = Self::irp_from_entry(entry);
+
+            if peek_context.is_null() {
+                return next_irp;
+            }
+
+            let predicate = &*(peek_context as *const &dyn Fn(&WduIrp, &T) -> bool);
+            if predicate(&WduIrp::wrap(next_irp), Self::context(next_irp)) {
+                return next_irp;
+            }
+
+            entry = (*entry).Flink;
+        }
+
+        core::ptr::null_mut()
+    }
+
+    // The spinlock keeps the previous IRQL itself
+    unsafe extern "system" fn acquire_lock(csq: *const IO_CSQ, _irql: *mut u8) {
+        (*Self::from_csq(csq).lock.get()).acquire();
+    }
+
+    unsafe extern "system" fn release_lock(csq: *const IO_CSQ, _irql: u8) {
+        (*Self::from_csq(csq).lock.get()).release();
+    }
+
+    unsafe extern "system" fn complete_canceled_irp(csq: *const IO_CSQ, irp: *const IRP) {
+        let context = Self::take_context(irp);
+        let mut wdu_irp = WduIrp::wrap(irp);
+
+        match Self::from_csq(csq).cancel {
+            Some(cancel) => cancel(&mut wdu_irp, context),
+            None => {
+                wdu_irp.complete(WduIoStatus::new_with_status(STATUS_CANCELLED));
+            }
+        }
+    }
+}
@@ -1,25 +1,87 @@
 use crate::{
-    common::driver::WduDriver, strings::unicode::str::WduUnicodeStr,
+    common::{
+        child::{WduChild, WduChildError, WduChildList, WduChildResult},
+        context::{typed_context, WduContextList},
+        driver::{is_wdu_driver, WduDriver},
+        guid::WduGuid,
+    },
+    io::{
+        file_obj::WduFileContexts,
+        irp::{MajorFunction, WduIoStatus, WduIrp},
+        pnp::{WduPnpIrp, WduPnpState},
+        power::{WduDevicePowerState, WduPowerRequest, WduPowerState, WduSystemPowerState},
+        queue::{WduDeviceQueues, WduIoQueue},
+    },
+    nt, nt_success,
+    security::{
+        descriptor::{WduSecurityDescriptor, WduSecurityDescriptorControl},
+        sddl::WduSddl,
+    },
+    strings::unicode::str::WduUnicodeStr,
     strings::unicode::string::WduUnicodeString,
+    strings::unicode::WduUnicodeError,
+    sync::remove_lock::WduRemoveLock,
+    ProcessorMode,
+};
+use alloc::boxed::Box;
+use bitflags::bitflags;
+use core::{
+    any::TypeId,
+    cell::{Cell, UnsafeCell},
+    ffi::c_void,
+    mem::{align_of, size_of},
+    sync::atomic::{AtomicPtr, Ordering},
 };
-use core::ffi::c_void;
 use snafu::Snafu;
 use windows_sys::{
     Wdk::{
-        Foundation::{DEVICE_OBJECT, DRIVER_OBJECT},
+        Foundation::{DEVICE_OBJECT, DRIVER_OBJECT, FILE_OBJECT},
+        Storage::FileSystem::{
+            ObOpenObjectByPointer, ZwSetSecurityObject, DO_BOOT_CRITICAL, DO_BUFFERED_IO,
+            DO_BUS_ENUMERATED_DEVICE, DO_DAX_VOLUME, DO_DEVICE_HAS_NAME, DO_DEVICE_INITIALIZING,
+            DO_DEVICE_IRP_REQUIRES_EXTENSION, DO_DEVICE_TO_BE_RESET, DO_DIRECT_IO,
+            DO_DISALLOW_EXECUTE, DO_EXCLUSIVE, DO_FORCE_NEITHER_IO, DO_LONG_TERM_REQUESTS,
+            DO_LOW_PRIORITY_FILESYSTEM, DO_MAP_IO_BUFFER, DO_NEVER_LAST_DEVICE, DO_POWER_INRUSH,
+            DO_POWER_PAGABLE, DO_SHUTDOWN_REGISTERED, DO_SUPPORTS_PERSISTENT_ACLS,
+            DO_SUPPORTS_TRANSACTIONS, DO_SYSTEM_BOOT_PARTITION, DO_SYSTEM_CRITICAL_PARTITION,
+            DO_SYSTEM_SYSTEM_PARTITION, DO_VERIFY_VOLUME, DO_VOLUME_DEVICE_OBJECT,
+        },
         System::SystemServices::{
-            IoCreateDevice, IoCreateSymbolicLink, IoDeleteDevice, IoDeleteSymbolicLink,
+            IoAttachDeviceToDeviceStack, IoCreateDevice, IoCreateSymbolicLink, IoDeleteDevice,
+            IoDeleteSymbolicLink, IoDetachDevice, IoRegisterDeviceInterface,
+            IoSetDeviceInterfaceState, PoRequestPowerIrp, PoSetPowerState, ZwClose,
+            FILE_AUTOGENERATED_DEVICE_NAME, FILE_CHARACTERISTIC_CSV,
+            FILE_CHARACTERISTIC_PNP_DEVICE, FILE_CHARACTERISTIC_TS_DEVICE,
+            FILE_CHARACTERISTIC_WEBDAV_DEVICE, FILE_DEVICE_ALLOW_APPCONTAINER_TRAVERSAL,
+            FILE_DEVICE_IS_MOUNTED, FILE_DEVICE_REQUIRE_SECURITY_CHECK, FILE_DEVICE_SECURE_OPEN,
+            FILE_FLOPPY_DISKETTE, FILE_PORTABLE_DEVICE, FILE_READ_ONLY_DEVICE, FILE_REMOTE_DEVICE,
+            FILE_REMOTE_DEVICE_VSMB, FILE_REMOVABLE_MEDIA, FILE_VIRTUAL_VOLUME,
+            FILE_WRITE_ONCE_MEDIA, POWER_STATE,
         },
     },
     Win32::{
-        Foundation::{NTSTATUS, STATUS_SUCCESS},
-        System::Ioctl::{
-            FILE_DEVICE_ACPI, FILE_DEVICE_DISK_FILE_SYSTEM, FILE_DEVICE_FILE_SYSTEM,
-            FILE_DEVICE_MASS_STORAGE, FILE_DEVICE_UNKNOWN,
+        Foundation::{
+            NTSTATUS, STATUS_OBJECT_NAME_NOT_FOUND, STATUS_PENDING, STATUS_SUCCESS, UNICODE_STRING,
+        },
+        Security::{
+            DACL_SECURITY_INFORMATION, GROUP_SECURITY_INFORMATION, OWNER_SECURITY_INFORMATION,
+            PROTECTED_DACL_SECURITY_INFORMATION, SACL_SECURITY_INFORMATION,
+            UNPROTECTED_DACL_SECURITY_INFORMATION,
+        },
+        System::{
+            Ioctl, Kernel::OBJ_KERNEL_HANDLE, Power::DEVICE_POWER_STATE, IO::IO_STATUS_BLOCK,
         },
     },
 };
 
+// Win32::Storage::FileSystem isn't a feature of the crate
+const WRITE_DAC: u32 = 0x40000;
+const WRITE_OWNER: u32 = 0x80000;
+const ACCESS_SYSTEM_SECURITY: u32 = 0x1000000;
+const REMOVE_LOCK_TAG: u32 = u32::from_ne_bytes(*b"WDUr");
+// MEMORY_ALLOCATION_ALIGNMENT, the DeviceExtension follows the DEVICE_OBJECT which is aligned to it
+const MAX_EXTENSION_ALIGNMENT: usize = 2 * size_of::<usize>();
+
 #[derive(Debug, Snafu)]
 pub enum WduDeviceError {
     #[snafu(display("Unable to create device. Status {status}"))]
@@ -28,65 +90,215 @@ pub enum WduDeviceError {
     InvalidBuilderState,
     #[snafu(display("Error. Status {status}"))]
     GenericError { status: NTSTATUS },
+    #[snafu(display("Unable to attach device to the device stack"))]
+    AttachError,
+    #[snafu(display("Unable to convert the SDDL string"))]
+    SddlConversion { source: WduUnicodeError },
+    #[snafu(display("Device extension alignment {alignment} is not supported"))]
+    ExtensionAlignment { alignment: usize },
+    #[snafu(display("Unable to copy the symbolic name"))]
+    SymbolicName { source: WduUnicodeError },
 }
 
 pub type WduDeviceResult<T> = Result<T, WduDeviceError>;
 
-// TODO: Complete
-#[derive(Clone, Copy)]
-pub enum WduDeviceType {
-    ACPI,
-    DiskFileSystem,
-    Disk,
-    FileSystem,
-    MassStorage,
-    Unknown,
-}
+/// Completion routine of a power IRP requested with [WduDevice::request_power_irp]
+pub type WduPowerCompletion = fn(&WduDevice, WduPowerRequest, WduIoStatus);
+
+// Generates the enum plus the conversions from and to the raw FILE_DEVICE_* value
+macro_rules! device_types {
+    ($($name:ident = $value:expr,)*) => {
+        /// Device type passed to IoCreateDevice. Vendor defined types (0x8000 and up) go in
+        /// `Other`.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum WduDeviceType {
+            $($name,)*
+            Other(u32),
+        }
 
-impl Into<u32> for WduDeviceType {
-    fn into(self) -> u32 {
-        match self {
-            WduDeviceType::ACPI => FILE_DEVICE_ACPI,
-            WduDeviceType::DiskFileSystem => FILE_DEVICE_DISK_FILE_SYSTEM,
-            WduDeviceType::Disk => 0x00000007,
-            WduDeviceType::FileSystem => FILE_DEVICE_FILE_SYSTEM,
-            WduDeviceType::MassStorage => FILE_DEVICE_MASS_STORAGE,
-            WduDeviceType::Unknown => FILE_DEVICE_UNKNOWN,
+        impl From<u32> for WduDeviceType {
+            fn from(value: u32) -> Self {
+                match value {
+                    $(v if v == $value => WduDeviceType::$name,)*
+                    other => WduDeviceType::Other(other),
+                }
+            }
         }
+
+        impl From<WduDeviceType> for u32 {
+            fn from(value: WduDeviceType) -> Self {
+                match value {
+                    $(WduDeviceType::$name => $value,)*
+                    WduDeviceType::Other(value) => value,
+                }
+            }
+        }
+    };
+}
+
+// CD_ROM, DISK, DVD & TAPE are only defined in Win32::Storage::FileSystem
+device_types! {
+    Beep = Ioctl::FILE_DEVICE_BEEP,
+    CdRom = 0x00000002,
+    CdRomFileSystem = Ioctl::FILE_DEVICE_CD_ROM_FILE_SYSTEM,
+    Controller = Ioctl::FILE_DEVICE_CONTROLLER,
+    Datalink = Ioctl::FILE_DEVICE_DATALINK,
+    Dfs = Ioctl::FILE_DEVICE_DFS,
+    Disk = 0x00000007,
+    DiskFileSystem = Ioctl::FILE_DEVICE_DISK_FILE_SYSTEM,
+    FileSystem = Ioctl::FILE_DEVICE_FILE_SYSTEM,
+    InportPort = Ioctl::FILE_DEVICE_INPORT_PORT,
+    Keyboard = Ioctl::FILE_DEVICE_KEYBOARD,
+    Mailslot = Ioctl::FILE_DEVICE_MAILSLOT,
+    MidiIn = Ioctl::FILE_DEVICE_MIDI_IN,
+    MidiOut = Ioctl::FILE_DEVICE_MIDI_OUT,
+    Mouse = Ioctl::FILE_DEVICE_MOUSE,
+    MultiUncProvider = Ioctl::FILE_DEVICE_MULTI_UNC_PROVIDER,
+    NamedPipe = Ioctl::FILE_DEVICE_NAMED_PIPE,
+    Network = Ioctl::FILE_DEVICE_NETWORK,
+    NetworkBrowser = Ioctl::FILE_DEVICE_NETWORK_BROWSER,
+    NetworkFileSystem = Ioctl::FILE_DEVICE_NETWORK_FILE_SYSTEM,
+    Null = Ioctl::FILE_DEVICE_NULL,
+    ParallelPort = Ioctl::FILE_DEVICE_PARALLEL_PORT,
+    PhysicalNetcard = Ioctl::FILE_DEVICE_PHYSICAL_NETCARD,
+    Printer = Ioctl::FILE_DEVICE_PRINTER,
+    Scanner = Ioctl::FILE_DEVICE_SCANNER,
+    SerialMousePort = Ioctl::FILE_DEVICE_SERIAL_MOUSE_PORT,
+    SerialPort = Ioctl::FILE_DEVICE_SERIAL_PORT,
+    Screen = Ioctl::FILE_DEVICE_SCREEN,
+    Sound = Ioctl::FILE_DEVICE_SOUND,
+    Streams = Ioctl::FILE_DEVICE_STREAMS,
+    Tape = 0x0000001F,
+    TapeFileSystem = Ioctl::FILE_DEVICE_TAPE_FILE_SYSTEM,
+    Transport = Ioctl::FILE_DEVICE_TRANSPORT,
+    Unknown = Ioctl::FILE_DEVICE_UNKNOWN,
+    Video = Ioctl::FILE_DEVICE_VIDEO,
+    VirtualDisk = Ioctl::FILE_DEVICE_VIRTUAL_DISK,
+    WaveIn = Ioctl::FILE_DEVICE_WAVE_IN,
+    WaveOut = Ioctl::FILE_DEVICE_WAVE_OUT,
+    Port8042 = Ioctl::FILE_DEVICE_8042_PORT,
+    NetworkRedirector = Ioctl::FILE_DEVICE_NETWORK_REDIRECTOR,
+    Battery = Ioctl::FILE_DEVICE_BATTERY,
+    BusExtender = Ioctl::FILE_DEVICE_BUS_EXTENDER,
+    Modem = Ioctl::FILE_DEVICE_MODEM,
+    Vdm = Ioctl::FILE_DEVICE_VDM,
+    MassStorage = Ioctl::FILE_DEVICE_MASS_STORAGE,
+    Smb = Ioctl::FILE_DEVICE_SMB,
+    Ks = Ioctl::FILE_DEVICE_KS,
+    Changer = Ioctl::FILE_DEVICE_CHANGER,
+    ACPI = Ioctl::FILE_DEVICE_ACPI,
+    Dvd = 0x00000033,
+    FullscreenVideo = Ioctl::FILE_DEVICE_FULLSCREEN_VIDEO,
+    DfsFileSystem = Ioctl::FILE_DEVICE_DFS_FILE_SYSTEM,
+    DfsVolume = Ioctl::FILE_DEVICE_DFS_VOLUME,
+    Serenum = Ioctl::FILE_DEVICE_SERENUM,
+    Termsrv = Ioctl::FILE_DEVICE_TERMSRV,
+    Ksec = Ioctl::FILE_DEVICE_KSEC,
+    Fips = Ioctl::FILE_DEVICE_FIPS,
+    Infiniband = Ioctl::FILE_DEVICE_INFINIBAND,
+    Vmbus = Ioctl::FILE_DEVICE_VMBUS,
+    CryptProvider = Ioctl::FILE_DEVICE_CRYPT_PROVIDER,
+    Wpd = Ioctl::FILE_DEVICE_WPD,
+    Bluetooth = Ioctl::FILE_DEVICE_BLUETOOTH,
+    MtComposite = Ioctl::FILE_DEVICE_MT_COMPOSITE,
+    MtTransport = Ioctl::FILE_DEVICE_MT_TRANSPORT,
+    Biometric = Ioctl::FILE_DEVICE_BIOMETRIC,
+    Pmi = Ioctl::FILE_DEVICE_PMI,
+    Ehstor = Ioctl::FILE_DEVICE_EHSTOR,
+    Devapi = Ioctl::FILE_DEVICE_DEVAPI,
+    Gpio = Ioctl::FILE_DEVICE_GPIO,
+    Usbex = Ioctl::FILE_DEVICE_USBEX,
+    Console = Ioctl::FILE_DEVICE_CONSOLE,
+    Nfp = Ioctl::FILE_DEVICE_NFP,
+    Sysenv = Ioctl::FILE_DEVICE_SYSENV,
+    VirtualBlock = Ioctl::FILE_DEVICE_VIRTUAL_BLOCK,
+    PointOfService = Ioctl::FILE_DEVICE_POINT_OF_SERVICE,
+    StorageReplication = Ioctl::FILE_DEVICE_STORAGE_REPLICATION,
+    TrustEnv = Ioctl::FILE_DEVICE_TRUST_ENV,
+    Ucm = Ioctl::FILE_DEVICE_UCM,
+    Ucmtcpci = Ioctl::FILE_DEVICE_UCMTCPCI,
+    PersistentMemory = Ioctl::FILE_DEVICE_PERSISTENT_MEMORY,
+    Nvdimm = Ioctl::FILE_DEVICE_NVDIMM,
+    Holographic = Ioctl::FILE_DEVICE_HOLOGRAPHIC,
+    Sdfxhci = Ioctl::FILE_DEVICE_SDFXHCI,
+    Ucmucsi = Ioctl::FILE_DEVICE_UCMUCSI,
+    Prm = Ioctl::FILE_DEVICE_PRM,
+    EventCollector = Ioctl::FILE_DEVICE_EVENT_COLLECTOR,
+    Usb4 = Ioctl::FILE_DEVICE_USB4,
+    Soundwire = Ioctl::FILE_DEVICE_SOUNDWIRE,
+}
+
+bitflags! {
+    /// Device characteristics (FILE_*) passed to IoCreateDevice.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WduDeviceChars: u32 {
+        const RemovableMedia = FILE_REMOVABLE_MEDIA;
+        const ReadOnly = FILE_READ_ONLY_DEVICE;
+        const FloppyDiskette = FILE_FLOPPY_DISKETTE;
+        const WriteOnce = FILE_WRITE_ONCE_MEDIA;
+        const Remote = FILE_REMOTE_DEVICE;
+        const Mounted = FILE_DEVICE_IS_MOUNTED;
+        const VirtualVolume = FILE_VIRTUAL_VOLUME;
+        const AutoGeneratedName = FILE_AUTOGENERATED_DEVICE_NAME;
+        const SecureOpen = FILE_DEVICE_SECURE_OPEN;
+        const PnpDevice = FILE_CHARACTERISTIC_PNP_DEVICE;
+        const TsDevice = FILE_CHARACTERISTIC_TS_DEVICE;
+        const WebDavDevice = FILE_CHARACTERISTIC_WEBDAV_DEVICE;
+        const Csv = FILE_CHARACTERISTIC_CSV;
+        const AllowAppContainerTraversal = FILE_DEVICE_ALLOW_APPCONTAINER_TRAVERSAL;
+        const PortableDevice = FILE_PORTABLE_DEVICE;
+        const RemoteDeviceVsmb = FILE_REMOTE_DEVICE_VSMB;
+        const RequireSecCheck = FILE_DEVICE_REQUIRE_SECURITY_CHECK;
     }
 }
 
-#[derive(Clone, Copy)]
-pub enum WduDeviceChars {
-    None,
-    RemovableMedia,
-    ReadOnly,
-    WriteOnce,
-    Remote,
-    Mounted,
-    VirtualVolume,
-    AutoGeneratedName,
-    SecureOpen,
-    AllowAppContainerTraversal,
-    PortableDevice,
-    RequireSecCheck,
+bitflags! {
+    /// DEVICE_OBJECT flags (DO_*).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WduDeviceFlags: u32 {
+        const VerifyVolume = DO_VERIFY_VOLUME;
+        const BufferedIo = DO_BUFFERED_IO;
+        const Exclusive = DO_EXCLUSIVE;
+        const DirectIo = DO_DIRECT_IO;
+        const MapIoBuffer = DO_MAP_IO_BUFFER;
+        const DeviceHasName = DO_DEVICE_HAS_NAME;
+        const DeviceInitializing = DO_DEVICE_INITIALIZING;
+        const SystemBootPartition = DO_SYSTEM_BOOT_PARTITION;
+        const LongTermRequests = DO_LONG_TERM_REQUESTS;
+        const NeverLastDevice = DO_NEVER_LAST_DEVICE;
+        const ShutdownRegistered = DO_SHUTDOWN_REGISTERED;
+        const BusEnumeratedDevice = DO_BUS_ENUMERATED_DEVICE;
+        const PowerPagable = DO_POWER_PAGABLE;
+        const PowerInrush = DO_POWER_INRUSH;
+        const LowPriorityFileSystem = DO_LOW_PRIORITY_FILESYSTEM;
+        const SupportsPersistentAcls = DO_SUPPORTS_PERSISTENT_ACLS;
+        const SupportsTransactions = DO_SUPPORTS_TRANSACTIONS;
+        const ForceNeitherIo = DO_FORCE_NEITHER_IO;
+        const VolumeDeviceObject = DO_VOLUME_DEVICE_OBJECT;
+        const SystemSystemPartition = DO_SYSTEM_SYSTEM_PARTITION;
+        const SystemCriticalPartition = DO_SYSTEM_CRITICAL_PARTITION;
+        const DisallowExecute = DO_DISALLOW_EXECUTE;
+        const DeviceToBeReset = DO_DEVICE_TO_BE_RESET;
+        const DeviceIrpRequiresExtension = DO_DEVICE_IRP_REQUIRES_EXTENSION;
+        const DaxVolume = DO_DAX_VOLUME;
+        const BootCritical = DO_BOOT_CRITICAL;
+    }
 }
 
-impl Into<u32> for WduDeviceChars {
-    fn into(self) -> u32 {
-        match self {
-            WduDeviceChars::None => 0x0,
-            WduDeviceChars::RemovableMedia => 0x1,
-            WduDeviceChars::ReadOnly => 0x2,
-            WduDeviceChars::WriteOnce => 0x8,
-            WduDeviceChars::Remote => 0x10,
-            WduDeviceChars::Mounted => 0x20,
-            WduDeviceChars::VirtualVolume => 0x40,
-            WduDeviceChars::AutoGeneratedName => 0x80,
-            WduDeviceChars::SecureOpen => 0x100,
-            WduDeviceChars::AllowAppContainerTraversal => 0x20000,
-            WduDeviceChars::PortableDevice => 0x40000,
-            WduDeviceChars::RequireSecCheck => 0x100000,
+/// How the I/O manager passes the buffers of read & write requests to the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WduIoMethod {
+    Buffered,
+    Direct,
+    Neither,
+}
+
+impl From<WduIoMethod> for WduDeviceFlags {
+    fn from(value: WduIoMethod) -> Self {
+        match value {
+            WduIoMethod::Buffered => WduDeviceFlags::BufferedIo,
+            WduIoMethod::Direct => WduDeviceFlags::DirectIo,
+            WduIoMethod::Neither => WduDeviceFlags::empty(),
         }
     }
 }
@@ -94,17 +306,46 @@ impl Into<u32> for WduDeviceChars {
 pub struct WduDevice {
     device_type: WduDeviceType,
     characteristics: WduDeviceChars,
+    flags: WduDeviceFlags,
+    clear_initializing: bool,
     exclusive: bool,
+    security: Option<(WduSddl, WduGuid)>,
     device: *const DEVICE_OBJECT,
+    lower: Cell<*const DEVICE_OBJECT>,
+    pnp_state: Cell<WduPnpState>,
+    prev_pnp_state: Cell<WduPnpState>,
+    power_map: Cell<[DEVICE_POWER_STATE; 7]>,
+    queues: AtomicPtr<WduDeviceQueues>,
+    file_contexts: WduFileContexts,
+    extension_type: Option<TypeId>,
+    drop_extension: Cell<Option<fn(&WduDevice)>>,
+    contexts: Cell<*mut WduContextList>,
+    children: Cell<*mut WduChildList>,
+    // Held while an IRP is dispatched, IRP_MN_REMOVE_DEVICE waits for it before deleting
+    remove_lock: UnsafeCell<WduRemoveLock>,
 }
 
 impl Default for WduDevice {
     fn default() -> Self {
         WduDevice {
             device_type: WduDeviceType::Unknown,
-            characteristics: WduDeviceChars::None,
+            characteristics: WduDeviceChars::empty(),
+            flags: WduDeviceFlags::empty(),
+            clear_initializing: true,
             exclusive: false,
+            security: None,
             device: core::ptr::null(),
+            lower: Cell::new(core::ptr::null()),
+            pnp_state: Cell::new(WduPnpState::NotStarted),
+            prev_pnp_state: Cell::new(WduPnpState::NotStarted),
+            power_map: Cell::new([0; 7]),
+            queues: AtomicPtr::new(core::ptr::null_mut()),
+            file_contexts: WduFileContexts::new(),
+            extension_type: None,
+            drop_extension: Cell::new(None),
+            contexts: Cell::new(core::ptr::null_mut()),
+            children: Cell::new(core::ptr::null_mut()),
+            remove_lock: UnsafeCell::new(WduRemoveLock::new()),
         }
     }
 }
@@ -132,6 +373,273 @@ impl WduDevice {
         unsafe { (*self.device).DriverObject }
     }
 
+    // Only devices created by a WduDriver hold a copy of WduDevice at the start of the
+    // DeviceExtension. Any other device (e.g. the PDO passed to AddDevice) is just a wrapper.
+    fn is_wdu_device(&self) -> bool {
+        !self.device.is_null() && is_wdu_driver(self.get_driver())
+    }
+
+    // Returns the copy of WduDevice stored in the DeviceExtension. This is the copy that holds
+    // the state tracked by the library.
+    fn header(&self) -> Option<&WduDevice> {
+        if !self.is_wdu_device() {
+            return None;
+        }
+
+        Some(Self::get_wdu_device(self.device))
+    }
+
+    /// Current PnP state of the device. Devices not created by this driver are always reported
+    /// as `NotStarted`.
+    pub fn pnp_state(&self) -> WduPnpState {
+        self.header()
+            .map_or_else(|| WduPnpState::NotStarted, |header| header.pnp_state.get())
+    }
+
+    pub(crate) fn update_pnp_state(&self, pnp_irp: &WduPnpIrp) {
+        let header = match self.header() {
+            Some(header) => header,
+            None => return,
+        };
+
+        let current = header.pnp_state.get();
+        let next = match pnp_irp {
+            WduPnpIrp::StartDevice { .. } => WduPnpState::Started,
+            WduPnpIrp::QueryCapabilities(caps) => {
+                header.power_map.set(caps.device_states());
+                return;
+            }
+            WduPnpIrp::QueryStopDevice => {
+                header.prev_pnp_state.set(current);
+                WduPnpState::StopPending
+            }
+            WduPnpIrp::StopDevice => WduPnpState::Stopped,
+            WduPnpIrp::CancelStopDevice if current == WduPnpState::StopPending => {
+                header.prev_pnp_state.get()
+            }
+            WduPnpIrp::QueryRemoveDevice => {
+                header.prev_pnp_state.set(current);
+                WduPnpState::RemovePending
+            }
+            WduPnpIrp::CancelRemoveDevice if current == WduPnpState::RemovePending => {
+                header.prev_pnp_state.get()
+            }
+            WduPnpIrp::SurpriseRemoval => WduPnpState::SurpriseRemovePending,
+            WduPnpIrp::RemoveDevice => WduPnpState::Deleted,
+            _ => return,
+        };
+
+        header.pnp_state.set(next);
+    }
+
+    /// Device this device is attached to, set by [WduDevice::attach_to_stack].
+    pub fn lower_device(&self) -> Option<WduDevice> {
+        self.header().and_then(|header| {
+            let lower = header.lower.get();
+            if lower.is_null() {
+                None
+            } else {
+                Some(WduDevice::wrap_device(lower))
+            }
+        })
+    }
+
+    /// Attaches the device on top of the device stack of `target`. Usually called from AddDevice
+    /// with the PDO as target. The device the attach ended up on is stored so unhandled PnP
+    /// IRPs can be passed down.
+    pub fn attach_to_stack(&self, target: &WduDevice) -> WduDeviceResult<()> {
+        let header = self.header().ok_or(WduDeviceError::InvalidBuilderState)?;
+
+        let lower = unsafe { IoAttachDeviceToDeviceStack(self.device, target.device) };
+        if lower.is_null() {
+            return Err(WduDeviceError::AttachError);
+        }
+
+        header.lower.set(lower);
+        Ok(())
+    }
+
+    /// Copies the device type, buffering method and power flags of the lower device. Filters
+    /// must call this after attaching so the I/O manager treats them as the device they sit on.
+    pub fn inherit_from_lower(&mut self) {
+        let lower = match self.lower_device() {
+            Some(lower) => lower.device,
+            None => return,
+        };
+
+        unsafe {
+            let device = self.device_as_mut();
+            (*device).DeviceType = (*lower).DeviceType;
+            (*device).Characteristics |= (*lower).Characteristics & FILE_DEVICE_SECURE_OPEN;
+            (*device).Flags |= (*lower).Flags & (DO_BUFFERED_IO | DO_DIRECT_IO | DO_POWER_PAGABLE);
+        }
+    }
+
+    /// Detaches the device from the lower device. The library already does this when the
+    /// device receives IRP_MN_REMOVE_DEVICE.
+    pub fn detach(&self) {
+        if let Some(header) = self.header() {
+            let lower = header.lower.replace(core::ptr::null());
+            if !lower.is_null() {
+                unsafe { IoDetachDevice(lower as *mut _) }
+            }
+        }
+    }
+
+    // Always acquired without a tag, so the tag kept by WduRemoveLock stays None
+    pub(crate) fn acquire_remove_lock(&self) -> NTSTATUS {
+        match self.header() {
+            Some(header) => unsafe { (*header.remove_lock.get()).acquire(None) },
+            None => STATUS_SUCCESS,
+        }
+    }
+
+    pub(crate) fn release_remove_lock(&self) {
+        if let Some(header) = self.header() {
+            unsafe { (*header.remove_lock.get()).release() }
+        }
+    }
+
+    // Waits until every IRP being dispatched releases the lock, new ones fail to acquire it
+    pub(crate) fn release_remove_lock_and_wait(&self) {
+        if let Some(header) = self.header() {
+            unsafe { (*header.remove_lock.get()).release_and_wait() }
+        }
+    }
+
+    // Allocated when the first queue is set
+    fn queues(&self) -> Option<&WduDeviceQueues> {
+        let header = self.header()?;
+
+        let queues = header.queues.load(Ordering::Acquire);
+        if !queues.is_null() {
+            return Some(unsafe { &*queues });
+        }
+
+        let new = Box::into_raw(WduDeviceQueues::new());
+        match header.queues.compare_exchange(
+            core::ptr::null_mut(),
+            new,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => Some(unsafe { &*new }),
+            Err(current) => {
+                // Another thread set the first queue
+                drop(unsafe { Box::from_raw(new) });
+                Some(unsafe { &*current })
+            }
+        }
+    }
+
+    /// Delivers the IRPs of `major` sent to this device through `queue`. The major function
+    /// must be routed with [WduDriver::io_queue]. Power and PnP IRPs can't be queued.
+    ///
+    /// The device keeps a handle to the queue. Replacing a queue while the device receives
+    /// requests doesn't move the requests already routed to the previous one.
+    pub fn set_queue(&self, major: MajorFunction, queue: WduIoQueue) {
+        debug_assert!(
+            !matches!(major, MajorFunction::Pnp | MajorFunction::Power),
+            "PnP & power IRPs can't be queued"
+        );
+
+        if let Some(queues) = self.queues() {
+            queues.set(major, queue);
+        }
+    }
+
+    /// Delivers the IRP_MJ_DEVICE_CONTROL IRPs with the given IOCTL through `queue`. Takes
+    /// precedence over the queue set for IRP_MJ_DEVICE_CONTROL.
+    pub fn set_ioctl_queue(&self, ioctl: u32, queue: WduIoQueue) {
+        if let Some(queues) = self.queues() {
+            queues.set_ioctl(ioctl, queue);
+        }
+    }
+
+    pub(crate) fn queue_for(&self, irp: &WduIrp) -> Option<WduIoQueue> {
+        let header = self.header()?;
+        let queues = header.queues.load(Ordering::Acquire);
+
+        if queues.is_null() {
+            return None;
+        }
+
+        unsafe { (*queues).find(irp) }
+    }
+
+    /// Device power state that corresponds to the given system power state, as reported by the
+    /// bus driver in IRP_MN_QUERY_CAPABILITIES. Returns `Unspecified` until the capabilities
+    /// have been queried.
+    pub fn device_power_state_for(&self, system: WduSystemPowerState) -> WduDevicePowerState {
+        let index: i32 = system.into();
+        self.header().map_or(WduDevicePowerState::Unspecified, |header| {
+            header
+                .power_map
+                .get()
+                .get(index as usize)
+                .map_or(WduDevicePowerState::Unspecified, |state| {
+                    WduDevicePowerState::from(*state)
+                })
+        })
+    }
+
+    /// Notifies the power manager of the new power state of the device. Returns the previous
+    /// state.
+    pub fn set_power_state(&self, state: WduPowerState) -> WduPowerState {
+        let prev = unsafe { PoSetPowerState(self.device, state.state_type(), state.raw()) };
+
+        WduPowerState::from_raw(state.state_type(), prev)
+    }
+
+    /// Requests a device power IRP that will be sent to the top of the device stack of this
+    /// device. Usually used by the power policy owner to send a D-IRP when a S-IRP is received.
+    pub fn request_power_irp(
+        &self,
+        request: WduPowerRequest,
+        completion: Option<WduPowerCompletion>,
+    ) -> WduDeviceResult<()> {
+        let context = completion.map_or_else(core::ptr::null, |pfn| pfn as *const c_void);
+        let pfn = Self::power_completion as *mut u8;
+
+        let status = unsafe {
+            PoRequestPowerIrp(
+                self.device,
+                request.minor(),
+                request.state(),
+                core::mem::transmute_copy(&pfn),
+                context,
+                core::ptr::null_mut(),
+            )
+        };
+
+        if status != STATUS_PENDING && status != STATUS_SUCCESS {
+            return Err(WduDeviceError::GenericError { status });
+        }
+
+        Ok(())
+    }
+
+    unsafe extern "system" fn power_completion(
+        device: *const DEVICE_OBJECT,
+        minor: u8,
+        state: POWER_STATE,
+        context: *const c_void,
+        io_status: *const IO_STATUS_BLOCK,
+    ) {
+        if context.is_null() {
+            return;
+        }
+
+        let completion: WduPowerCompletion = core::mem::transmute(context);
+        let io_status = WduIoStatus::from_raw(&*io_status);
+
+        completion(
+            &WduDevice::wrap_device(device),
+            WduPowerRequest::from_raw(minor, state),
+            io_status,
+        );
+    }
+
     pub fn exclusive(mut self, exclusive: bool) -> Self {
         self.exclusive = exclusive;
         self
@@ -147,38 +655,117 @@ impl WduDevice {
         self
     }
 
-    // TODO: Create WduDeviceFlags
-    pub fn set_flags(&mut self, flags: u32) {
+    /// Creates the device with IoCreateDeviceSecure, `sddl` is the default security descriptor
+    /// of the device. Administrators can override it for every device of `class_guid` through
+    /// the registry, so use a GUID of our own, not one of the system device classes.
+    pub fn secure(mut self, sddl: WduSddl, class_guid: WduGuid) -> Self {
+        self.security = Some((sddl, class_guid));
+        self
+    }
+
+    /// Flags set in the device once created.
+    pub fn initial_flags(mut self, flags: WduDeviceFlags) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    /// Sets DO_BUFFERED_IO or DO_DIRECT_IO once the device is created.
+    pub fn io_method(mut self, method: WduIoMethod) -> Self {
+        self.flags
+            .remove(WduDeviceFlags::BufferedIo | WduDeviceFlags::DirectIo);
+        self.flags |= method.into();
+        self
+    }
+
+    /// Whether `build` clears DO_DEVICE_INITIALIZING, true by default. Devices that must not get
+    /// requests until they are attached to a stack can keep it and clear it themselves.
+    pub fn clear_initializing(mut self, clear: bool) -> Self {
+        self.clear_initializing = clear;
+        self
+    }
+
+    pub fn flags(&self) -> WduDeviceFlags {
+        WduDeviceFlags::from_bits_retain(unsafe { (*self.device).Flags })
+    }
+
+    pub fn set_flags(&mut self, flags: WduDeviceFlags) {
         unsafe {
-            (*self.device_as_mut()).Flags |= flags;
+            (*self.device_as_mut()).Flags |= flags.bits();
         }
     }
 
+    pub fn clear_flags(&mut self, flags: WduDeviceFlags) {
+        unsafe {
+            (*self.device_as_mut()).Flags &= !flags.bits();
+        }
+    }
+
+    /// Creates the device with `T::default()` as extension, see [WduDevice::build_with].
+    pub fn build<T: Default + 'static>(
+        self,
+        driver: &WduDriver<impl Sized>,
+        device_name: Option<&WduUnicodeStr>,
+    ) -> WduDeviceResult<Self> {
+        self.build_with(driver, device_name, T::default())
+    }
+
     // TODO: Consider if we store a copy of device_name
-    pub fn build<T>(
+    /// Creates the device and moves `extension` into the device extension. From then on the
+    /// extension can only be accessed as a `T` and it's dropped by [WduDevice::delete].
+    pub fn build_with<T: 'static>(
         mut self,
-        driver: &WduDriver,
+        driver: &WduDriver<impl Sized>,
         device_name: Option<&WduUnicodeStr>,
+        extension: T,
     ) -> WduDeviceResult<Self> {
+        if align_of::<T>() > MAX_EXTENSION_ALIGNMENT {
+            return Err(WduDeviceError::ExtensionAlignment {
+                alignment: align_of::<T>(),
+            });
+        }
+
         // I don't like this
         let name = device_name.map_or_else(|| WduUnicodeStr::default().into(), |name| name.into());
-        let ext_size = core::mem::size_of::<WduDevice>() + core::mem::size_of::<T>();
-        let status = unsafe {
-            IoCreateDevice(
-                driver.as_ptr(),
-                ext_size as u32,
-                &name,
-                self.device_type.into(),
-                self.characteristics.into(),
-                u8::from(self.exclusive),
-                &mut self.device as *mut _ as *mut _,
-            )
+        let ext_size = Self::extension_offset::<T>() + size_of::<T>();
+        let status = match self.security {
+            None => unsafe {
+                IoCreateDevice(
+                    driver.as_ptr(),
+                    ext_size as u32,
+                    &name,
+                    self.device_type.into(),
+                    self.characteristics.bits(),
+                    u8::from(self.exclusive),
+                    &mut self.device as *mut _ as *mut _,
+                )
+            },
+            Some((sddl, class_guid)) => {
+                let sddl = WduUnicodeString::try_from(sddl.as_str())
+                    .map_err(|source| WduDeviceError::SddlConversion { source })?;
+                unsafe {
+                    nt::IoCreateDeviceSecure(
+                        driver.as_ptr(),
+                        ext_size as u32,
+                        &name,
+                        self.device_type.into(),
+                        self.characteristics.bits(),
+                        u8::from(self.exclusive),
+                        sddl.as_ptr(),
+                        class_guid.as_ptr(),
+                        &mut self.device as *mut _ as *mut _,
+                    )
+                }
+            }
         };
 
         if status != STATUS_SUCCESS {
             return Err(WduDeviceError::CreateError { status });
         }
 
+        self.set_flags(self.flags);
+        self.extension_type = Some(TypeId::of::<T>());
+        self.drop_extension.set(Some(drop_extension::<T>));
+
         let ext = self.device_extension();
         unsafe {
             // Copy WduDevice to the our piece of the device extension so we have a reference to it.
@@ -186,17 +773,107 @@ impl WduDevice {
                 &self as *const WduDevice as *const _,
                 core::mem::size_of::<WduDevice>(),
             );
+            ext.wrapping_add(Self::extension_offset::<T>())
+                .cast::<T>()
+                .write(extension);
+        }
+        let header = Self::get_wdu_device(self.device);
+        header.file_contexts.init();
+        unsafe { (*header.remove_lock.get()).init(REMOVE_LOCK_TAG, 0, 0) };
+
+        // Last, the device can get requests from now on
+        if self.clear_initializing {
+            self.clear_flags(WduDeviceFlags::DeviceInitializing);
         }
 
         Ok(self)
     }
 
+    /// Deletes the device. The children of the device are torn down first, then the I/O queues
+    /// set in the device are purged and their handles dropped and the typed contexts and the
+    /// device extension are dropped, so this must be called at PASSIVE_LEVEL.
     pub fn delete(&self) {
+        if let Some(header) = self.header() {
+            unsafe { WduChildList::teardown(header.children.as_ptr()) };
+
+            let queues = header.queues.swap(core::ptr::null_mut(), Ordering::AcqRel);
+            if !queues.is_null() {
+                unsafe { Box::from_raw(queues) }.delete_all();
+            }
+
+            header.file_contexts.free_all();
+            unsafe { WduContextList::free(header.contexts.as_ptr()) };
+
+            if let Some(drop_extension) = header.drop_extension.take() {
+                drop_extension(self);
+            }
+        }
+
         if !self.device.is_null() {
             unsafe { IoDeleteDevice(self.device) }
         }
     }
 
+    /// Replaces the security of the device with `descriptor`. Only the parts present in the
+    /// descriptor (owner, group, DACL & SACL) are set. Must be called at PASSIVE_LEVEL.
+    pub fn set_security(&self, descriptor: &WduSecurityDescriptor) -> WduDeviceResult<()> {
+        let mut information = 0;
+        let mut access = 0;
+
+        if descriptor.owner_sid().is_some() {
+            information |= OWNER_SECURITY_INFORMATION;
+            access |= WRITE_OWNER;
+        }
+        if descriptor.group_sid().is_some() {
+            information |= GROUP_SECURITY_INFORMATION;
+            access |= WRITE_OWNER;
+        }
+        if descriptor.is_dacl_present() {
+            information |= DACL_SECURITY_INFORMATION;
+            information |= if descriptor
+                .control()
+                .contains(WduSecurityDescriptorControl::DaclProtected)
+            {
+                PROTECTED_DACL_SECURITY_INFORMATION
+            } else {
+                UNPROTECTED_DACL_SECURITY_INFORMATION
+            };
+            access |= WRITE_DAC;
+        }
+        if descriptor.is_sacl_present() {
+            information |= SACL_SECURITY_INFORMATION;
+            access |= ACCESS_SYSTEM_SECURITY;
+        }
+
+        let descriptor = descriptor.to_bytes();
+        let mut handle = 0;
+        let status = unsafe {
+            ObOpenObjectByPointer(
+                self.device as *const _,
+                OBJ_KERNEL_HANDLE as u32,
+                core::ptr::null(),
+                access,
+                0,
+                ProcessorMode::KernelMode.into(),
+                &mut handle,
+            )
+        };
+
+        if !nt_success(status) {
+            return Err(WduDeviceError::GenericError { status });
+        }
+
+        let status =
+            unsafe { ZwSetSecurityObject(handle, information, descriptor.as_ptr() as *mut _) };
+        unsafe { ZwClose(handle) };
+
+        if !nt_success(status) {
+            return Err(WduDeviceError::GenericError { status });
+        }
+
+        Ok(())
+    }
+
     pub fn symbolic_name(
         &self,
         device_name: &WduUnicodeStr,
@@ -221,6 +898,93 @@ impl WduDevice {
         Ok(())
     }
 
+    /// Registers a device interface of class `guid` for this device, which must be the PDO
+    /// (e.g. the one received in AddDevice). Returns the symbolic link name of the interface,
+    /// keep it to enable and disable the interface. The interface is disabled until enabled
+    /// with [WduDevice::enable_interface], usually when handling IRP_MN_START_DEVICE.
+    pub fn register_interface(
+        &self,
+        guid: &WduGuid,
+        reference: Option<&WduUnicodeStr>,
+    ) -> WduDeviceResult<WduUnicodeString> {
+        let reference: Option<UNICODE_STRING> = reference.map(|reference| reference.into());
+        let mut symbolic_link = UNICODE_STRING {
+            Length: 0,
+            MaximumLength: 0,
+            Buffer: core::ptr::null_mut(),
+        };
+
+        let status = unsafe {
+            IoRegisterDeviceInterface(
+                self.device,
+                guid.as_ptr(),
+                reference
+                    .as_ref()
+                    .map_or(core::ptr::null(), |reference| reference as *const _),
+                &mut symbolic_link,
+            )
+        };
+
+        if !nt_success(status) {
+            return Err(WduDeviceError::GenericError { status });
+        }
+
+        // Allocated by the I/O manager, freed with RtlFreeUnicodeString on drop
+        Ok(WduUnicodeString::take(symbolic_link))
+    }
+
+    pub fn enable_interface(symbolic_link: &WduUnicodeString) -> WduDeviceResult<()> {
+        Self::set_interface_state(symbolic_link, true)
+    }
+
+    /// Disabling an interface that's already disabled is not an error. The interfaces of a
+    /// device should be disabled when it's surprise removed or removed.
+    pub fn disable_interface(symbolic_link: &WduUnicodeString) -> WduDeviceResult<()> {
+        match Self::set_interface_state(symbolic_link, false) {
+            Err(WduDeviceError::GenericError {
+                status: STATUS_OBJECT_NAME_NOT_FOUND,
+            }) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn set_interface_state(symbolic_link: &WduUnicodeString, enable: bool) -> WduDeviceResult<()> {
+        let status = unsafe { IoSetDeviceInterfaceState(symbolic_link.as_ptr(), u8::from(enable)) };
+
+        if !nt_success(status) {
+            return Err(WduDeviceError::GenericError { status });
+        }
+
+        Ok(())
+    }
+
+    /// Makes `child` a child of the device and returns it in its final location. The children
+    /// are torn down in reverse order of creation when the device is deleted. Only devices
+    /// created by this driver can have children.
+    pub fn add_child<T: WduChild + 'static>(&self, child: T) -> WduChildResult<&T> {
+        let header = self.header().ok_or(WduChildError::Unsupported)?;
+        unsafe { WduChildList::add(header.children.as_ptr(), child) }
+    }
+
+    // Only devices created by a WduDriver can hold contexts
+    fn context_list(&self) -> Option<*mut *mut WduContextList> {
+        self.header().map(|header| header.contexts.as_ptr())
+    }
+
+    pub(crate) fn file_context_list(
+        &self,
+        file_object: *const FILE_OBJECT,
+    ) -> Option<*mut *mut WduContextList> {
+        self.header()
+            .map(|header| header.file_contexts.list(file_object))
+    }
+
+    pub(crate) fn free_file_contexts(&self, file_object: *const FILE_OBJECT) {
+        if let Some(header) = self.header() {
+            header.file_contexts.remove(file_object);
+        }
+    }
+
     pub(crate) fn get_wdu_device<'a>(device: *const DEVICE_OBJECT) -> &'a WduDevice {
         unsafe {
             let dev_ext = (*device).DeviceExtension;
@@ -228,41 +992,86 @@ impl WduDevice {
         }
     }
 
-    pub fn extension_as_ref<T>(&self) -> &T {
-        let mut dev_ext = self.device_extension();
-        unsafe {
-            dev_ext = dev_ext.wrapping_add(core::mem::size_of::<WduDevice>());
-            core::mem::transmute(dev_ext)
-        }
+    // The extension follows our copy of WduDevice
+    fn extension_offset<T>() -> usize {
+        size_of::<WduDevice>().next_multiple_of(align_of::<T>())
     }
 
-    pub fn extension_as_mut_ref<T>(&self) -> &mut T {
-        let mut dev_ext = self.device_extension();
-        unsafe {
-            dev_ext = dev_ext.wrapping_add(core::mem::size_of::<WduDevice>());
-            core::mem::transmute(dev_ext)
+    /// Pointer to the device extension. None if the device wasn't built with a `T` extension.
+    pub fn extension_ptr<T: 'static>(&self) -> Option<*mut T> {
+        let header = self.header()?;
+        if header.extension_type != Some(TypeId::of::<T>()) {
+            return None;
         }
+
+        Some(
+            self.device_extension()
+                .wrapping_add(Self::extension_offset::<T>())
+                .cast(),
+        )
     }
 
-    // We use read to take ownership of the interior data so we can properly call any allocated
-    // memory that we might be storing inside the DeviceExtension. We use read because we don't
-    // own the data where the device extension is allocated, the IO manager will deallocate the
-    // DeviceExtension once the device is deleted.
-    pub fn extension<T>(&self) -> T {
-        let mut dev_ext = self.device_extension();
-        unsafe {
-            dev_ext = dev_ext.wrapping_add(core::mem::size_of::<WduDevice>());
-            core::ptr::read(dev_ext as *const _)
+    /// Device extension. None if the device wasn't built with a `T` extension.
+    pub fn extension<T: 'static>(&self) -> Option<&T> {
+        self.extension_ptr().map(|extension| unsafe { &*extension })
+    }
+
+    /// Mutable device extension. None if the device wasn't built with a `T` extension. Handlers
+    /// only get a shared reference to the device, they can use [extension_ptr](Self::extension_ptr)
+    /// with their own synchronization.
+    ///
+    /// # Safety
+    /// Every request sent to the device can reach the extension, the caller must make sure no
+    /// other reference to it is alive while the returned one is used.
+    pub unsafe fn extension_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.extension_ptr().map(|extension| &mut *extension)
+    }
+}
+
+typed_context!(WduDevice);
+
+/// Symbolic link deleted on teardown. Usually added as a child of the device or the driver so
+/// the link goes away before the device. Dropping it doesn't delete the link.
+///
+/// ```ignore
+/// driver.add_child(WduSymbolicLink::create(&device_name, &win32_name)?)?;
+/// ```
+pub struct WduSymbolicLink {
+    name: WduUnicodeString,
+}
+
+impl WduSymbolicLink {
+    pub fn create(
+        device_name: &WduUnicodeStr,
+        symbolic_name: &WduUnicodeStr,
+    ) -> WduDeviceResult<Self> {
+        let name = symbolic_name
+            .to_owned()
+            .map_err(|source| WduDeviceError::SymbolicName { source })?;
+
+        let status = unsafe { IoCreateSymbolicLink(name.as_ptr(), &device_name.into()) };
+        if status != STATUS_SUCCESS {
+            return Err(WduDeviceError::GenericError { status });
         }
+
+        Ok(WduSymbolicLink { name })
+    }
+
+    pub fn name(&self) -> &WduUnicodeString {
+        &self.name
     }
+}
 
-    pub fn extension_as_ptr<T>(&self) -> *const T {
-        let mut dev_ext = self.device_extension();
-        unsafe { dev_ext.wrapping_add(core::mem::size_of::<WduDevice>()) as *const _ }
+impl WduChild for WduSymbolicLink {
+    fn teardown(&mut self) {
+        // Nothing else to do if it fails
+        let _ = unsafe { IoDeleteSymbolicLink(self.name.as_ptr()) };
     }
+}
 
-    pub fn extension_as_mut_ptr<T>(&self) -> *mut T {
-        let mut dev_ext = self.device_extension();
-        unsafe { dev_ext.wrapping_add(core::mem::size_of::<WduDevice>()) as *mut _ }
+// Set by build, lets delete drop the extension without knowing its type
+fn drop_extension<T: 'static>(device: &WduDevice) {
+    if let Some(extension) = device.extension_ptr::<T>() {
+        unsafe { core::ptr::drop_in_place(extension) }
     }
 }
@@ -1,13 +1,24 @@
 use crate::{
-    io::irp::WduIrp,
-    memory::mdl::{PagePriority, WduMdl},
+    io::{
+        ioctl::{WduIoctlCode, WduIoctlMethod},
+        irp::{WduIoStatus, WduIrp, WduIrpDisposition},
+        stack::WduStackParameters,
+    },
+    memory::{
+        mdl::{PagePriority, WduMdl},
+        user::{WduUserBuffer, WduUserMemoryError},
+    },
 };
-use core::ffi::c_void;
-use windows_sys::Win32::System::Ioctl::{
-    METHOD_BUFFERED, METHOD_IN_DIRECT, METHOD_NEITHER, METHOD_OUT_DIRECT,
+use core::{
+    ffi::c_void,
+    mem::{align_of, size_of},
+};
+use snafu::Snafu;
+use windows_sys::Win32::Foundation::{
+    NTSTATUS, STATUS_ACCESS_VIOLATION, STATUS_BUFFER_TOO_SMALL, STATUS_INVALID_PARAMETER,
 };
 
-/// Macro to define a I/O Control code (CTL_CODE)
+/// Macro to define a I/O Control code (CTL_CODE). See [WduIoctlCode] for a typed version.
 #[macro_export]
 macro_rules! encode_ioctl {
     ($device_type:expr, $function_code:expr, $method:expr, $access:expr) => {
@@ -15,27 +26,52 @@ macro_rules! encode_ioctl {
     };
 }
 
-macro_rules! method_from_ioctl {
-    ($ioctl:expr) => {
-        $ioctl & 0x3
-    };
+#[derive(Debug, Snafu)]
+pub enum WduDeviceControlError {
+    #[snafu(display("The request has no buffer"))]
+    NoBuffer,
+    #[snafu(display("Buffer of {size} bytes, at least {required} required"))]
+    BufferTooSmall { required: usize, size: usize },
+    #[snafu(display("Buffer is not aligned to {align} bytes"))]
+    Misaligned { align: usize },
+    #[snafu(display("METHOD_NEITHER buffers are user addresses and must be probed"))]
+    UserBuffer,
+    #[snafu(display("Invalid user buffer: {source}"))]
+    InvalidUserBuffer { source: WduUserMemoryError },
+}
+
+impl WduDeviceControlError {
+    /// Status to complete the request with.
+    pub fn status(&self) -> NTSTATUS {
+        match self {
+            Self::BufferTooSmall { .. } => STATUS_BUFFER_TOO_SMALL,
+            Self::InvalidUserBuffer {
+                source: WduUserMemoryError::AccessViolation { .. },
+            } => STATUS_ACCESS_VIOLATION,
+            _ => STATUS_INVALID_PARAMETER,
+        }
+    }
 }
 
-/*
- Hack until wdkmetadata has the right layout of IO_STACK_LOCATION.Parameters.DeviceIoControl.
- windows_sys defines DeviceIoControl Out/In buffer and Ioctl as u32, in x64 these values are
- defined as unsigned long.
- This hack fixes this mismatch in the layout
-*/
-#[repr(C)]
-#[allow(non_snake_case)]
-struct DeviceIoControlRaw {
-    OutputBufferLength: usize,
-    InputBufferLength: usize,
-    IoControlCode: usize,
-    Type3InputBuffer: *mut core::ffi::c_void,
+pub type WduDeviceControlResult<T> = Result<T, WduDeviceControlError>;
+
+/// Types that can be read from and written to IOCTL buffers.
+///
+/// # Safety
+/// The type must be `#[repr(C)]` (or a primitive), without padding and valid for any bit pattern,
+/// since the contents come from the requestor.
+pub unsafe trait WduIoctlData: Copy {}
+
+macro_rules! ioctl_data {
+    ($($type:ty),*) => {
+        $(unsafe impl WduIoctlData for $type {})*
+    };
 }
 
+ioctl_data!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: WduIoctlData, const N: usize> WduIoctlData for [T; N] {}
+
 pub enum WduIocltBuffers {
     Unknown,
     Buffered(*mut c_void),
@@ -52,29 +88,29 @@ pub struct WduDeviceControl {
 
 impl WduDeviceControl {
     pub(crate) unsafe fn new(irp: &WduIrp) -> Self {
-        let current_stack = WduIrp::current_stack(irp.as_ptr());
-        let parameters = (*current_stack).Parameters;
-
-        // Temporary
-        let data: DeviceIoControlRaw = core::mem::transmute(parameters);
-
-        let ioctl = data.IoControlCode as u32;
-        let in_buf_len = data.InputBufferLength;
-        let out_buf_len = data.OutputBufferLength;
+        let WduStackParameters::DeviceIoControl {
+            output_buffer_length,
+            input_buffer_length,
+            io_control_code: ioctl,
+            type3_input_buffer,
+        } = irp.current_stack_location().parameters()
+        else {
+            unreachable!("WduDeviceControl from a non device control IRP");
+        };
 
-        let method_bits = method_from_ioctl!(ioctl);
+        let in_buf_len = input_buffer_length as usize;
+        let out_buf_len = output_buffer_length as usize;
 
-        let buffer = match method_bits {
-            METHOD_BUFFERED => WduIocltBuffers::Buffered(irp.system_buffer()),
-            METHOD_IN_DIRECT | METHOD_OUT_DIRECT => {
+        let buffer = match WduIoctlCode::from_raw(ioctl).method() {
+            WduIoctlMethod::Buffered => WduIocltBuffers::Buffered(irp.system_buffer()),
+            WduIoctlMethod::InDirect | WduIoctlMethod::OutDirect => {
                 let buffers = (irp.system_buffer(), irp.mdl_address());
                 WduIocltBuffers::Direct(buffers)
             }
-            METHOD_NEITHER => {
-                let buffers = (data.Type3InputBuffer, irp.user_buffer());
+            WduIoctlMethod::Neither => {
+                let buffers = (type3_input_buffer, irp.user_buffer());
                 WduIocltBuffers::Neither(buffers)
             }
-            _ => WduIocltBuffers::Unknown,
         };
 
         Self {
@@ -89,6 +125,10 @@ impl WduDeviceControl {
         self.ioctl
     }
 
+    pub fn ioctl_code(&self) -> WduIoctlCode {
+        WduIoctlCode::from_raw(self.ioctl)
+    }
+
     pub fn input_buffer_size(&self) -> usize {
         self.in_buf_len
     }
@@ -127,4 +167,155 @@ impl WduDeviceControl {
             _ => 0,
         }
     }
+
+    /// Input buffer as `T`, fails if the buffer is smaller than `T` or not aligned for it.
+    pub fn input_as<T: WduIoctlData>(&self) -> WduDeviceControlResult<&T> {
+        let (buffer, size) = self.checked_input()?;
+        check_buffer::<T>(buffer, size)?;
+
+        Ok(unsafe { &*(buffer as *const T) })
+    }
+
+    pub fn input_slice(&self) -> WduDeviceControlResult<&[u8]> {
+        let (buffer, size) = self.checked_input()?;
+
+        Ok(unsafe { core::slice::from_raw_parts(buffer, size) })
+    }
+
+    /// Output buffer as `T`. With METHOD_BUFFERED the input and output share the same buffer, so
+    /// writing the output overwrites the input.
+    pub fn output_as_mut<T: WduIoctlData>(&mut self) -> WduDeviceControlResult<&mut T> {
+        let (buffer, size) = self.checked_output()?;
+        check_buffer::<T>(buffer, size)?;
+
+        Ok(unsafe { &mut *(buffer as *mut T) })
+    }
+
+    /// Writer over the output buffer that keeps track of the bytes written, to be returned as
+    /// the Information of the request.
+    pub fn output_writer(&mut self) -> WduDeviceControlResult<WduIoctlWriter<'_>> {
+        let (buffer, capacity) = self.checked_output()?;
+
+        Ok(WduIoctlWriter {
+            buffer: unsafe { core::slice::from_raw_parts_mut(buffer, capacity) },
+            written: 0,
+        })
+    }
+
+    /// Input buffer of a METHOD_NEITHER request sent from user mode. Must be used in the context
+    /// of the requestor, i.e. before queuing or pending the request.
+    pub fn user_input<T: WduIoctlData>(&self) -> WduDeviceControlResult<WduUserBuffer<T>> {
+        match self.buffer {
+            WduIocltBuffers::Neither((input, _)) => {
+                WduUserBuffer::from_bytes(input, self.in_buf_len)
+                    .map_err(|source| WduDeviceControlError::InvalidUserBuffer { source })
+            }
+            _ => Err(WduDeviceControlError::NoBuffer),
+        }
+    }
+
+    /// Output buffer of a METHOD_NEITHER request sent from user mode. Same restrictions as
+    /// [user_input](WduDeviceControl::user_input).
+    pub fn user_output<T: WduIoctlData>(&self) -> WduDeviceControlResult<WduUserBuffer<T>> {
+        match self.buffer {
+            WduIocltBuffers::Neither((_, output)) => {
+                WduUserBuffer::from_bytes(output, self.out_buf_len)
+                    .map_err(|source| WduDeviceControlError::InvalidUserBuffer { source })
+            }
+            _ => Err(WduDeviceControlError::NoBuffer),
+        }
+    }
+
+    // Only buffers already in system space are handed out
+    fn checked_input(&self) -> WduDeviceControlResult<(*const u8, usize)> {
+        let buffer = match self.buffer {
+            WduIocltBuffers::Buffered(buffer) => buffer,
+            WduIocltBuffers::Direct((buffer, _)) => buffer,
+            WduIocltBuffers::Neither(_) => return Err(WduDeviceControlError::UserBuffer),
+            WduIocltBuffers::Unknown => return Err(WduDeviceControlError::NoBuffer),
+        };
+
+        if buffer.is_null() || self.in_buf_len == 0 {
+            return Err(WduDeviceControlError::NoBuffer);
+        }
+
+        Ok((buffer as *const u8, self.in_buf_len))
+    }
+
+    fn checked_output(&self) -> WduDeviceControlResult<(*mut u8, usize)> {
+        if let WduIocltBuffers::Neither(_) = self.buffer {
+            return Err(WduDeviceControlError::UserBuffer);
+        }
+
+        let buffer = self.output_buffer();
+        if buffer.is_null() || self.out_buf_len == 0 {
+            return Err(WduDeviceControlError::NoBuffer);
+        }
+
+        Ok((buffer as *mut u8, self.out_buf_len))
+    }
+}
+
+fn check_buffer<T>(buffer: *const u8, size: usize) -> WduDeviceControlResult<()> {
+    if size < size_of::<T>() {
+        return Err(WduDeviceControlError::BufferTooSmall {
+            required: size_of::<T>(),
+            size,
+        });
+    }
+
+    if !(buffer as usize).is_multiple_of(align_of::<T>()) {
+        return Err(WduDeviceControlError::Misaligned {
+            align: align_of::<T>(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Sequential writer over the output buffer of a [WduDeviceControl].
+pub struct WduIoctlWriter<'a> {
+    buffer: &'a mut [u8],
+    written: usize,
+}
+
+impl<'a> WduIoctlWriter<'a> {
+    /// Appends `bytes`, nothing is written if they don't fit.
+    pub fn write(&mut self, bytes: &[u8]) -> WduDeviceControlResult<()> {
+        if bytes.len() > self.remaining() {
+            return Err(WduDeviceControlError::BufferTooSmall {
+                required: self.written + bytes.len(),
+                size: self.buffer.len(),
+            });
+        }
+
+        self.buffer[self.written..self.written + bytes.len()].copy_from_slice(bytes);
+        self.written += bytes.len();
+        Ok(())
+    }
+
+    /// Appends the bytes of `value`. The output buffer isn't required to be aligned.
+    pub fn write_value<T: WduIoctlData>(&mut self, value: &T) -> WduDeviceControlResult<()> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        self.write(bytes)
+    }
+
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.written
+    }
+
+    /// Success status with the bytes written as Information.
+    pub fn io_status(&self) -> WduIoStatus {
+        WduIoStatus::success_with_info(self.written)
+    }
+
+    /// Completes the request with [io_status](WduIoctlWriter::io_status).
+    pub fn complete(self, irp: &mut WduIrp) -> WduIrpDisposition {
+        irp.complete(self.io_status())
+    }
 }
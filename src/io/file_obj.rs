@@ -1,13 +1,25 @@
 use crate::{
-    inner_getters_ptr, strings::unicode::str::WduUnicodeStr,
+    common::context::{typed_context, WduContextList},
+    inner_getters_ptr,
+    io::device::WduDevice,
+    strings::unicode::str::WduUnicodeStr,
     strings::unicode::string::WduUnicodeString,
+    sync::spinlock::WduSpinLock,
 };
 use alloc::boxed::Box;
-use windows_sys::Wdk::Foundation::FILE_OBJECT;
+use core::cell::UnsafeCell;
+use windows_sys::Wdk::Foundation::{DEVICE_OBJECT, FILE_OBJECT};
 
-#[derive(PartialEq)]
 pub struct WduFileObject {
     file_object: *mut FILE_OBJECT,
+    // Device the request was sent to, keeps the typed contexts
+    device: *const DEVICE_OBJECT,
+}
+
+impl PartialEq for WduFileObject {
+    fn eq(&self, other: &Self) -> bool {
+        self.file_object == other.file_object
+    }
 }
 
 inner_getters_ptr!(WduFileObject, file_object, FILE_OBJECT);
@@ -17,8 +29,20 @@ impl WduFileObject {
         !self.file_object.is_null()
     }
 
+    /// File objects wrapped this way can't hold typed contexts, only the ones received in a
+    /// request sent to one of our devices can.
     pub fn wrap(fo: *mut FILE_OBJECT) -> Self {
-        Self { file_object: fo }
+        Self {
+            file_object: fo,
+            device: core::ptr::null(),
+        }
+    }
+
+    pub(crate) fn wrap_for_device(fo: *mut FILE_OBJECT, device: *const DEVICE_OBJECT) -> Self {
+        Self {
+            file_object: fo,
+            device,
+        }
     }
 
     pub fn file_name(&self) -> WduUnicodeStr {
@@ -112,4 +136,142 @@ impl WduFileObject {
             Some(core::mem::transmute((*self.file_object).FsContext2))
         }
     }
+
+    // The typed contexts are kept by the device the request was sent to, the file object itself
+    // may belong to another driver (e.g. opens through a filter or on a PDO).
+    fn context_list(&self) -> Option<*mut *mut WduContextList> {
+        if self.file_object.is_null() || self.device.is_null() {
+            return None;
+        }
+
+        WduDevice::wrap_device(self.device).file_context_list(self.file_object)
+    }
+
+    // Called once the close request leaves the driver
+    pub(crate) fn free_contexts(&self) {
+        if !self.file_object.is_null() && !self.device.is_null() {
+            WduDevice::wrap_device(self.device).free_file_contexts(self.file_object);
+        }
+    }
+}
+
+typed_context!(WduFileObject);
+
+struct WduFileContextsEntry {
+    file_object: *const FILE_OBJECT,
+    contexts: *mut WduContextList,
+    next: *mut WduFileContextsEntry,
+}
+
+// Typed contexts of the file objects opened through a device, keyed by FILE_OBJECT. Lives in the
+// copy of WduDevice in the DeviceExtension, entries are added on the first use of a file object
+// and removed once its close request leaves the driver.
+pub(crate) struct WduFileContexts {
+    lock: UnsafeCell<WduSpinLock>,
+    head: UnsafeCell<*mut WduFileContextsEntry>,
+}
+
+impl WduFileContexts {
+    pub(crate) fn new() -> Self {
+        WduFileContexts {
+            lock: UnsafeCell::new(WduSpinLock::new()),
+            head: UnsafeCell::new(core::ptr::null_mut()),
+        }
+    }
+
+    // Once the map is in its final location
+    pub(crate) fn init(&self) {
+        unsafe { (*self.lock.get()).init() }
+    }
+
+    fn locked<R>(&self, f: impl FnOnce(&mut *mut WduFileContextsEntry) -> R) -> R {
+        let lock = self.lock.get();
+
+        unsafe {
+            (*lock).acquire();
+            let result = f(&mut *self.head.get());
+            (*lock).release();
+            result
+        }
+    }
+
+    fn find(
+        head: *mut WduFileContextsEntry,
+        file_object: *const FILE_OBJECT,
+    ) -> *mut WduFileContextsEntry {
+        let mut entry = head;
+        unsafe {
+            while !entry.is_null() && (*entry).file_object != file_object {
+                entry = (*entry).next;
+            }
+        }
+
+        entry
+    }
+
+    // Location of the context list pointer of `file_object`, valid until the entry is removed
+    pub(crate) fn list(&self, file_object: *const FILE_OBJECT) -> *mut *mut WduContextList {
+        let mut entry = self.locked(|head| Self::find(*head, file_object));
+
+        if entry.is_null() {
+            // Allocated before taking the lock
+            let new = Box::into_raw(Box::new(WduFileContextsEntry {
+                file_object,
+                contexts: core::ptr::null_mut(),
+                next: core::ptr::null_mut(),
+            }));
+
+            entry = self.locked(|head| {
+                let found = Self::find(*head, file_object);
+                if !found.is_null() {
+                    return found;
+                }
+
+                unsafe { (*new).next = *head };
+                *head = new;
+                new
+            });
+
+            if entry != new {
+                drop(unsafe { Box::from_raw(new) });
+            }
+        }
+
+        unsafe { core::ptr::addr_of_mut!((*entry).contexts) }
+    }
+
+    // Runs the cleanup callbacks of the contexts of `file_object` and drops them
+    pub(crate) fn remove(&self, file_object: *const FILE_OBJECT) {
+        let entry = self.locked(|head| {
+            let mut link: *mut *mut WduFileContextsEntry = head;
+            unsafe {
+                while !(*link).is_null() {
+                    let entry = *link;
+                    if (*entry).file_object == file_object {
+                        *link = (*entry).next;
+                        return entry;
+                    }
+                    link = core::ptr::addr_of_mut!((*entry).next);
+                }
+            }
+
+            core::ptr::null_mut()
+        });
+
+        if !entry.is_null() {
+            let mut entry = unsafe { Box::from_raw(entry) };
+            unsafe { WduContextList::free(&mut entry.contexts) };
+        }
+    }
+
+    // File objects still open when the device is deleted
+    pub(crate) fn free_all(&self) {
+        let mut entry = self.locked(|head| core::mem::replace(head, core::ptr::null_mut()));
+
+        while !entry.is_null() {
+            let mut current = unsafe { Box::from_raw(entry) };
+            unsafe { WduContextList::free(&mut current.contexts) };
+            entry = current.next;
+        }
+    }
 }
@@ -0,0 +1,472 @@
+//! I/O control codes (CTL_CODE) and routing of IRP_MJ_DEVICE_CONTROL by code.
+use crate::{
+    common::driver::DispatchFn,
+    io::{
+        device::WduDevice,
+        device_control::{WduDeviceControl, WduIoctlData},
+        irp::{WduIoStatus, WduIrp, WduIrpDisposition},
+    },
+    nt_success,
+};
+use alloc::{boxed::Box, vec::Vec};
+use bitflags::bitflags;
+use core::{fmt, mem::size_of};
+use snafu::Snafu;
+use windows_sys::{
+    Wdk::System::SystemServices::IoValidateDeviceIoControlAccess,
+    Win32::{
+        Foundation::{NTSTATUS, STATUS_BUFFER_TOO_SMALL, STATUS_INVALID_PARAMETER, STATUS_SUCCESS},
+        System::Ioctl::{
+            FILE_ANY_ACCESS, FILE_READ_ACCESS, FILE_WRITE_ACCESS, METHOD_BUFFERED,
+            METHOD_IN_DIRECT, METHOD_NEITHER, METHOD_OUT_DIRECT,
+        },
+    },
+};
+
+const MAX_DEVICE_TYPE: u32 = 0xFFFF;
+const MAX_FUNCTION: u32 = 0xFFF;
+// Values below these are reserved for Microsoft
+const CUSTOM_DEVICE_TYPE: u32 = 0x8000;
+const CUSTOM_FUNCTION: u32 = 0x800;
+
+#[derive(Debug, Snafu)]
+pub enum WduIoctlError {
+    #[snafu(display("Device type {device_type:#x} doesn't fit in 16 bits"))]
+    InvalidDeviceType { device_type: u32 },
+    #[snafu(display("Function {function:#x} doesn't fit in 12 bits"))]
+    InvalidFunction { function: u32 },
+    #[snafu(display("Function {function:#x} is in the range reserved for Microsoft"))]
+    ReservedFunction { function: u32 },
+}
+
+pub type WduIoctlResult<T> = Result<T, WduIoctlError>;
+
+/// Transfer type of the control code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WduIoctlMethod {
+    Buffered,
+    InDirect,
+    OutDirect,
+    Neither,
+}
+
+impl WduIoctlMethod {
+    pub const fn from_raw(method: u32) -> Self {
+        match method & 0x3 {
+            METHOD_BUFFERED => Self::Buffered,
+            METHOD_IN_DIRECT => Self::InDirect,
+            METHOD_OUT_DIRECT => Self::OutDirect,
+            _ => Self::Neither,
+        }
+    }
+
+    pub const fn raw(self) -> u32 {
+        match self {
+            Self::Buffered => METHOD_BUFFERED,
+            Self::InDirect => METHOD_IN_DIRECT,
+            Self::OutDirect => METHOD_OUT_DIRECT,
+            Self::Neither => METHOD_NEITHER,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Buffered => "METHOD_BUFFERED",
+            Self::InDirect => "METHOD_IN_DIRECT",
+            Self::OutDirect => "METHOD_OUT_DIRECT",
+            Self::Neither => "METHOD_NEITHER",
+        }
+    }
+}
+
+bitflags! {
+    /// Access the caller must have requested when opening the handle.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WduIoctlAccess: u32 {
+        const Any = FILE_ANY_ACCESS;
+        const Read = FILE_READ_ACCESS;
+        const Write = FILE_WRITE_ACCESS;
+    }
+}
+
+/// I/O control code, same layout as the CTL_CODE macro:
+///
+/// ```text
+///  31     16 15  14 13     2 1    0
+/// +---------+------+--------+------+
+/// | DevType |Access|Function|Method|
+/// +---------+------+--------+------+
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct WduIoctlCode(u32);
+
+impl WduIoctlCode {
+    /// Builds the code, panics if `device_type` or `function` don't fit in their fields. Meant
+    /// for constants, so the check happens at compile time:
+    ///
+    /// ```ignore
+    /// const IOCTL_TEST: WduIoctlCode = WduIoctlCode::new(
+    ///     FILE_DEVICE_UNKNOWN,
+    ///     0x800,
+    ///     WduIoctlMethod::Buffered,
+    ///     WduIoctlAccess::Any,
+    /// );
+    /// ```
+    pub const fn new(
+        device_type: u32,
+        function: u32,
+        method: WduIoctlMethod,
+        access: WduIoctlAccess,
+    ) -> Self {
+        match Self::try_new(device_type, function, method, access) {
+            Ok(code) => code,
+            Err(_) => panic!("Invalid I/O control code"),
+        }
+    }
+
+    pub const fn try_new(
+        device_type: u32,
+        function: u32,
+        method: WduIoctlMethod,
+        access: WduIoctlAccess,
+    ) -> WduIoctlResult<Self> {
+        if device_type > MAX_DEVICE_TYPE {
+            return Err(WduIoctlError::InvalidDeviceType { device_type });
+        }
+
+        if function > MAX_FUNCTION {
+            return Err(WduIoctlError::InvalidFunction { function });
+        }
+
+        Ok(Self(
+            (device_type << 16) | (access.bits() << 14) | (function << 2) | method.raw(),
+        ))
+    }
+
+    pub const fn from_raw(code: u32) -> Self {
+        Self(code)
+    }
+
+    pub const fn raw(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn device_type(&self) -> u32 {
+        self.0 >> 16
+    }
+
+    pub const fn function(&self) -> u32 {
+        (self.0 >> 2) & MAX_FUNCTION
+    }
+
+    pub const fn method(&self) -> WduIoctlMethod {
+        WduIoctlMethod::from_raw(self.0)
+    }
+
+    pub const fn access(&self) -> WduIoctlAccess {
+        WduIoctlAccess::from_bits_truncate((self.0 >> 14) & 0x3)
+    }
+
+    /// Device types below 0x8000 are reserved for Microsoft. Most third party drivers still
+    /// use FILE_DEVICE_UNKNOWN, so this isn't checked by [validate](WduIoctlCode::validate).
+    pub const fn is_custom_device_type(&self) -> bool {
+        self.device_type() >= CUSTOM_DEVICE_TYPE
+    }
+
+    pub const fn is_custom_function(&self) -> bool {
+        self.function() >= CUSTOM_FUNCTION
+    }
+
+    /// Checks the function is outside the range reserved for Microsoft, as required for codes
+    /// defined by third party drivers.
+    pub const fn validate(&self) -> WduIoctlResult<()> {
+        if !self.is_custom_function() {
+            return Err(WduIoctlError::ReservedFunction {
+                function: self.function(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl From<u32> for WduIoctlCode {
+    fn from(value: u32) -> Self {
+        Self::from_raw(value)
+    }
+}
+
+impl From<WduIoctlCode> for u32 {
+    fn from(value: WduIoctlCode) -> Self {
+        value.raw()
+    }
+}
+
+impl PartialEq<u32> for WduIoctlCode {
+    fn eq(&self, other: &u32) -> bool {
+        self.0 == *other
+    }
+}
+
+impl fmt::Display for WduIoctlCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = self.access();
+        let access = if access == WduIoctlAccess::Any {
+            "FILE_ANY_ACCESS"
+        } else if access == WduIoctlAccess::Read {
+            "FILE_READ_ACCESS"
+        } else if access == WduIoctlAccess::Write {
+            "FILE_WRITE_ACCESS"
+        } else {
+            "FILE_READ_ACCESS | FILE_WRITE_ACCESS"
+        };
+
+        write!(
+            f,
+            "{:#010x} CTL_CODE({:#x}, {:#x}, {}, {})",
+            self.0,
+            self.device_type(),
+            self.function(),
+            self.method().name(),
+            access
+        )
+    }
+}
+
+/// IOCTL registered in a [WduIoctlRouter]. The router only calls the handler once the buffers
+/// are at least the registered sizes and the caller has the required access, so the handler can
+/// use [input_as](crate::io::device_control::WduDeviceControl::input_as) and
+/// [output_as_mut](crate::io::device_control::WduDeviceControl::output_as_mut) with the
+/// registered types. Handlers added with [new_with](Self::new_with) also receive the driver
+/// context.
+pub struct WduIoctlRoute<C = ()> {
+    code: WduIoctlCode,
+    handler: DispatchFn<C, WduDeviceControl>,
+    input_size: usize,
+    output_size: usize,
+    access: WduIoctlAccess,
+}
+
+impl<C: 'static> WduIoctlRoute<C> {
+    pub fn new<F>(code: WduIoctlCode, handler: F) -> Self
+    where
+        F: Fn(&WduDevice, &mut WduIrp, WduDeviceControl) -> WduIrpDisposition
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self::new_with(code, move |_, device, irp, ioctl| {
+            handler(device, irp, ioctl)
+        })
+    }
+
+    pub fn new_with<F>(code: WduIoctlCode, handler: F) -> Self
+    where
+        F: Fn(&C, &WduDevice, &mut WduIrp, WduDeviceControl) -> WduIrpDisposition
+            + Send
+            + Sync
+            + 'static,
+    {
+        WduIoctlRoute {
+            code,
+            handler: Box::new(handler),
+            input_size: 0,
+            output_size: 0,
+            access: WduIoctlAccess::Any,
+        }
+    }
+
+    /// Input buffer must hold a `T`.
+    pub fn input<T: WduIoctlData>(self) -> Self {
+        self.min_input(size_of::<T>())
+    }
+
+    /// Output buffer must hold a `T`.
+    pub fn output<T: WduIoctlData>(self) -> Self {
+        self.min_output(size_of::<T>())
+    }
+
+    pub fn min_input(mut self, size: usize) -> Self {
+        self.input_size = size;
+        self
+    }
+
+    pub fn min_output(mut self, size: usize) -> Self {
+        self.output_size = size;
+        self
+    }
+
+    /// Access the handle must have been opened with, on top of the one encoded in the code.
+    /// Usually for codes defined with FILE_ANY_ACCESS that can't be changed.
+    pub fn access(mut self, access: WduIoctlAccess) -> Self {
+        self.access = access;
+        self
+    }
+}
+
+impl<C> WduIoctlRoute<C> {
+    fn validate(&self, irp: &WduIrp, ioctl: &WduDeviceControl) -> NTSTATUS {
+        if self.access != WduIoctlAccess::Any {
+            let status =
+                unsafe { IoValidateDeviceIoControlAccess(irp.as_ptr(), self.access.bits()) };
+            if !nt_success(status) {
+                return status;
+            }
+        }
+
+        if ioctl.input_buffer_size() < self.input_size {
+            return STATUS_INVALID_PARAMETER;
+        }
+
+        if ioctl.output_buffer_size() < self.output_size {
+            return STATUS_BUFFER_TOO_SMALL;
+        }
+
+        STATUS_SUCCESS
+    }
+}
+
+/// Table of IOCTL handlers, set with
+/// [IoDispath::ioctl_router](crate::common::driver::IoDispath::ioctl_router). Codes without a
+/// route go to the [ioctl_irp](crate::common::driver::IoDispath::ioctl_irp) handler if any,
+/// otherwise they are completed with STATUS_INVALID_DEVICE_REQUEST.
+pub struct WduIoctlRouter<C = ()> {
+    routes: Vec<WduIoctlRoute<C>>,
+}
+
+impl<C> Default for WduIoctlRouter<C> {
+    fn default() -> Self {
+        Self { routes: Vec::new() }
+    }
+}
+
+impl<C> WduIoctlRouter<C> {
+    /// Adds `route`, replacing any previous route for the same code.
+    pub fn route(mut self, route: WduIoctlRoute<C>) -> Self {
+        match self.routes.iter_mut().find(|r| r.code == route.code) {
+            Some(existing) => *existing = route,
+            None => self.routes.push(route),
+        }
+        self
+    }
+
+    pub(crate) fn handles(&self, ioctl: &WduDeviceControl) -> bool {
+        self.find(ioctl).is_some()
+    }
+
+    pub(crate) fn dispatch(
+        &self,
+        context: &C,
+        device: &WduDevice,
+        irp: &mut WduIrp,
+        ioctl: WduDeviceControl,
+    ) -> Option<WduIrpDisposition> {
+        let route = self.find(&ioctl)?;
+
+        let status = route.validate(irp, &ioctl);
+        if !nt_success(status) {
+            return Some(irp.complete(WduIoStatus::new_with_status(status)));
+        }
+
+        Some((route.handler)(context, device, irp, ioctl))
+    }
+
+    fn find(&self, ioctl: &WduDeviceControl) -> Option<&WduIoctlRoute<C>> {
+        self.routes.iter().find(|r| r.code == ioctl.ioctl())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_ioctl;
+    use alloc::string::ToString;
+    use windows_sys::Win32::System::Ioctl::FILE_DEVICE_UNKNOWN;
+
+    const IOCTL_TEST: WduIoctlCode = WduIoctlCode::new(
+        FILE_DEVICE_UNKNOWN,
+        0x801,
+        WduIoctlMethod::OutDirect,
+        WduIoctlAccess::Read.union(WduIoctlAccess::Write),
+    );
+
+    #[test]
+    fn encode() {
+        assert_eq!(IOCTL_TEST.raw(), 0x22e006);
+        assert_eq!(
+            IOCTL_TEST,
+            encode_ioctl!(
+                FILE_DEVICE_UNKNOWN,
+                0x801,
+                METHOD_OUT_DIRECT,
+                FILE_READ_ACCESS | FILE_WRITE_ACCESS
+            )
+        );
+
+        let code = WduIoctlCode::new(
+            FILE_DEVICE_UNKNOWN,
+            0x800,
+            WduIoctlMethod::Buffered,
+            WduIoctlAccess::Any,
+        );
+        assert_eq!(code.raw(), 0x222000);
+    }
+
+    #[test]
+    fn decode() {
+        let code = WduIoctlCode::from(0x22e006);
+        assert_eq!(code.device_type(), FILE_DEVICE_UNKNOWN);
+        assert_eq!(code.function(), 0x801);
+        assert_eq!(code.method(), WduIoctlMethod::OutDirect);
+        assert_eq!(code.access(), WduIoctlAccess::Read | WduIoctlAccess::Write);
+        assert!(code.is_custom_function());
+        assert!(!code.is_custom_device_type());
+        assert_eq!(u32::from(code), 0x22e006);
+
+        for method in [
+            WduIoctlMethod::Buffered,
+            WduIoctlMethod::InDirect,
+            WduIoctlMethod::OutDirect,
+            WduIoctlMethod::Neither,
+        ] {
+            assert_eq!(WduIoctlMethod::from_raw(method.raw()), method);
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let method = WduIoctlMethod::Buffered;
+        let access = WduIoctlAccess::Any;
+
+        assert!(matches!(
+            WduIoctlCode::try_new(0x10000, 0x800, method, access),
+            Err(WduIoctlError::InvalidDeviceType {
+                device_type: 0x10000
+            })
+        ));
+        assert!(matches!(
+            WduIoctlCode::try_new(0x8000, 0x1000, method, access),
+            Err(WduIoctlError::InvalidFunction { function: 0x1000 })
+        ));
+
+        let code = WduIoctlCode::try_new(0x8000, 0x7ff, method, access).unwrap();
+        assert!(code.is_custom_device_type());
+        assert!(matches!(
+            code.validate(),
+            Err(WduIoctlError::ReservedFunction { function: 0x7ff })
+        ));
+        assert!(IOCTL_TEST.validate().is_ok());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            IOCTL_TEST.to_string(),
+            "0x0022e006 CTL_CODE(0x22, 0x801, METHOD_OUT_DIRECT, FILE_READ_ACCESS | FILE_WRITE_ACCESS)"
+        );
+        assert_eq!(
+            WduIoctlCode::from_raw(0x222003).to_string(),
+            "0x00222003 CTL_CODE(0x22, 0x800, METHOD_NEITHER, FILE_ANY_ACCESS)"
+        );
+    }
+}
@@ -1,48 +1,213 @@
 use crate::{
+    common::context::{typed_context, WduContextList},
     inner_getters_ptr,
-    io::{device::WduDevice, file_obj::WduFileObject},
+    io::{device::WduDevice, file_obj::WduFileObject, stack::WduIoStackLocation},
     memory::mdl::WduMdl,
-    ProcessorMode,
+    nt_success, ProcessorMode,
+};
+use alloc::{boxed::Box, sync::Arc};
+use bitflags::bitflags;
+use core::{
+    cell::{Cell, OnceCell},
+    ffi::c_void,
+    sync::atomic::{AtomicU8, Ordering},
 };
-use alloc::boxed::Box;
-use core::ffi::c_void;
 use windows_sys::{
     Wdk::{
         Foundation::{DEVICE_OBJECT, IO_STACK_LOCATION, IRP},
         System::SystemServices::{
-            IoForwardIrpSynchronously, IoReleaseCancelSpinLock, IofCompleteRequest, IRP_MJ_CLEANUP,
-            IRP_MJ_CLOSE, IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL, IRP_MJ_READ, IRP_MJ_WRITE,
-            SL_PENDING_RETURNED,
+            IoForwardIrpSynchronously, IoReleaseCancelSpinLock, IofCallDriver, IofCompleteRequest,
+            PoCallDriver, PoStartNextPowerIrp, IRP_MJ_CLEANUP, IRP_MJ_CLOSE, IRP_MJ_CREATE,
+            IRP_MJ_CREATE_MAILSLOT, IRP_MJ_CREATE_NAMED_PIPE, IRP_MJ_DEVICE_CHANGE,
+            IRP_MJ_DEVICE_CONTROL, IRP_MJ_DIRECTORY_CONTROL, IRP_MJ_FILE_SYSTEM_CONTROL,
+            IRP_MJ_FLUSH_BUFFERS, IRP_MJ_INTERNAL_DEVICE_CONTROL, IRP_MJ_LOCK_CONTROL,
+            IRP_MJ_MAXIMUM_FUNCTION, IRP_MJ_PNP, IRP_MJ_POWER, IRP_MJ_QUERY_EA,
+            IRP_MJ_QUERY_INFORMATION, IRP_MJ_QUERY_QUOTA, IRP_MJ_QUERY_SECURITY,
+            IRP_MJ_QUERY_VOLUME_INFORMATION, IRP_MJ_READ, IRP_MJ_SET_EA, IRP_MJ_SET_INFORMATION,
+            IRP_MJ_SET_QUOTA, IRP_MJ_SET_SECURITY, IRP_MJ_SET_VOLUME_INFORMATION, IRP_MJ_SHUTDOWN,
+            IRP_MJ_SYSTEM_CONTROL, IRP_MJ_WRITE, SL_INVOKE_ON_CANCEL, SL_INVOKE_ON_ERROR,
+            SL_INVOKE_ON_SUCCESS, SL_PENDING_RETURNED,
+        },
+    },
+    Win32::{
+        Foundation::{
+            NTSTATUS, STATUS_MORE_PROCESSING_REQUIRED, STATUS_PENDING, STATUS_SUCCESS,
+            STATUS_UNSUCCESSFUL,
         },
+        System::IO::IO_STATUS_BLOCK,
     },
-    Win32::Foundation::{NTSTATUS, STATUS_SUCCESS, STATUS_UNSUCCESSFUL},
 };
 
 pub type WduCancelRoutine = fn(&WduDevice, &mut WduIrp) -> ();
 
+/// Completion routine set with [WduIrp::forward_with_completion].
+pub type WduCompletionRoutine = fn(&WduDevice, &mut WduIrp) -> WduCompletionAction;
+
+/// What the I/O manager should do once a completion routine returns.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WduCompletionAction {
+    /// Keep completing the IRP up the stack (STATUS_CONTINUE_COMPLETION). If a lower driver
+    /// returned STATUS_PENDING the IRP is marked pending for us.
+    Continue,
+    /// Stop the completion (STATUS_MORE_PROCESSING_REQUIRED). The driver owns the IRP again and
+    /// must complete it later.
+    MoreProcessingRequired,
+}
+
+bitflags! {
+    /// When the completion routine set with [WduIrp::set_completion] is called.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WduCompletionFlags: u8 {
+        const InvokeOnSuccess = SL_INVOKE_ON_SUCCESS as u8;
+        const InvokeOnError = SL_INVOKE_ON_ERROR as u8;
+        const InvokeOnCancel = SL_INVOKE_ON_CANCEL as u8;
+        const InvokeAlways = Self::InvokeOnSuccess.bits()
+            | Self::InvokeOnError.bits()
+            | Self::InvokeOnCancel.bits();
+    }
+}
+
+type WduCompletionFn = dyn FnOnce(&WduDevice, &mut WduIrp) -> WduCompletionAction + Send;
+
+// Context of the completion trampoline, freed once the IRP comes back or when the IRP is
+// completed or passed down without being sent to the routine's driver.
+struct WduCompletion {
+    flags: WduCompletionFlags,
+    routine: Box<WduCompletionFn>,
+}
+
+// DriverContext[2] & [3] are used by WduCancelSafeQueue and the cancel routine
+const CONTEXT_INDEX: usize = 0;
+
+/// Typed contexts attached to the IRP are dropped when the IRP is completed or sent to another
+/// driver, since the next owner can overwrite DriverContext.
+///
+/// Clones share the state of the IRP, once it's completed or sent down through any of them the
+/// others won't complete it again.
 #[derive(Clone)]
 pub struct WduIrp {
     irp: *mut IRP,
     mj: MajorFunction,
     cancel: Option<WduCancelRoutine>,
+    state: WduIrpStateCell,
 }
 
-inner_getters_ptr!(WduIrp, irp, IRP);
+// Tracks what the driver did with the IRP
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum WduIrpState {
+    Owned,
+    Pending,
+    Completed,
+    Forwarded,
+}
 
-#[derive(Copy, Clone)]
-pub(crate) enum MajorFunction {
-    Create,
-    Close,
-    Cleanup,
-    Read,
-    Write,
-    DeviceControl,
+impl From<u8> for WduIrpState {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => WduIrpState::Owned,
+            1 => WduIrpState::Pending,
+            2 => WduIrpState::Completed,
+            _ => WduIrpState::Forwarded,
+        }
+    }
 }
 
-pub enum WduPowerIrp {}
-pub enum WduPnpIrp {}
+// The state is kept in the WduIrp until it's cloned, most IRPs never are so only then it's moved
+// to an allocation shared by the clones.
+struct WduIrpStateCell {
+    local: Cell<WduIrpState>,
+    shared: OnceCell<Arc<AtomicU8>>,
+}
 
-#[derive(Clone)]
+impl WduIrpStateCell {
+    fn new() -> Self {
+        Self {
+            local: Cell::new(WduIrpState::Owned),
+            shared: OnceCell::new(),
+        }
+    }
+
+    fn get(&self) -> WduIrpState {
+        match self.shared.get() {
+            Some(shared) => WduIrpState::from(shared.load(Ordering::Acquire)),
+            None => self.local.get(),
+        }
+    }
+
+    fn set(&self, state: WduIrpState) {
+        match self.shared.get() {
+            Some(shared) => shared.store(state as u8, Ordering::Release),
+            None => self.local.set(state),
+        }
+    }
+}
+
+impl Clone for WduIrpStateCell {
+    fn clone(&self) -> Self {
+        let shared = self
+            .shared
+            .get_or_init(|| Arc::new(AtomicU8::new(self.local.get() as u8)));
+
+        Self {
+            local: self.local.clone(),
+            shared: OnceCell::from(shared.clone()),
+        }
+    }
+}
+
+/// Outcome of a dispatch handler.
+///
+/// The library uses it to decide what to do with the IRP once the handler returns and what
+/// status is returned to the I/O manager.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WduIrpDisposition {
+    /// The IRP is done. If the handler didn't complete it, the library completes it with this
+    /// status keeping the Information already set in the IRP.
+    Completed(NTSTATUS),
+    /// The IRP was marked pending and will be completed later. Returns STATUS_PENDING.
+    Pending,
+    /// The IRP was sent to another driver. Returns the status from the call.
+    Forwarded(NTSTATUS),
+}
+
+inner_getters_ptr!(WduIrp, irp, IRP);
+
+/// IRP major function codes. The discriminant matches the IRP_MJ_* value, which is also the
+/// index in the DRIVER_OBJECT MajorFunction table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MajorFunction {
+    Create = IRP_MJ_CREATE as isize,
+    CreateNamedPipe = IRP_MJ_CREATE_NAMED_PIPE as isize,
+    Close = IRP_MJ_CLOSE as isize,
+    Read = IRP_MJ_READ as isize,
+    Write = IRP_MJ_WRITE as isize,
+    QueryInformation = IRP_MJ_QUERY_INFORMATION as isize,
+    SetInformation = IRP_MJ_SET_INFORMATION as isize,
+    QueryEa = IRP_MJ_QUERY_EA as isize,
+    SetEa = IRP_MJ_SET_EA as isize,
+    FlushBuffers = IRP_MJ_FLUSH_BUFFERS as isize,
+    QueryVolumeInformation = IRP_MJ_QUERY_VOLUME_INFORMATION as isize,
+    SetVolumeInformation = IRP_MJ_SET_VOLUME_INFORMATION as isize,
+    DirectoryControl = IRP_MJ_DIRECTORY_CONTROL as isize,
+    FileSystemControl = IRP_MJ_FILE_SYSTEM_CONTROL as isize,
+    DeviceControl = IRP_MJ_DEVICE_CONTROL as isize,
+    // Also IRP_MJ_SCSI
+    InternalDeviceControl = IRP_MJ_INTERNAL_DEVICE_CONTROL as isize,
+    Shutdown = IRP_MJ_SHUTDOWN as isize,
+    LockControl = IRP_MJ_LOCK_CONTROL as isize,
+    Cleanup = IRP_MJ_CLEANUP as isize,
+    CreateMailslot = IRP_MJ_CREATE_MAILSLOT as isize,
+    QuerySecurity = IRP_MJ_QUERY_SECURITY as isize,
+    SetSecurity = IRP_MJ_SET_SECURITY as isize,
+    Power = IRP_MJ_POWER as isize,
+    SystemControl = IRP_MJ_SYSTEM_CONTROL as isize,
+    DeviceChange = IRP_MJ_DEVICE_CHANGE as isize,
+    QueryQuota = IRP_MJ_QUERY_QUOTA as isize,
+    SetQuota = IRP_MJ_SET_QUOTA as isize,
+    Pnp = IRP_MJ_PNP as isize,
+}
+
+#[derive(Debug, Copy, Clone)]
 pub struct WduIoStatus {
     status: NTSTATUS,
     info: usize,
@@ -52,37 +217,102 @@ impl From<u32> for MajorFunction {
     fn from(value: u32) -> Self {
         match value {
             IRP_MJ_CREATE => MajorFunction::Create,
+            IRP_MJ_CREATE_NAMED_PIPE => MajorFunction::CreateNamedPipe,
             IRP_MJ_CLOSE => MajorFunction::Close,
-            IRP_MJ_CLEANUP => MajorFunction::Cleanup,
             IRP_MJ_READ => MajorFunction::Read,
             IRP_MJ_WRITE => MajorFunction::Write,
+            IRP_MJ_QUERY_INFORMATION => MajorFunction::QueryInformation,
+            IRP_MJ_SET_INFORMATION => MajorFunction::SetInformation,
+            IRP_MJ_QUERY_EA => MajorFunction::QueryEa,
+            IRP_MJ_SET_EA => MajorFunction::SetEa,
+            IRP_MJ_FLUSH_BUFFERS => MajorFunction::FlushBuffers,
+            IRP_MJ_QUERY_VOLUME_INFORMATION => MajorFunction::QueryVolumeInformation,
+            IRP_MJ_SET_VOLUME_INFORMATION => MajorFunction::SetVolumeInformation,
+            IRP_MJ_DIRECTORY_CONTROL => MajorFunction::DirectoryControl,
+            IRP_MJ_FILE_SYSTEM_CONTROL => MajorFunction::FileSystemControl,
             IRP_MJ_DEVICE_CONTROL => MajorFunction::DeviceControl,
-            _ => todo!(),
+            IRP_MJ_INTERNAL_DEVICE_CONTROL => MajorFunction::InternalDeviceControl,
+            IRP_MJ_SHUTDOWN => MajorFunction::Shutdown,
+            IRP_MJ_LOCK_CONTROL => MajorFunction::LockControl,
+            IRP_MJ_CLEANUP => MajorFunction::Cleanup,
+            IRP_MJ_CREATE_MAILSLOT => MajorFunction::CreateMailslot,
+            IRP_MJ_QUERY_SECURITY => MajorFunction::QuerySecurity,
+            IRP_MJ_SET_SECURITY => MajorFunction::SetSecurity,
+            IRP_MJ_POWER => MajorFunction::Power,
+            IRP_MJ_SYSTEM_CONTROL => MajorFunction::SystemControl,
+            IRP_MJ_DEVICE_CHANGE => MajorFunction::DeviceChange,
+            IRP_MJ_QUERY_QUOTA => MajorFunction::QueryQuota,
+            IRP_MJ_SET_QUOTA => MajorFunction::SetQuota,
+            IRP_MJ_PNP => MajorFunction::Pnp,
+            // The I/O manager only dispatches through the MajorFunction table
+            _ => unreachable!("Invalid major function {value}"),
         }
     }
 }
 
 impl MajorFunction {
+    /// Every major function, in IRP_MJ_* order
+    pub const ALL: [MajorFunction; IRP_MJ_MAXIMUM_FUNCTION as usize + 1] = [
+        MajorFunction::Create,
+        MajorFunction::CreateNamedPipe,
+        MajorFunction::Close,
+        MajorFunction::Read,
+        MajorFunction::Write,
+        MajorFunction::QueryInformation,
+        MajorFunction::SetInformation,
+        MajorFunction::QueryEa,
+        MajorFunction::SetEa,
+        MajorFunction::FlushBuffers,
+        MajorFunction::QueryVolumeInformation,
+        MajorFunction::SetVolumeInformation,
+        MajorFunction::DirectoryControl,
+        MajorFunction::FileSystemControl,
+        MajorFunction::DeviceControl,
+        MajorFunction::InternalDeviceControl,
+        MajorFunction::Shutdown,
+        MajorFunction::LockControl,
+        MajorFunction::Cleanup,
+        MajorFunction::CreateMailslot,
+        MajorFunction::QuerySecurity,
+        MajorFunction::SetSecurity,
+        MajorFunction::Power,
+        MajorFunction::SystemControl,
+        MajorFunction::DeviceChange,
+        MajorFunction::QueryQuota,
+        MajorFunction::SetQuota,
+        MajorFunction::Pnp,
+    ];
+
+    pub(crate) fn index(&self) -> usize {
+        *self as usize
+    }
+
     fn is_io(&self) -> bool {
-        match self {
-            MajorFunction::Write | MajorFunction::Read | MajorFunction::DeviceControl => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            MajorFunction::Write | MajorFunction::Read | MajorFunction::DeviceControl
+        )
     }
 
     fn is_fileobj(&self) -> bool {
-        match self {
-            MajorFunction::Create | MajorFunction::Close | MajorFunction::Cleanup => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            MajorFunction::Create | MajorFunction::Close | MajorFunction::Cleanup
+        )
     }
 
     fn is_power(&self) -> bool {
-        todo!()
+        matches!(self, MajorFunction::Power)
     }
 
     fn is_pnp(&self) -> bool {
-        todo!()
+        matches!(self, MajorFunction::Pnp)
+    }
+}
+
+impl Default for WduIoStatus {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -95,10 +325,7 @@ impl WduIoStatus {
     }
 
     pub fn new_with_status(status: NTSTATUS) -> Self {
-        WduIoStatus {
-            status: status,
-            info: 0,
-        }
+        WduIoStatus { status, info: 0 }
     }
 
     pub fn success_no_info() -> Self {
@@ -126,16 +353,82 @@ impl WduIoStatus {
     pub fn set_info(&mut self, info: usize) {
         self.info = info;
     }
+
+    pub fn info(&self) -> usize {
+        self.info
+    }
+
+    pub(crate) fn from_raw(io_status: &IO_STATUS_BLOCK) -> Self {
+        Self {
+            status: unsafe { io_status.Anonymous.Status },
+            info: io_status.Information,
+        }
+    }
 }
 
-// TODO: SetcompletionRoutine, CopyCurrentIrpStackToNext & IoCallDriver
 impl WduIrp {
     pub(crate) fn wrap(irp: *const IRP) -> Self {
-        let mj = MajorFunction::from(unsafe { (*Self::current_stack(irp)).MajorFunction as u32 });
+        // IRPs built by this driver have no current stack location before being sent or once
+        // completed back to us, the major function is in the first stack location.
+        let stack = if Self::has_current_stack(irp) {
+            Self::current_stack(irp)
+        } else {
+            Self::next_stack_as_mut(irp)
+        };
+        let mj = MajorFunction::from(unsafe { (*stack).MajorFunction as u32 });
         WduIrp {
             irp: irp as *mut _,
             mj,
             cancel: None,
+            state: WduIrpStateCell::new(),
+        }
+    }
+
+    // Completes the IRP if the handler didn't and returns the status for the I/O manager
+    pub(crate) fn finish(&mut self, disposition: WduIrpDisposition) -> NTSTATUS {
+        match disposition {
+            WduIrpDisposition::Completed(status) => {
+                debug_assert!(
+                    self.state.get() != WduIrpState::Forwarded,
+                    "IRP forwarded but disposition is Completed"
+                );
+                debug_assert!(
+                    self.state.get() != WduIrpState::Pending,
+                    "IRP marked pending but disposition is Completed"
+                );
+
+                match self.state.get() {
+                    WduIrpState::Owned => {
+                        let mut io_status = self.io_status();
+                        io_status.set_status(status);
+                        self.complete(io_status);
+                        status
+                    }
+                    // Whoever completes it later owns the IRP now and SL_PENDING_RETURNED
+                    // requires STATUS_PENDING
+                    WduIrpState::Pending => STATUS_PENDING,
+                    _ => status,
+                }
+            }
+            WduIrpDisposition::Pending => {
+                debug_assert!(
+                    self.state.get() != WduIrpState::Owned,
+                    "Disposition is Pending but IRP was not marked pending"
+                );
+
+                // Returning STATUS_PENDING requires SL_PENDING_RETURNED
+                if self.state.get() == WduIrpState::Owned {
+                    self.mark_pending();
+                }
+                STATUS_PENDING
+            }
+            WduIrpDisposition::Forwarded(status) => {
+                debug_assert!(
+                    self.state.get() == WduIrpState::Forwarded,
+                    "Disposition is Forwarded but IRP was not sent down"
+                );
+                status
+            }
         }
     }
 
@@ -155,7 +448,7 @@ impl WduIrp {
         self.mj.is_pnp()
     }
 
-    pub(crate) fn major(&self) -> MajorFunction {
+    pub fn major(&self) -> MajorFunction {
         self.mj
     }
 
@@ -165,31 +458,295 @@ impl WduIrp {
         }
     }
 
-    pub fn forward_sync(&self, device: &WduDevice) -> bool {
+    /*
+    NT_ASSERT(Irp->CurrentLocation <= Irp->StackCount);
+    Irp->CurrentLocation++;
+    Irp->Tail.Overlay.CurrentStackLocation++;
+    */
+    pub(crate) fn skip_current_stack(&mut self) {
+        unsafe {
+            assert!((*self.irp).CurrentLocation <= (*self.irp).StackCount);
+            (*self.irp).CurrentLocation += 1;
+
+            let stack = &mut (*self.irp)
+                .Tail
+                .Overlay
+                .Anonymous2
+                .Anonymous
+                .CurrentStackLocation;
+            *stack = stack.wrapping_add(1);
+        }
+    }
+
+    /*
+    irpSp = IoGetCurrentIrpStackLocation(Irp);
+    nextIrpSp = IoGetNextIrpStackLocation(Irp);
+    RtlCopyMemory(nextIrpSp, irpSp, FIELD_OFFSET(IO_STACK_LOCATION, CompletionRoutine));
+    nextIrpSp->Control = 0;
+    */
+    pub fn copy_current_stack_to_next(&mut self) {
+        unsafe {
+            let current = Self::current_stack(self.irp);
+            let next = Self::next_stack_as_mut(self.irp);
+
+            (*next).MajorFunction = (*current).MajorFunction;
+            (*next).MinorFunction = (*current).MinorFunction;
+            (*next).Flags = (*current).Flags;
+            (*next).Control = 0;
+            (*next).Parameters = (*current).Parameters;
+            (*next).DeviceObject = (*current).DeviceObject;
+            (*next).FileObject = (*current).FileObject;
+        }
+    }
+
+    pub(crate) fn call_driver(&mut self, device: &WduDevice) -> NTSTATUS {
+        self.free_contexts();
+        self.set_forwarded();
+        unsafe { IofCallDriver(device.device(), self.irp) }
+    }
+
+    // Power IRPs must go through PoCallDriver
+    fn send(&mut self, device: &WduDevice) -> NTSTATUS {
+        if self.mj.is_power() {
+            self.po_call_driver(device)
+        } else {
+            self.call_driver(device)
+        }
+    }
+
+    /// Sends the IRP to `device` without any further processing from this driver. Usually
+    /// the lower device of a filter.
+    pub fn pass_through(&mut self, device: &WduDevice) -> WduIrpDisposition {
+        self.free_file_contexts();
+        // Skipping the stack location leaves the routine behind, nothing would call it
+        self.free_completion();
+        self.start_next_power_irp_before_send();
+        self.skip_current_stack();
+        WduIrpDisposition::Forwarded(self.send(device))
+    }
+
+    /// Sends the IRP to `device` and waits until the lower drivers complete it. Once this
+    /// returns the driver owns the IRP again and must complete it. Only callable at
+    /// PASSIVE_LEVEL.
+    pub fn forward_and_wait(&mut self, device: &WduDevice) -> NTSTATUS {
+        // The contexts survive, lower drivers own DriverContext until they complete the IRP
+        let contexts = self.take_contexts();
+        // IoForwardIrpSynchronously sets its own completion routine
+        self.free_completion();
         let res = unsafe { IoForwardIrpSynchronously(device.device(), self.irp) };
+        unsafe { self.driver_context().write(contexts) };
+
+        if res != 1 {
+            return STATUS_UNSUCCESSFUL;
+        }
 
-        u8::from(res) == 1
+        self.io_status().status()
     }
 
-    // TODO: Consider if we should consume self
-    pub fn complete(&mut self, io_status: WduIoStatus) {
-        if self.irp.is_null() {
+    /// Sends the IRP to `device`. `completion` is called once the lower drivers complete the
+    /// IRP, regardless of the completion status.
+    pub fn forward_with_completion(
+        &mut self,
+        device: &WduDevice,
+        completion: WduCompletionRoutine,
+    ) -> WduIrpDisposition {
+        self.start_next_power_irp_before_send();
+        self.copy_current_stack_to_next();
+        self.set_completion(
+            WduCompletionFlags::InvokeAlways,
+            (),
+            move |device, irp, _| completion(device, irp),
+        );
+
+        self.free_file_contexts();
+        WduIrpDisposition::Forwarded(self.send(device))
+    }
+
+    /*
+    IoSetCompletionRoutine(Irp, CompletionRoutine, Context, Success, Error, Cancel)
+    */
+    /// Sets the completion routine in the next stack location, `context` is moved into the
+    /// routine when it runs. Must be called after the next stack location is set up (e.g.
+    /// [WduIrp::copy_current_stack_to_next]) and before sending the IRP. Setting a new routine
+    /// replaces the previous one.
+    ///
+    /// The routine may run at DISPATCH_LEVEL and is only called for the outcomes in `flags`.
+    /// The routine & `context` are dropped once the IRP comes back, whether the routine was
+    /// called or not, or right away if the IRP is completed or passed through instead of being
+    /// sent.
+    pub fn set_completion<C, F>(&mut self, flags: WduCompletionFlags, context: C, completion: F)
+    where
+        C: Send + 'static,
+        F: FnOnce(&WduDevice, &mut WduIrp, C) -> WduCompletionAction + Send + 'static,
+    {
+        let completion = Box::new(WduCompletion {
+            flags,
+            routine: Box::new(move |device, irp| completion(device, irp, context)),
+        });
+
+        self.free_completion();
+
+        unsafe {
+            let next = Self::next_stack_as_mut(self.irp);
+            let pfn = Self::completion_routine as *mut u8;
+
+            (*next).CompletionRoutine = Some(core::mem::transmute_copy(&pfn));
+            (*next).Context = Box::into_raw(completion) as *mut c_void;
+            // Always called so the context is freed, the trampoline filters on `flags`
+            (*next).Control = WduCompletionFlags::InvokeAlways.bits();
+        }
+    }
+
+    // The I/O manager doesn't clear the routine once it's called, the trampoline clears the
+    // context so it isn't freed twice.
+    unsafe fn take_wdu_completion(stack: *mut IO_STACK_LOCATION) -> Option<Box<WduCompletion>> {
+        let routine = (*stack).CompletionRoutine?;
+        if routine as usize != Self::completion_routine as *const () as usize
+            || (*stack).Context.is_null()
+        {
+            return None;
+        }
+
+        let context = core::mem::replace(&mut (*stack).Context, core::ptr::null_mut());
+        (*stack).CompletionRoutine = None;
+        (*stack).Control = 0;
+        Some(Box::from_raw(context as *mut WduCompletion))
+    }
+
+    // Drops a routine set with set_completion that won't be called since the IRP isn't sent
+    fn free_completion(&mut self) {
+        // The lowest driver has no next stack location
+        if self.irp.is_null() || unsafe { (*self.irp).CurrentLocation } <= 1 {
             return;
         }
 
+        unsafe { drop(Self::take_wdu_completion(Self::next_stack_as_mut(self.irp))) }
+    }
+
+    unsafe extern "system" fn completion_routine(
+        device: *const DEVICE_OBJECT,
+        irp: *const IRP,
+        context: *const c_void,
+    ) -> NTSTATUS {
+        // The routine's stack location is the next one once the I/O manager moved back up
+        (*Self::next_stack_as_mut(irp)).Context = core::ptr::null_mut();
+        let completion = Box::from_raw(context as *mut WduCompletion);
+        let device = WduDevice::wrap_device(device);
+        let mut wdu_irp = WduIrp::wrap(irp);
+        wdu_irp.reset_contexts();
+
+        // Same filter the I/O manager applies to the SL_INVOKE_ON_XXX flags
+        let success = nt_success((*irp).IoStatus.Anonymous.Status);
+        let flags = completion.flags;
+        let invoke = (success && flags.contains(WduCompletionFlags::InvokeOnSuccess))
+            || (!success && flags.contains(WduCompletionFlags::InvokeOnError))
+            || (wdu_irp.is_cancel() && flags.contains(WduCompletionFlags::InvokeOnCancel));
+
+        let action = if invoke {
+            (completion.routine)(&device, &mut wdu_irp)
+        } else {
+            WduCompletionAction::Continue
+        };
+
+        match action {
+            WduCompletionAction::Continue => {
+                // The IRP goes back to the upper driver
+                wdu_irp.free_contexts();
+
+                // Pending must be propagated up the stack if we let the completion continue
+                if wdu_irp.pending_returned() && Self::has_current_stack(irp) {
+                    wdu_irp.mark_pending();
+                }
+                STATUS_SUCCESS
+            }
+            WduCompletionAction::MoreProcessingRequired => STATUS_MORE_PROCESSING_REQUIRED,
+        }
+    }
+
+    /// Whether a lower driver returned STATUS_PENDING for this IRP.
+    pub fn pending_returned(&self) -> bool {
+        unsafe { (*self.irp).PendingReturned == u8::from(true) }
+    }
+
+    fn set_forwarded(&mut self) {
+        debug_assert!(
+            self.state.get() != WduIrpState::Completed,
+            "IRP forwarded after being completed"
+        );
+        self.state.set(WduIrpState::Forwarded);
+    }
+
+    pub(crate) fn start_next_power_irp(&mut self) {
+        unsafe { PoStartNextPowerIrp(self.irp) }
+    }
+
+    // PoStartNextPowerIrp must be called while the current stack location is still ours, before
+    // it's skipped or copied to the next one
+    fn start_next_power_irp_before_send(&mut self) {
+        if self.mj.is_power() {
+            self.start_next_power_irp();
+        }
+    }
+
+    pub(crate) fn po_call_driver(&mut self, device: &WduDevice) -> NTSTATUS {
+        self.free_contexts();
+        self.set_forwarded();
+        unsafe { PoCallDriver(device.device(), self.irp) }
+    }
+
+    pub fn io_status(&self) -> WduIoStatus {
+        unsafe {
+            WduIoStatus {
+                status: (*self.irp).IoStatus.Anonymous.Status,
+                info: (*self.irp).IoStatus.Information,
+            }
+        }
+    }
+
+    pub fn set_io_status(&mut self, io_status: WduIoStatus) {
         unsafe {
             (*self.irp).IoStatus.Anonymous.Status = io_status.status;
             (*self.irp).IoStatus.Information = io_status.info;
         }
+    }
+
+    // TODO: Consider if we should consume self
+    /// Completes the IRP. The returned disposition can be used as the return value of the
+    /// dispatch handler.
+    pub fn complete(&mut self, io_status: WduIoStatus) -> WduIrpDisposition {
+        let status = io_status.status;
+        if self.irp.is_null() {
+            return WduIrpDisposition::Completed(status);
+        }
+
+        let completed = matches!(
+            self.state.get(),
+            WduIrpState::Completed | WduIrpState::Forwarded
+        );
+        debug_assert!(!completed, "IRP completed twice");
+        if completed {
+            return WduIrpDisposition::Completed(status);
+        }
 
+        self.free_file_contexts();
+        self.free_completion();
+        // No close request follows a failed create
+        if self.mj == MajorFunction::Create && !nt_success(status) {
+            self.file_object().free_contexts();
+        }
+        self.free_contexts();
+
+        self.set_io_status(io_status);
+        self.state.set(WduIrpState::Completed);
         self.complete_request(0);
+
+        WduIrpDisposition::Completed(status)
     }
 
     /*
     NT_ASSERT(Irp->CurrentLocation <= Irp->StackCount + 1);
     return Irp->Tail.Overlay.CurrentStackLocation;
     */
-    // TODO: Wrap IO_STACK_LOCATION
     pub(crate) fn current_stack(irp: *const IRP) -> *const IO_STACK_LOCATION {
         unsafe {
             assert!((*irp).CurrentLocation <= (*irp).StackCount + 1);
@@ -202,6 +759,27 @@ impl WduIrp {
         }
     }
 
+    pub(crate) fn has_current_stack(irp: *const IRP) -> bool {
+        unsafe { (*irp).CurrentLocation <= (*irp).StackCount }
+    }
+
+    /*
+    NT_ASSERT(Irp->CurrentLocation > 0);
+    return Irp->Tail.Overlay.CurrentStackLocation - 1;
+    */
+    pub(crate) fn next_stack_as_mut(irp: *const IRP) -> *mut IO_STACK_LOCATION {
+        unsafe {
+            assert!((*irp).CurrentLocation > 0);
+            ((*irp)
+                .Tail
+                .Overlay
+                .Anonymous2
+                .Anonymous
+                .CurrentStackLocation as *mut IO_STACK_LOCATION)
+                .wrapping_sub(1)
+        }
+    }
+
     pub(crate) fn current_stack_as_mut(irp: *const IRP) -> *mut IO_STACK_LOCATION {
         unsafe {
             assert!((*irp).CurrentLocation <= (*irp).StackCount + 1);
@@ -226,8 +804,21 @@ impl WduIrp {
         WduMdl::wrap((*self.irp).MdlAddress)
     }
 
+    /// Stack location of this driver.
+    pub fn current_stack_location(&self) -> WduIoStackLocation {
+        WduIoStackLocation::wrap(Self::current_stack_as_mut(self.irp))
+    }
+
+    /// Stack location of the next lower driver, set it up before sending the IRP down.
+    pub fn next_stack_location(&mut self) -> WduIoStackLocation {
+        WduIoStackLocation::wrap(Self::next_stack_as_mut(self.irp))
+    }
+
     pub fn file_object(&self) -> WduFileObject {
-        unsafe { WduFileObject::wrap((*Self::current_stack(self.irp)).FileObject) }
+        unsafe {
+            let stack = Self::current_stack(self.irp);
+            WduFileObject::wrap_for_device((*stack).FileObject, (*stack).DeviceObject)
+        }
     }
 
     pub fn original_file_object(&self) -> WduFileObject {
@@ -242,6 +833,16 @@ impl WduIrp {
         unsafe {
             (*Self::current_stack_as_mut(self.irp)).Control |= SL_PENDING_RETURNED as u8;
         }
+
+        self.set_pending_state();
+    }
+
+    // For IRPs marked pending by the system, e.g. IoCsqInsertIrpEx. Doesn't touch the IRP, which
+    // might already be completed.
+    pub(crate) fn set_pending_state(&mut self) {
+        if self.state.get() == WduIrpState::Owned {
+            self.state.set(WduIrpState::Pending);
+        }
     }
 
     pub fn release_cancel_lock(&self) {
@@ -254,6 +855,45 @@ impl WduIrp {
         ProcessorMode::from(unsafe { (*self.irp).RequestorMode })
     }
 
+    fn driver_context(&self) -> *mut *mut WduContextList {
+        unsafe {
+            core::ptr::addr_of_mut!(
+                (*self.irp).Tail.Overlay.Anonymous1.Anonymous.DriverContext[CONTEXT_INDEX]
+            )
+            .cast()
+        }
+    }
+
+    fn context_list(&self) -> Option<*mut *mut WduContextList> {
+        if self.irp.is_null() {
+            return None;
+        }
+
+        Some(self.driver_context())
+    }
+
+    fn take_contexts(&mut self) -> *mut WduContextList {
+        unsafe { self.driver_context().replace(core::ptr::null_mut()) }
+    }
+
+    // DriverContext keeps whatever the previous owner left there, must be called when the IRP
+    // reaches the driver.
+    pub(crate) fn reset_contexts(&mut self) {
+        unsafe { self.driver_context().write(core::ptr::null_mut()) }
+    }
+
+    fn free_contexts(&mut self) {
+        unsafe { WduContextList::free(self.driver_context()) }
+    }
+
+    // The contexts of a file object live until its close request is completed or sent down.
+    // Must be called while the current stack location is still ours.
+    fn free_file_contexts(&self) {
+        if self.mj == MajorFunction::Close {
+            self.file_object().free_contexts();
+        }
+    }
+
     // TODO: Add proper error handling in below functions
     fn set_context(&mut self) {
         unsafe {
@@ -278,6 +918,8 @@ impl WduIrp {
     //  MS implementation "uses an interlocked exchange intrinsic to set the address of the
     //  Cancel routine as an atomic operation"
     //  Consider how WDF is doing this with MarkCancelable(Ex)/UnmarkCancelable
+    /// To park cancelable IRPs prefer [WduCancelSafeQueue](crate::io::csq::WduCancelSafeQueue),
+    /// which doesn't race with the cancellation.
     pub fn set_cancel_rtn(&mut self, cancel: Option<WduCancelRoutine>) -> Option<WduCancelRoutine> {
         let prev = self.cancel;
         self.cancel = cancel;
@@ -310,3 +952,5 @@ impl WduIrp {
             .map_or_else(|| (), |pfn| pfn(&device, &mut original_irp))
     }
 }
+
+typed_context!(WduIrp);
@@ -1,6 +1,14 @@
 //! Collection of utils to work with I/O operations.
 pub mod create;
+pub mod csq;
 pub mod device;
 pub mod device_control;
 pub mod file_obj;
+pub mod ioctl;
 pub mod irp;
+pub mod pnp;
+pub mod power;
+pub mod queue;
+pub mod read_write;
+pub mod request;
+pub mod stack;
@@ -0,0 +1,223 @@
+use crate::io::{
+    power::{WduDevicePowerState, WduSystemPowerState},
+    stack::WduIoStackLocation,
+};
+use bitflags::bitflags;
+use windows_sys::{
+    Wdk::System::SystemServices::{
+        BusRelations, EjectionRelations, PowerRelations, RemovalRelations, SingleBusRelations,
+        TargetDeviceRelation, TransportRelations, CM_RESOURCE_LIST, DEVICE_CAPABILITIES,
+        DEVICE_RELATION_TYPE, IRP_MN_CANCEL_REMOVE_DEVICE, IRP_MN_CANCEL_STOP_DEVICE,
+        IRP_MN_QUERY_CAPABILITIES, IRP_MN_QUERY_DEVICE_RELATIONS, IRP_MN_QUERY_REMOVE_DEVICE,
+        IRP_MN_QUERY_STOP_DEVICE, IRP_MN_REMOVE_DEVICE, IRP_MN_START_DEVICE, IRP_MN_STOP_DEVICE,
+        IRP_MN_SURPRISE_REMOVAL,
+    },
+    Win32::System::Power::DEVICE_POWER_STATE,
+};
+
+/// PnP state of a device. Tracked by the library for every device created with
+/// [WduDevice::build](crate::io::device::WduDevice::build) as PnP IRPs go through the driver.
+///
+/// See [State Transitions for PnP Devices](https://learn.microsoft.com/en-us/windows-hardware/drivers/kernel/state-transitions-for-pnp-devices)
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum WduPnpState {
+    #[default]
+    NotStarted,
+    Started,
+    StopPending,
+    Stopped,
+    RemovePending,
+    SurpriseRemovePending,
+    Deleted,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WduDeviceRelationType {
+    BusRelations,
+    EjectionRelations,
+    PowerRelations,
+    RemovalRelations,
+    TargetDeviceRelation,
+    SingleBusRelations,
+    TransportRelations,
+    Unknown(DEVICE_RELATION_TYPE),
+}
+
+#[allow(non_upper_case_globals)]
+impl From<DEVICE_RELATION_TYPE> for WduDeviceRelationType {
+    fn from(value: DEVICE_RELATION_TYPE) -> Self {
+        match value {
+            BusRelations => Self::BusRelations,
+            EjectionRelations => Self::EjectionRelations,
+            PowerRelations => Self::PowerRelations,
+            RemovalRelations => Self::RemovalRelations,
+            TargetDeviceRelation => Self::TargetDeviceRelation,
+            SingleBusRelations => Self::SingleBusRelations,
+            TransportRelations => Self::TransportRelations,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+bitflags! {
+    /// Bitfield members of DEVICE_CAPABILITIES
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WduCapabilityFlags: u32 {
+        const DeviceD1 = 1 << 0;
+        const DeviceD2 = 1 << 1;
+        const LockSupported = 1 << 2;
+        const EjectSupported = 1 << 3;
+        const Removable = 1 << 4;
+        const DockDevice = 1 << 5;
+        const UniqueID = 1 << 6;
+        const SilentInstall = 1 << 7;
+        const RawDeviceOK = 1 << 8;
+        const SurpriseRemovalOK = 1 << 9;
+        const WakeFromD0 = 1 << 10;
+        const WakeFromD1 = 1 << 11;
+        const WakeFromD2 = 1 << 12;
+        const WakeFromD3 = 1 << 13;
+        const HardwareDisabled = 1 << 14;
+        const NonDynamic = 1 << 15;
+        const WarmEjectSupported = 1 << 16;
+        const NoDisplayInUI = 1 << 17;
+        const WakeFromInterrupt = 1 << 19;
+        const SecureDevice = 1 << 20;
+        const ChildOfVgaEnabledBridge = 1 << 21;
+        const DecodeIoOnBoot = 1 << 22;
+    }
+}
+
+/// Wrapper over the DEVICE_CAPABILITIES received in a IRP_MN_QUERY_CAPABILITIES
+#[repr(transparent)]
+pub struct WduDeviceCapabilities(*mut DEVICE_CAPABILITIES);
+
+impl WduDeviceCapabilities {
+    fn get(&self) -> &DEVICE_CAPABILITIES {
+        unsafe { &(*self.0) }
+    }
+
+    fn get_mut(&mut self) -> &mut DEVICE_CAPABILITIES {
+        unsafe { &mut (*self.0) }
+    }
+
+    pub fn as_ptr(&self) -> *const DEVICE_CAPABILITIES {
+        self.0
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut DEVICE_CAPABILITIES {
+        self.0
+    }
+
+    pub fn flags(&self) -> WduCapabilityFlags {
+        WduCapabilityFlags::from_bits_retain(self.get()._bitfield)
+    }
+
+    pub fn set_flags(&mut self, flags: WduCapabilityFlags) {
+        self.get_mut()._bitfield |= flags.bits();
+    }
+
+    pub fn clear_flags(&mut self, flags: WduCapabilityFlags) {
+        self.get_mut()._bitfield &= !flags.bits();
+    }
+
+    pub fn address(&self) -> u32 {
+        self.get().Address
+    }
+
+    pub fn set_address(&mut self, address: u32) {
+        self.get_mut().Address = address;
+    }
+
+    pub fn ui_number(&self) -> u32 {
+        self.get().UINumber
+    }
+
+    pub fn set_ui_number(&mut self, ui_number: u32) {
+        self.get_mut().UINumber = ui_number;
+    }
+
+    /// Highest device power state the device can maintain for the given system power state.
+    /// Used to map a S-IRP to the D-IRP the power policy owner has to request.
+    pub fn device_state(&self, system: WduSystemPowerState) -> WduDevicePowerState {
+        let index: i32 = system.into();
+        self.get()
+            .DeviceState
+            .get(index as usize)
+            .map_or(WduDevicePowerState::Unspecified, |state| {
+                WduDevicePowerState::from(*state)
+            })
+    }
+
+    pub fn set_device_state(&mut self, system: WduSystemPowerState, device: WduDevicePowerState) {
+        let index: i32 = system.into();
+        if let Some(state) = self.get_mut().DeviceState.get_mut(index as usize) {
+            *state = device.into();
+        }
+    }
+
+    pub(crate) fn device_states(&self) -> [DEVICE_POWER_STATE; 7] {
+        self.get().DeviceState
+    }
+
+    pub fn system_wake(&self) -> WduSystemPowerState {
+        WduSystemPowerState::from(self.get().SystemWake)
+    }
+
+    pub fn device_wake(&self) -> WduDevicePowerState {
+        WduDevicePowerState::from(self.get().DeviceWake)
+    }
+}
+
+/// PnP minor functions handled by the library.
+pub enum WduPnpIrp {
+    StartDevice {
+        resources: *mut CM_RESOURCE_LIST,
+        resources_translated: *mut CM_RESOURCE_LIST,
+    },
+    QueryStopDevice,
+    StopDevice,
+    CancelStopDevice,
+    QueryRemoveDevice,
+    RemoveDevice,
+    CancelRemoveDevice,
+    SurpriseRemoval,
+    QueryCapabilities(WduDeviceCapabilities),
+    QueryDeviceRelations(WduDeviceRelationType),
+    Other(u8),
+}
+
+impl WduPnpIrp {
+    pub(crate) unsafe fn new(stack: &WduIoStackLocation) -> Self {
+        let parameters = (*stack.as_ptr()).Parameters;
+        let minor = stack.minor();
+
+        match minor as u32 {
+            IRP_MN_START_DEVICE => Self::StartDevice {
+                resources: parameters.StartDevice.AllocatedResources,
+                resources_translated: parameters.StartDevice.AllocatedResourcesTranslated,
+            },
+            IRP_MN_QUERY_STOP_DEVICE => Self::QueryStopDevice,
+            IRP_MN_STOP_DEVICE => Self::StopDevice,
+            IRP_MN_CANCEL_STOP_DEVICE => Self::CancelStopDevice,
+            IRP_MN_QUERY_REMOVE_DEVICE => Self::QueryRemoveDevice,
+            IRP_MN_REMOVE_DEVICE => Self::RemoveDevice,
+            IRP_MN_CANCEL_REMOVE_DEVICE => Self::CancelRemoveDevice,
+            IRP_MN_SURPRISE_REMOVAL => Self::SurpriseRemoval,
+            IRP_MN_QUERY_CAPABILITIES => Self::QueryCapabilities(WduDeviceCapabilities(
+                parameters.DeviceCapabilities.Capabilities,
+            )),
+            IRP_MN_QUERY_DEVICE_RELATIONS => Self::QueryDeviceRelations(
+                WduDeviceRelationType::from(parameters.QueryDeviceRelations.Type),
+            ),
+            _ => Self::Other(minor),
+        }
+    }
+
+    /// Minor functions where the lower driver must handle the IRP before we do. For these the
+    /// library forwards the IRP synchronously and only calls the client handler if the lower
+    /// driver succeeded.
+    pub(crate) fn lower_first(&self) -> bool {
+        matches!(self, Self::StartDevice { .. } | Self::QueryCapabilities(_))
+    }
+}
@@ -0,0 +1,211 @@
+use crate::io::stack::WduIoStackLocation;
+use windows_sys::{
+    Wdk::System::SystemServices::{
+        DevicePowerState, SystemPowerState, IRP_MN_POWER_SEQUENCE, IRP_MN_QUERY_POWER,
+        IRP_MN_SET_POWER, IRP_MN_WAIT_WAKE, POWER_STATE, POWER_STATE_TYPE,
+    },
+    Win32::System::Power::{
+        PowerDeviceD0, PowerDeviceD1, PowerDeviceD2, PowerDeviceD3, PowerSystemHibernate,
+        PowerSystemShutdown, PowerSystemSleeping1, PowerSystemSleeping2, PowerSystemSleeping3,
+        PowerSystemWorking, DEVICE_POWER_STATE, POWER_ACTION, SYSTEM_POWER_STATE,
+    },
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WduSystemPowerState {
+    Unspecified,
+    Working,
+    Sleeping1,
+    Sleeping2,
+    Sleeping3,
+    Hibernate,
+    Shutdown,
+}
+
+#[allow(non_upper_case_globals)]
+impl From<SYSTEM_POWER_STATE> for WduSystemPowerState {
+    fn from(value: SYSTEM_POWER_STATE) -> Self {
+        match value {
+            PowerSystemWorking => Self::Working,
+            PowerSystemSleeping1 => Self::Sleeping1,
+            PowerSystemSleeping2 => Self::Sleeping2,
+            PowerSystemSleeping3 => Self::Sleeping3,
+            PowerSystemHibernate => Self::Hibernate,
+            PowerSystemShutdown => Self::Shutdown,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+impl From<WduSystemPowerState> for SYSTEM_POWER_STATE {
+    fn from(value: WduSystemPowerState) -> Self {
+        match value {
+            WduSystemPowerState::Unspecified => 0,
+            WduSystemPowerState::Working => PowerSystemWorking,
+            WduSystemPowerState::Sleeping1 => PowerSystemSleeping1,
+            WduSystemPowerState::Sleeping2 => PowerSystemSleeping2,
+            WduSystemPowerState::Sleeping3 => PowerSystemSleeping3,
+            WduSystemPowerState::Hibernate => PowerSystemHibernate,
+            WduSystemPowerState::Shutdown => PowerSystemShutdown,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WduDevicePowerState {
+    Unspecified,
+    D0,
+    D1,
+    D2,
+    D3,
+}
+
+#[allow(non_upper_case_globals)]
+impl From<DEVICE_POWER_STATE> for WduDevicePowerState {
+    fn from(value: DEVICE_POWER_STATE) -> Self {
+        match value {
+            PowerDeviceD0 => Self::D0,
+            PowerDeviceD1 => Self::D1,
+            PowerDeviceD2 => Self::D2,
+            PowerDeviceD3 => Self::D3,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+impl From<WduDevicePowerState> for DEVICE_POWER_STATE {
+    fn from(value: WduDevicePowerState) -> Self {
+        match value {
+            WduDevicePowerState::Unspecified => 0,
+            WduDevicePowerState::D0 => PowerDeviceD0,
+            WduDevicePowerState::D1 => PowerDeviceD1,
+            WduDevicePowerState::D2 => PowerDeviceD2,
+            WduDevicePowerState::D3 => PowerDeviceD3,
+        }
+    }
+}
+
+/// Typed version of the POWER_STATE union, the variant is given by the POWER_STATE_TYPE.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WduPowerState {
+    System(WduSystemPowerState),
+    Device(WduDevicePowerState),
+}
+
+impl WduPowerState {
+    pub(crate) fn from_raw(state_type: POWER_STATE_TYPE, state: POWER_STATE) -> Self {
+        unsafe {
+            if state_type == DevicePowerState {
+                Self::Device(WduDevicePowerState::from(state.DeviceState))
+            } else {
+                Self::System(WduSystemPowerState::from(state.SystemState))
+            }
+        }
+    }
+
+    pub(crate) fn state_type(&self) -> POWER_STATE_TYPE {
+        match self {
+            WduPowerState::System(_) => SystemPowerState,
+            WduPowerState::Device(_) => DevicePowerState,
+        }
+    }
+
+    pub(crate) fn raw(&self) -> POWER_STATE {
+        match self {
+            WduPowerState::System(state) => POWER_STATE {
+                SystemState: (*state).into(),
+            },
+            WduPowerState::Device(state) => POWER_STATE {
+                DeviceState: (*state).into(),
+            },
+        }
+    }
+}
+
+/// Power minor functions handled by the library.
+pub enum WduPowerIrp {
+    SetPower {
+        state: WduPowerState,
+        shutdown_type: POWER_ACTION,
+    },
+    QueryPower {
+        state: WduPowerState,
+        shutdown_type: POWER_ACTION,
+    },
+    WaitWake(WduSystemPowerState),
+    PowerSequence,
+    Other(u8),
+}
+
+impl WduPowerIrp {
+    pub(crate) unsafe fn new(stack: &WduIoStackLocation) -> Self {
+        let minor = stack.minor();
+
+        match minor as u32 {
+            IRP_MN_SET_POWER | IRP_MN_QUERY_POWER => {
+                let (state_type, state, shutdown_type) = stack.power_state();
+                let state = WduPowerState::from_raw(state_type, state);
+
+                if minor as u32 == IRP_MN_SET_POWER {
+                    Self::SetPower {
+                        state,
+                        shutdown_type,
+                    }
+                } else {
+                    Self::QueryPower {
+                        state,
+                        shutdown_type,
+                    }
+                }
+            }
+            IRP_MN_WAIT_WAKE => {
+                let parameters = (*stack.as_ptr()).Parameters;
+                Self::WaitWake(WduSystemPowerState::from(parameters.WaitWake.PowerState))
+            }
+            IRP_MN_POWER_SEQUENCE => Self::PowerSequence,
+            _ => Self::Other(minor),
+        }
+    }
+}
+
+/// Power IRP that can be requested with
+/// [WduDevice::request_power_irp](crate::io::device::WduDevice::request_power_irp).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WduPowerRequest {
+    SetPower(WduDevicePowerState),
+    QueryPower(WduDevicePowerState),
+    WaitWake(WduSystemPowerState),
+}
+
+impl WduPowerRequest {
+    pub(crate) fn minor(&self) -> u8 {
+        match self {
+            WduPowerRequest::SetPower(_) => IRP_MN_SET_POWER as u8,
+            WduPowerRequest::QueryPower(_) => IRP_MN_QUERY_POWER as u8,
+            WduPowerRequest::WaitWake(_) => IRP_MN_WAIT_WAKE as u8,
+        }
+    }
+
+    pub(crate) fn state(&self) -> POWER_STATE {
+        match self {
+            WduPowerRequest::SetPower(state) | WduPowerRequest::QueryPower(state) => POWER_STATE {
+                DeviceState: (*state).into(),
+            },
+            WduPowerRequest::WaitWake(state) => POWER_STATE {
+                SystemState: (*state).into(),
+            },
+        }
+    }
+
+    pub(crate) fn from_raw(minor: u8, state: POWER_STATE) -> Self {
+        unsafe {
+            match minor as u32 {
+                IRP_MN_WAIT_WAKE => Self::WaitWake(WduSystemPowerState::from(state.SystemState)),
+                IRP_MN_QUERY_POWER => {
+                    Self::QueryPower(WduDevicePowerState::from(state.DeviceState))
+                }
+                _ => Self::SetPower(WduDevicePowerState::from(state.DeviceState)),
+            }
+        }
+    }
+}
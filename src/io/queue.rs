@@ -0,0 +1,562 @@
+//! WDF-style I/O queues. A device sets a queue per major function (or per IOCTL) and the
+//! library delivers the IRPs through it instead of the driver dispatch routines.
+use crate::{
+    common::{child::WduChild, driver::WduDriver},
+    io::{
+        csq::WduCancelSafeQueue,
+        device::WduDevice,
+        irp::{MajorFunction, WduIoStatus, WduIrp, WduIrpDisposition},
+        stack::WduStackParameters,
+    },
+    sync::{
+        event::{WduEvent, WduEventType},
+        spinlock::WduSpinLock,
+    },
+    ProcessorMode,
+};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::cell::UnsafeCell;
+use snafu::Snafu;
+use windows_sys::{
+    Wdk::Foundation::{DEVICE_OBJECT, KEVENT},
+    Win32::Foundation::{STATUS_CANCELLED, STATUS_INVALID_DEVICE_STATE},
+};
+
+#[derive(Debug, Snafu)]
+pub enum WduIoQueueError {
+    #[snafu(display("Sequential and parallel queues require a handler"))]
+    MissingHandler,
+    #[snafu(display("Unable to initialize the queue"))]
+    InitError,
+}
+
+pub type WduIoQueueResult<T> = Result<T, WduIoQueueError>;
+
+type QueueFn<C> =
+    Box<dyn Fn(&C, &WduIoQueue, &WduDevice, &mut WduIrp) -> WduIrpDisposition + Send + Sync>;
+// Handler with the driver context already bound
+type BoundQueueFn =
+    Box<dyn Fn(&WduIoQueue, &WduDevice, &mut WduIrp) -> WduIrpDisposition + Send + Sync>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WduIoQueueDispatch {
+    /// One request in flight at a time.
+    Sequential,
+    /// Requests are delivered as soon as they arrive.
+    Parallel,
+    /// Requests are never delivered, the driver pulls them with
+    /// [retrieve_next](WduIoQueue::retrieve_next).
+    Manual,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum WduIoQueueState {
+    Started,
+    // Accepts requests but doesn't deliver them
+    Stopped,
+    // Rejects new requests and delivers the queued ones
+    Draining,
+    // Rejects new requests, the queued ones were cancelled
+    Purged,
+}
+
+pub struct WduIoQueueConfig<C = ()> {
+    dispatch: WduIoQueueDispatch,
+    handler: Option<QueueFn<C>>,
+}
+
+impl<C> WduIoQueueConfig<C> {
+    pub fn new(dispatch: WduIoQueueDispatch) -> Self {
+        WduIoQueueConfig {
+            dispatch,
+            handler: None,
+        }
+    }
+}
+
+impl<C: 'static> WduIoQueueConfig<C> {
+    /// Called when the queue delivers a request. If the handler returns
+    /// [Pending](WduIrpDisposition::Pending) the request must be completed later with
+    /// [WduIoQueue::complete].
+    pub fn handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&WduIoQueue, &WduDevice, &mut WduIrp) -> WduIrpDisposition + Send + Sync + 'static,
+    {
+        self.handler_with(move |_, queue, device, irp| handler(queue, device, irp))
+    }
+
+    /// Same as [handler](Self::handler), also receives the driver context.
+    pub fn handler_with<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&C, &WduIoQueue, &WduDevice, &mut WduIrp) -> WduIrpDisposition
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+}
+
+// Driver context captured by the queue handler. The driver state is freed after the devices and
+// their queues are deleted.
+struct QueueContext<C>(*const C);
+
+unsafe impl<C: Sync> Send for QueueContext<C> {}
+unsafe impl<C: Sync> Sync for QueueContext<C> {}
+
+impl<C> QueueContext<C> {
+    fn get(&self) -> &C {
+        unsafe { &*self.0 }
+    }
+}
+
+// Context of every IRP parked in the cancel-safe queue, keeps the queue alive while the IRP is
+// parked
+struct WduQueuedIrp(Arc<WduIoQueueInner>);
+
+// Only accessed with the queue lock held
+struct WduIoQueueCounters {
+    state: WduIoQueueState,
+    // Requests delivered or retrieved and not completed yet
+    in_flight: usize,
+    // Requests parked in the csq
+    queued: usize,
+    // Set while a caller delivers the parked requests
+    delivering: bool,
+}
+
+struct WduIoQueueInner {
+    csq: WduCancelSafeQueue<WduQueuedIrp>,
+    lock: UnsafeCell<WduSpinLock>,
+    counters: UnsafeCell<WduIoQueueCounters>,
+    // Signaled when a draining or purged queue has no requests left
+    idle: UnsafeCell<KEVENT>,
+    dispatch: WduIoQueueDispatch,
+    handler: Option<BoundQueueFn>,
+    device: *const DEVICE_OBJECT,
+}
+
+// The counters are protected by the lock, the csq & the event synchronize themselves
+unsafe impl Send for WduIoQueueInner {}
+unsafe impl Sync for WduIoQueueInner {}
+
+impl WduIoQueueInner {
+    fn locked<R>(&self, f: impl FnOnce(&mut WduIoQueueCounters) -> R) -> R {
+        let lock = self.lock.get();
+
+        unsafe {
+            (*lock).acquire();
+            let result = f(&mut *self.counters.get());
+            (*lock).release();
+            result
+        }
+    }
+
+    // Must be called with the lock held
+    fn check_idle(&self, counters: &WduIoQueueCounters) {
+        if matches!(
+            counters.state,
+            WduIoQueueState::Draining | WduIoQueueState::Purged
+        ) && counters.in_flight == 0
+            && counters.queued == 0
+        {
+            WduEvent::wrap(self.idle.get()).set(0, false);
+        }
+    }
+}
+
+/// Handle to an I/O queue. Handles are reference counted, clones refer to the same queue, which
+/// is freed once every handle is dropped. [WduDevice::delete] purges the queues set in the
+/// device and drops its handles.
+#[derive(Clone)]
+pub struct WduIoQueue {
+    inner: Arc<WduIoQueueInner>,
+}
+
+impl WduIoQueue {
+    /// Creates a started queue for `device`, a device of `driver`.
+    pub fn create<C: Sync + 'static>(
+        driver: &WduDriver<C>,
+        device: &WduDevice,
+        config: WduIoQueueConfig<C>,
+    ) -> WduIoQueueResult<Self> {
+        if config.dispatch != WduIoQueueDispatch::Manual && config.handler.is_none() {
+            return Err(WduIoQueueError::MissingHandler);
+        }
+
+        let context = QueueContext(driver.context() as *const C);
+        let handler = config.handler.map(|handler| -> BoundQueueFn {
+            Box::new(move |queue, device, irp| handler(context.get(), queue, device, irp))
+        });
+
+        let mut inner = Arc::new(WduIoQueueInner {
+            csq: WduCancelSafeQueue::new(),
+            lock: UnsafeCell::new(WduSpinLock::new()),
+            counters: UnsafeCell::new(WduIoQueueCounters {
+                state: WduIoQueueState::Started,
+                in_flight: 0,
+                queued: 0,
+                delivering: false,
+            }),
+            idle: UnsafeCell::new(unsafe { core::mem::zeroed() }),
+            dispatch: config.dispatch,
+            handler,
+            device: device.device(),
+        });
+
+        // Initialized once in the Arc, the system keeps pointers to the csq, lock & event
+        let init = Arc::get_mut(&mut inner).unwrap();
+        init.lock.get_mut().init();
+        WduEvent::wrap(init.idle.get_mut()).init(WduEventType::NotificationEvent, false);
+        init.csq
+            .init(Some(Self::canceled))
+            .map_err(|_| WduIoQueueError::InitError)?;
+
+        Ok(WduIoQueue { inner })
+    }
+
+    // Entry point of the IRPs routed to this queue
+    pub(crate) fn dispatch(&self, irp: &mut WduIrp) -> WduIrpDisposition {
+        let inner = &self.inner;
+
+        let (state, deliver) = inner.locked(|counters| {
+            let deliver = match (counters.state, inner.dispatch) {
+                (WduIoQueueState::Started, WduIoQueueDispatch::Parallel) => true,
+                // Parked requests go first
+                (WduIoQueueState::Started, WduIoQueueDispatch::Sequential) => {
+                    counters.in_flight == 0 && counters.queued == 0
+                }
+                _ => false,
+            };
+
+            if deliver {
+                counters.in_flight += 1;
+            } else if matches!(
+                counters.state,
+                WduIoQueueState::Started | WduIoQueueState::Stopped
+            ) {
+                counters.queued += 1;
+            }
+
+            (counters.state, deliver)
+        });
+
+        if deliver {
+            return self.deliver(irp);
+        }
+
+        if matches!(state, WduIoQueueState::Draining | WduIoQueueState::Purged) {
+            return irp.complete(WduIoStatus::new_with_status(STATUS_INVALID_DEVICE_STATE));
+        }
+
+        let disposition = match inner.csq.insert(irp, WduQueuedIrp(inner.clone())) {
+            Ok(disposition) => disposition,
+            Err(_) => {
+                inner.locked(|counters| counters.queued -= 1);
+                return irp.complete(WduIoStatus::new_with_status(STATUS_INVALID_DEVICE_STATE));
+            }
+        };
+
+        // The queue might have been purged while the IRP was being parked, the purge doesn't
+        // wait for it anymore
+        let purged = inner.locked(|counters| counters.state == WduIoQueueState::Purged);
+        if purged {
+            self.cancel_queued();
+        }
+
+        // The queue might have become idle while the IRP was being parked
+        self.dispatch_next();
+        disposition
+    }
+
+    fn deliver(&self, irp: &mut WduIrp) -> WduIrpDisposition {
+        let device = WduDevice::wrap_device(self.inner.device);
+
+        // Sequential & parallel queues always have a handler
+        let disposition = (self.inner.handler.as_ref().unwrap())(self, &device, irp);
+
+        if disposition != WduIrpDisposition::Pending {
+            irp.finish(disposition);
+            self.request_done();
+        }
+
+        disposition
+    }
+
+    // Delivers parked requests while the dispatch type allows it. Only the outermost caller
+    // delivers, requests completed by the handlers it calls just update the counters and leave
+    // the next ones to it.
+    fn dispatch_next(&self) {
+        let inner = &self.inner;
+
+        if inner.locked(|counters| core::mem::replace(&mut counters.delivering, true)) {
+            return;
+        }
+
+        loop {
+            // Removed with the lock held so a sequential queue never delivers two requests
+            let next = inner.locked(|counters| {
+                let can_deliver = match (counters.state, inner.dispatch) {
+                    (WduIoQueueState::Started | WduIoQueueState::Draining, dispatch) => {
+                        match dispatch {
+                            WduIoQueueDispatch::Parallel => true,
+                            WduIoQueueDispatch::Sequential => counters.in_flight == 0,
+                            WduIoQueueDispatch::Manual => false,
+                        }
+                    }
+                    _ => false,
+                };
+
+                let next = can_deliver.then(|| inner.csq.remove_next()).flatten();
+                match next {
+                    Some(_) => {
+                        counters.queued -= 1;
+                        counters.in_flight += 1;
+                    }
+                    // Given up with the lock held, a request completed meanwhile is seen by the
+                    // next caller
+                    None => counters.delivering = false,
+                }
+                next
+            });
+
+            let Some((mut irp, _)) = next else {
+                return;
+            };
+
+            self.deliver(&mut irp);
+        }
+    }
+
+    fn request_done(&self) {
+        let inner = &self.inner;
+
+        inner.locked(|counters| {
+            debug_assert!(
+                counters.in_flight > 0,
+                "Request completed twice through the queue"
+            );
+            counters.in_flight -= 1;
+            inner.check_idle(counters);
+        });
+
+        self.dispatch_next();
+    }
+
+    fn canceled(irp: &mut WduIrp, queued: WduQueuedIrp) {
+        let inner = queued.0;
+
+        irp.complete(WduIoStatus::new_with_status(STATUS_CANCELLED));
+
+        inner.locked(|counters| {
+            counters.queued -= 1;
+            inner.check_idle(counters);
+        });
+    }
+
+    /// Completes a request delivered with a [Pending](WduIrpDisposition::Pending) disposition
+    /// or retrieved from a manual queue, and delivers the next one.
+    pub fn complete(&self, irp: &mut WduIrp, io_status: WduIoStatus) {
+        irp.complete(io_status);
+        self.request_done();
+    }
+
+    /// Removes the first request of the queue. Meant for manual queues, the request must be
+    /// completed with [complete](WduIoQueue::complete).
+    pub fn retrieve_next(&self) -> Option<WduIrp> {
+        self.retrieve_next_if(|_| true)
+    }
+
+    /// Removes the first request for which `predicate` returns true. The predicate is called at
+    /// DISPATCH_LEVEL.
+    pub fn retrieve_next_if<F>(&self, predicate: F) -> Option<WduIrp>
+    where
+        F: Fn(&WduIrp) -> bool,
+    {
+        let inner = &self.inner;
+
+        let retrieved = inner.locked(|counters| {
+            let retrieved = inner.csq.remove_next_if(|irp, _| predicate(irp));
+            if retrieved.is_some() {
+                counters.queued -= 1;
+                counters.in_flight += 1;
+            }
+            retrieved
+        });
+
+        retrieved.map(|(irp, _)| irp)
+    }
+
+    /// Starts delivering requests again, also after a drain or a purge.
+    pub fn start(&self) {
+        self.set_state(WduIoQueueState::Started);
+        self.dispatch_next();
+    }
+
+    /// Stops delivering requests. New requests are still accepted and wait in the queue.
+    pub fn stop(&self) {
+        self.set_state(WduIoQueueState::Stopped);
+    }
+
+    /// Rejects new requests and waits until the queued and in flight requests are completed.
+    /// Queued requests are still delivered. PASSIVE_LEVEL only.
+    pub fn drain(&self) {
+        self.set_state(WduIoQueueState::Draining);
+        self.dispatch_next();
+        self.wait_idle();
+    }
+
+    /// Rejects new requests, cancels the queued ones and waits until the in flight requests are
+    /// completed. PASSIVE_LEVEL only.
+    pub fn purge(&self) {
+        self.set_state(WduIoQueueState::Purged);
+        self.cancel_queued();
+        self.wait_idle();
+    }
+
+    fn cancel_queued(&self) {
+        let inner = &self.inner;
+
+        while let Some((mut irp, _)) = inner.csq.remove_next() {
+            irp.complete(WduIoStatus::new_with_status(STATUS_CANCELLED));
+
+            inner.locked(|counters| {
+                counters.queued -= 1;
+                inner.check_idle(counters);
+            });
+        }
+    }
+
+    fn set_state(&self, state: WduIoQueueState) {
+        let inner = &self.inner;
+
+        inner.locked(|counters| {
+            counters.state = state;
+            WduEvent::wrap(inner.idle.get()).clear();
+            inner.check_idle(counters);
+        });
+    }
+
+    fn wait_idle(&self) {
+        WduEvent::wrap(self.inner.idle.get()).wait(ProcessorMode::KernelMode, false, None);
+    }
+
+    /// Purges the queue and drops this handle. PASSIVE_LEVEL only.
+    pub fn delete(self) {
+        self.purge();
+    }
+
+    fn same_queue(&self, other: &WduIoQueue) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+/// Purges the queue when the parent goes away, the handle is dropped along with the child.
+impl WduChild for WduIoQueue {
+    fn teardown(&mut self) {
+        self.purge();
+    }
+}
+
+struct WduQueueTable {
+    majors: [Option<WduIoQueue>; MajorFunction::ALL.len()],
+    ioctls: Vec<(u32, WduIoQueue)>,
+}
+
+// Queues set in a device, the copy of WduDevice in the DeviceExtension keeps a pointer to it.
+// Queues can be set while the device is receiving requests, so the table is behind a lock.
+pub(crate) struct WduDeviceQueues {
+    lock: UnsafeCell<WduSpinLock>,
+    table: UnsafeCell<WduQueueTable>,
+}
+
+impl WduDeviceQueues {
+    // Initialized once boxed, same as the other spin locks
+    pub(crate) fn new() -> Box<Self> {
+        let mut queues = Box::new(WduDeviceQueues {
+            lock: UnsafeCell::new(WduSpinLock::new()),
+            table: UnsafeCell::new(WduQueueTable {
+                majors: core::array::from_fn(|_| None),
+                ioctls: Vec::new(),
+            }),
+        });
+
+        queues.lock.get_mut().init();
+        queues
+    }
+
+    fn locked<R>(&self, f: impl FnOnce(&mut WduQueueTable) -> R) -> R {
+        let lock = self.lock.get();
+
+        unsafe {
+            (*lock).acquire();
+            let result = f(&mut *self.table.get());
+            (*lock).release();
+            result
+        }
+    }
+
+    // The replaced queue is dropped once the lock is released
+    pub(crate) fn set(&self, major: MajorFunction, queue: WduIoQueue) {
+        let _replaced = self.locked(|table| table.majors[major.index()].replace(queue));
+    }
+
+    pub(crate) fn set_ioctl(&self, ioctl: u32, queue: WduIoQueue) {
+        let _replaced = self.locked(|table| {
+            let entry = table.ioctls.iter_mut().find(|(code, _)| *code == ioctl);
+            match entry {
+                Some(entry) => Some(core::mem::replace(&mut entry.1, queue)),
+                None => {
+                    table.ioctls.push((ioctl, queue));
+                    None
+                }
+            }
+        });
+    }
+
+    // IOCTL queues take precedence over the IRP_MJ_DEVICE_CONTROL one
+    pub(crate) fn find(&self, irp: &WduIrp) -> Option<WduIoQueue> {
+        let io_control_code = if irp.major() == MajorFunction::DeviceControl {
+            match irp.current_stack_location().parameters() {
+                WduStackParameters::DeviceIoControl {
+                    io_control_code, ..
+                } => Some(io_control_code),
+                _ => None,
+            }
+        } else {
+            None
+        };
+
+        self.locked(|table| {
+            let found = io_control_code.and_then(|ioctl| {
+                table
+                    .ioctls
+                    .iter()
+                    .find(|(code, _)| *code == ioctl)
+                    .map(|(_, queue)| queue.clone())
+            });
+
+            found.or_else(|| table.majors[irp.major().index()].clone())
+        })
+    }
+
+    // The same queue can be set for several major functions or IOCTLs
+    pub(crate) fn delete_all(self: Box<Self>) {
+        let table = self.table.into_inner();
+        let mut queues: Vec<WduIoQueue> = Vec::new();
+
+        for queue in table
+            .majors
+            .into_iter()
+            .flatten()
+            .chain(table.ioctls.into_iter().map(|q| q.1))
+        {
+            if !queues.iter().any(|q| q.same_queue(&queue)) {
+                queues.push(queue);
+            }
+        }
+
+        queues.into_iter().for_each(|queue| queue.delete());
+    }
+}
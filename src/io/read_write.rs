@@ -0,0 +1,93 @@
+use crate::{
+    io::{
+        device::{WduDevice, WduDeviceFlags},
+        irp::WduIrp,
+        stack::WduStackParameters,
+    },
+    memory::mdl::{PagePriority, WduMdl},
+};
+use core::ffi::c_void;
+
+/// Buffer of a read or write, depends on the I/O method of the device (DO_BUFFERED_IO,
+/// DO_DIRECT_IO or neither).
+pub enum WduReadWriteBuffer {
+    Unknown,
+    Buffered(*mut c_void),
+    Direct(WduMdl),
+    Neither(*mut c_void),
+}
+
+/// IRP_MJ_READ & IRP_MJ_WRITE request.
+pub struct WduReadWrite {
+    length: usize,
+    byte_offset: i64,
+    key: u32,
+    buffer: WduReadWriteBuffer,
+}
+
+impl WduReadWrite {
+    pub(crate) unsafe fn new(device: &WduDevice, irp: &WduIrp) -> Self {
+        let (length, key, byte_offset) = match irp.current_stack_location().parameters() {
+            WduStackParameters::Read {
+                length,
+                key,
+                byte_offset,
+            }
+            | WduStackParameters::Write {
+                length,
+                key,
+                byte_offset,
+            } => (length, key, byte_offset),
+            _ => unreachable!("WduReadWrite from a non read/write IRP"),
+        };
+
+        let flags = device.flags();
+
+        let buffer = if length == 0 {
+            // Zero-length requests come without buffer nor MDL
+            WduReadWriteBuffer::Unknown
+        } else if flags.contains(WduDeviceFlags::BufferedIo) {
+            WduReadWriteBuffer::Buffered(irp.system_buffer())
+        } else if flags.contains(WduDeviceFlags::DirectIo) {
+            WduReadWriteBuffer::Direct(irp.mdl_address())
+        } else {
+            WduReadWriteBuffer::Neither(irp.user_buffer())
+        };
+
+        Self {
+            length: length as usize,
+            byte_offset,
+            key,
+            buffer,
+        }
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    pub fn byte_offset(&self) -> i64 {
+        self.byte_offset
+    }
+
+    pub fn key(&self) -> u32 {
+        self.key
+    }
+
+    pub fn buffer_kind(&self) -> &WduReadWriteBuffer {
+        &self.buffer
+    }
+
+    /// System address of the buffer, mapping the MDL for direct I/O. With neither I/O this is
+    /// the user address, only valid in the context of the requestor.
+    pub fn buffer(&self) -> *mut c_void {
+        match &self.buffer {
+            WduReadWriteBuffer::Buffered(buffer) => *buffer,
+            WduReadWriteBuffer::Neither(buffer) => *buffer,
+            WduReadWriteBuffer::Direct(mdl) => {
+                mdl.get_system_addr(PagePriority::Normal | PagePriority::MdlMappingNoExecute)
+            }
+            WduReadWriteBuffer::Unknown => core::ptr::null_mut(),
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! IRPs built by this driver and sent to other drivers.
+use crate::{
+    io::{
+        device::WduDevice,
+        file_obj::WduFileObject,
+        irp::{MajorFunction, WduCompletionAction, WduCompletionFlags, WduIoStatus, WduIrp},
+        stack::WduIoStackLocation,
+    },
+    sync::event::{WduEvent, WduEventType},
+    ProcessorMode,
+};
+use alloc::boxed::Box;
+use core::{ffi::c_void, marker::PhantomData};
+use snafu::Snafu;
+use windows_sys::{
+    Wdk::{
+        Foundation::KEVENT,
+        System::SystemServices::{
+            IoAllocateIrp, IoBuildDeviceIoControlRequest, IoBuildSynchronousFsdRequest, IoFreeIrp,
+            IRP_MJ_READ, IRP_MJ_WRITE,
+        },
+    },
+    Win32::{
+        Foundation::{NTSTATUS, STATUS_CANCELLED, STATUS_PENDING},
+        System::IO::IO_STATUS_BLOCK,
+    },
+};
+
+#[derive(Debug, Snafu)]
+pub enum WduIoRequestError {
+    #[snafu(display("Unable to build the IRP"))]
+    BuildError,
+    #[snafu(display("Buffer length {length} doesn't fit in an IRP"))]
+    InvalidLength { length: usize },
+}
+
+pub type WduIoRequestResult<T> = Result<T, WduIoRequestError>;
+
+enum WduIoRequestKind<'a> {
+    DeviceControl {
+        code: u32,
+        input: Option<&'a [u8]>,
+        output: Option<&'a mut [u8]>,
+        internal: bool,
+    },
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+/// Builder of IRPs sent synchronously to other drivers. Uses IoBuildDeviceIoControlRequest and
+/// IoBuildSynchronousFsdRequest, so [build](WduIoRequestBuilder::build) and the send must happen
+/// at PASSIVE_LEVEL and in the same thread.
+pub struct WduIoRequestBuilder<'a> {
+    kind: WduIoRequestKind<'a>,
+    offset: Option<i64>,
+    file_object: Option<WduFileObject>,
+}
+
+impl<'a> WduIoRequestBuilder<'a> {
+    /// IRP_MJ_DEVICE_CONTROL request. The buffers are handled according to the transfer type
+    /// encoded in `code`.
+    pub fn device_control(code: u32) -> Self {
+        Self::with_kind(WduIoRequestKind::DeviceControl {
+            code,
+            input: None,
+            output: None,
+            internal: false,
+        })
+    }
+
+    /// IRP_MJ_READ request into `buffer`.
+    pub fn read(buffer: &'a mut [u8]) -> Self {
+        Self::with_kind(WduIoRequestKind::Read(buffer))
+    }
+
+    /// IRP_MJ_WRITE request from `buffer`.
+    pub fn write(buffer: &'a [u8]) -> Self {
+        Self::with_kind(WduIoRequestKind::Write(buffer))
+    }
+
+    fn with_kind(kind: WduIoRequestKind<'a>) -> Self {
+        WduIoRequestBuilder {
+            kind,
+            offset: None,
+            file_object: None,
+        }
+    }
+
+    /// Input buffer of a device control request. Ignored for reads and writes.
+    pub fn input(mut self, buffer: &'a [u8]) -> Self {
+        if let WduIoRequestKind::DeviceControl { input, .. } = &mut self.kind {
+            *input = Some(buffer);
+        }
+        self
+    }
+
+    /// Output buffer of a device control request. Ignored for reads and writes.
+    pub fn output(mut self, buffer: &'a mut [u8]) -> Self {
+        if let WduIoRequestKind::DeviceControl { output, .. } = &mut self.kind {
+            *output = Some(buffer);
+        }
+        self
+    }
+
+    /// Sends an IRP_MJ_INTERNAL_DEVICE_CONTROL instead. Ignored for reads and writes.
+    pub fn internal(mut self, is_internal: bool) -> Self {
+        if let WduIoRequestKind::DeviceControl { internal, .. } = &mut self.kind {
+            *internal = is_internal;
+        }
+        self
+    }
+
+    /// Starting offset of a read or write. Ignored for device control requests.
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// File object set in the first stack location. Usually the one obtained along with the
+    /// target device (IoGetDeviceObjectPointer).
+    pub fn file_object(mut self, file_object: WduFileObject) -> Self {
+        self.file_object = Some(file_object);
+        self
+    }
+
+    pub fn build(self, target: &WduDevice) -> WduIoRequestResult<WduIoRequest<'a>> {
+        // Event & IO_STATUS_BLOCK are written by the I/O manager when the IRP completes, they
+        // must outlive the IRP so keep them boxed in the request.
+        let mut sync = Box::new(WduSyncBlock {
+            event: unsafe { core::mem::zeroed() },
+            io_status: unsafe { core::mem::zeroed() },
+        });
+        WduEvent::wrap(&mut sync.event).init(WduEventType::NotificationEvent, false);
+
+        let irp = unsafe {
+            match self.kind {
+                WduIoRequestKind::DeviceControl {
+                    code,
+                    input,
+                    output,
+                    internal,
+                } => {
+                    let (input, input_len) = match input {
+                        Some(input) => (input.as_ptr() as *const c_void, buffer_len(input.len())?),
+                        None => (core::ptr::null(), 0),
+                    };
+                    let (output, output_len) = match output {
+                        Some(output) => (
+                            output.as_mut_ptr() as *mut c_void,
+                            buffer_len(output.len())?,
+                        ),
+                        None => (core::ptr::null_mut(), 0),
+                    };
+
+                    IoBuildDeviceIoControlRequest(
+                        code,
+                        target.device(),
+                        input,
+                        input_len,
+                        output,
+                        output_len,
+                        u8::from(internal),
+                        &sync.event,
+                        &mut sync.io_status,
+                    )
+                }
+                WduIoRequestKind::Read(buffer) => IoBuildSynchronousFsdRequest(
+                    IRP_MJ_READ,
+                    target.device(),
+                    buffer.as_mut_ptr() as *mut c_void,
+                    buffer_len(buffer.len())?,
+                    self.offset
+                        .as_ref()
+                        .map_or(core::ptr::null(), |offset| offset),
+                    &sync.event,
+                    &mut sync.io_status,
+                ),
+                // The I/O manager doesn't write into the buffer of a write request
+                WduIoRequestKind::Write(buffer) => IoBuildSynchronousFsdRequest(
+                    IRP_MJ_WRITE,
+                    target.device(),
+                    buffer.as_ptr() as *mut c_void,
+                    buffer_len(buffer.len())?,
+                    self.offset
+                        .as_ref()
+                        .map_or(core::ptr::null(), |offset| offset),
+                    &sync.event,
+                    &mut sync.io_status,
+                ),
+            }
+        };
+
+        if irp.is_null() {
+            return Err(WduIoRequestError::BuildError);
+        }
+
+        let mut irp = WduIrp::wrap(irp);
+
+        if let Some(file_object) = self.file_object {
+            irp.next_stack_location().set_file_object(&file_object);
+        }
+
+        Ok(WduIoRequest {
+            irp,
+            target: WduDevice::wrap_device(target.device()),
+            sync,
+            sent: false,
+            _buffers: PhantomData,
+        })
+    }
+}
+
+fn buffer_len(length: usize) -> WduIoRequestResult<u32> {
+    u32::try_from(length).map_err(|_| WduIoRequestError::InvalidLength { length })
+}
+
+struct WduSyncBlock {
+    event: KEVENT,
+    io_status: IO_STATUS_BLOCK,
+}
+
+/// IRP built with [WduIoRequestBuilder]. The I/O manager frees the IRP once completed, if the
+/// request is dropped without being sent the IRP is completed with STATUS_CANCELLED.
+pub struct WduIoRequest<'a> {
+    irp: WduIrp,
+    target: WduDevice,
+    sync: Box<WduSyncBlock>,
+    sent: bool,
+    // The IRP references the buffers given to the builder until it completes
+    _buffers: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> WduIoRequest<'a> {
+    pub fn irp(&mut self) -> &mut WduIrp {
+        &mut self.irp
+    }
+
+    /// Sends the IRP to the target device and waits for its completion. PASSIVE_LEVEL only.
+    pub fn send_and_wait(mut self) -> WduIoStatus {
+        self.sent = true;
+        let status = self.irp.call_driver(&self.target);
+
+        if status == STATUS_PENDING {
+            self.event().wait(ProcessorMode::KernelMode, false, None);
+            return WduIoStatus::from_raw(&self.sync.io_status);
+        }
+
+        let mut io_status = WduIoStatus::from_raw(&self.sync.io_status);
+        io_status.set_status(status);
+        io_status
+    }
+
+    fn event(&mut self) -> WduEvent {
+        WduEvent::wrap(&mut self.sync.event)
+    }
+}
+
+impl<'a> Drop for WduIoRequest<'a> {
+    fn drop(&mut self) {
+        // Threaded IRPs can't be freed with IoFreeIrp, they must go through IoCompleteRequest.
+        // Wait for the I/O manager to signal the event before freeing it.
+        if !self.sent {
+            self.irp
+                .complete(WduIoStatus::new_with_status(STATUS_CANCELLED));
+            self.event().wait(ProcessorMode::KernelMode, false, None);
+        }
+    }
+}
+
+/// IRP allocated with IoAllocateIrp. Unlike [WduIoRequest] it isn't tied to the current thread
+/// and can be sent asynchronously, the parameters of the first stack location are set by the
+/// caller with [next_stack](WduAsyncIoRequest::next_stack). Freed once completed or dropped.
+pub struct WduAsyncIoRequest {
+    irp: WduIrp,
+    target: WduDevice,
+}
+
+impl WduAsyncIoRequest {
+    /// Allocates an IRP with enough stack locations for `target`.
+    pub fn allocate(major: MajorFunction, target: &WduDevice) -> WduIoRequestResult<Self> {
+        let irp = unsafe { IoAllocateIrp((*target.device()).StackSize, u8::from(false)) };
+
+        if irp.is_null() {
+            return Err(WduIoRequestError::BuildError);
+        }
+
+        let mut irp = WduIrp::wrap(irp);
+        irp.next_stack_location().set_major(major);
+
+        Ok(WduAsyncIoRequest {
+            irp,
+            target: WduDevice::wrap_device(target.device()),
+        })
+    }
+
+    pub fn irp(&mut self) -> &mut WduIrp {
+        &mut self.irp
+    }
+
+    /// Stack location of the target device.
+    pub fn next_stack(&mut self) -> WduIoStackLocation {
+        self.irp.next_stack_location()
+    }
+
+    /// Sends the IRP to the target device and waits for its completion. PASSIVE_LEVEL only.
+    pub fn send_and_wait(mut self) -> WduIoStatus {
+        let mut event: Box<KEVENT> = Box::new(unsafe { core::mem::zeroed() });
+        let mut event = WduEvent::wrap(&mut *event);
+        event.init(WduEventType::NotificationEvent, false);
+
+        self.irp.set_completion(
+            WduCompletionFlags::InvokeAlways,
+            event.clone(),
+            |_, _, mut event| {
+                event.set(0, false);
+                WduCompletionAction::MoreProcessingRequired
+            },
+        );
+
+        if self.irp.call_driver(&self.target) == STATUS_PENDING {
+            event.wait(ProcessorMode::KernelMode, false, None);
+        }
+
+        self.irp.io_status()
+    }
+
+    /// Sends the IRP to the target device without waiting. `completion` is called once the
+    /// target completes it, possibly at DISPATCH_LEVEL, and then the IRP is freed. Returns the
+    /// status from the target.
+    pub fn send<F>(mut self, completion: F) -> NTSTATUS
+    where
+        F: FnOnce(&mut WduIrp, WduIoStatus) + Send + 'static,
+    {
+        self.irp
+            .set_completion(WduCompletionFlags::InvokeAlways, (), move |_, irp, _| {
+                let io_status = irp.io_status();
+                completion(irp, io_status);
+
+                unsafe { IoFreeIrp(irp.as_ptr()) }
+                WduCompletionAction::MoreProcessingRequired
+            });
+
+        let status = self.irp.call_driver(&self.target);
+        // The completion routine owns the IRP now
+        core::mem::forget(self);
+        status
+    }
+}
+
+impl Drop for WduAsyncIoRequest {
+    fn drop(&mut self) {
+        unsafe { IoFreeIrp(self.irp.as_ptr()) }
+    }
+}
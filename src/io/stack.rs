@@ -0,0 +1,376 @@
+use crate::{
+    inner_getters_ptr,
+    io::{
+        device::WduDevice, file_obj::WduFileObject, irp::MajorFunction, pnp::WduPnpIrp,
+        power::WduPowerIrp,
+    },
+};
+use core::ffi::c_void;
+use windows_sys::{
+    Wdk::{
+        Foundation::{FILE_OBJECT, IO_SECURITY_CONTEXT, IO_STACK_LOCATION},
+        System::SystemServices::{POWER_STATE, POWER_STATE_TYPE},
+    },
+    Win32::System::{Power::POWER_ACTION, WindowsProgramming::FILE_INFORMATION_CLASS},
+};
+
+/*
+ Hack until wdkmetadata has the right layout of IO_STACK_LOCATION.Parameters.
+ Members declared with POINTER_ALIGNMENT in wdm.h are u32 in windows_sys, so in x64 every member
+ after them is at the wrong offset. These structs use usize for those members (only the low 32
+ bits are meaningful) which gives the right layout in both x86 and x64, as long as the members
+ only declared in x64 are left out in x86.
+*/
+#[repr(C)]
+#[allow(non_snake_case)]
+struct CreateRaw {
+    SecurityContext: *mut IO_SECURITY_CONTEXT,
+    Options: u32,
+    Attributes: CreateAttributesRaw,
+    EaLength: usize,
+}
+
+// FileAttributes is POINTER_ALIGNMENT and ShareAccess shares the same slot
+#[repr(C)]
+#[allow(non_snake_case)]
+struct CreateAttributesRaw {
+    FileAttributes: u16,
+    ShareAccess: u16,
+    _align: [usize; 0],
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct ReadWriteRaw {
+    Length: usize,
+    Key: u32,
+    // Only declared in x64
+    #[cfg(target_pointer_width = "64")]
+    Flags: u32,
+    ByteOffset: i64,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct DeviceIoControlRaw {
+    OutputBufferLength: usize,
+    InputBufferLength: usize,
+    IoControlCode: usize,
+    Type3InputBuffer: *mut c_void,
+}
+
+// Every member is POINTER_ALIGNMENT except State, which is followed by a pointer aligned member
+#[repr(C)]
+#[allow(non_snake_case)]
+struct PowerRaw {
+    SystemContext: usize,
+    Type: usize,
+    State: usize,
+    ShutdownType: usize,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct QueryFileRaw {
+    Length: usize,
+    FileInformationClass: usize,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct SetFileRaw {
+    Length: usize,
+    FileInformationClass: usize,
+    FileObject: *mut FILE_OBJECT,
+    // ReplaceIfExists & AdvanceOnly, ClusterCount or DeleteHandle
+    Anonymous: usize,
+}
+
+/// Parameters of a stack location, the variant is given by the major function.
+pub enum WduStackParameters {
+    Create {
+        security_context: *mut IO_SECURITY_CONTEXT,
+        options: u32,
+        file_attributes: u16,
+        share_access: u16,
+        ea_length: u32,
+    },
+    Read {
+        length: u32,
+        key: u32,
+        byte_offset: i64,
+    },
+    Write {
+        length: u32,
+        key: u32,
+        byte_offset: i64,
+    },
+    /// Used by both IRP_MJ_DEVICE_CONTROL and IRP_MJ_INTERNAL_DEVICE_CONTROL.
+    DeviceIoControl {
+        output_buffer_length: u32,
+        input_buffer_length: u32,
+        io_control_code: u32,
+        type3_input_buffer: *mut c_void,
+    },
+    QueryInformation {
+        length: u32,
+        file_information_class: FILE_INFORMATION_CLASS,
+    },
+    SetInformation {
+        length: u32,
+        file_information_class: FILE_INFORMATION_CLASS,
+        file_object: *mut FILE_OBJECT,
+        replace_if_exists: bool,
+        advance_only: bool,
+    },
+    Pnp(WduPnpIrp),
+    Power(WduPowerIrp),
+    /// Major functions without typed parameters.
+    Other,
+}
+
+/// Wrapper of IO_STACK_LOCATION. Obtained from
+/// [WduIrp::current_stack_location](crate::io::irp::WduIrp::current_stack_location) or, when
+/// setting up an IRP for the next driver,
+/// [WduIrp::next_stack_location](crate::io::irp::WduIrp::next_stack_location).
+pub struct WduIoStackLocation {
+    stack: *mut IO_STACK_LOCATION,
+}
+
+inner_getters_ptr!(WduIoStackLocation, stack, IO_STACK_LOCATION);
+
+impl WduIoStackLocation {
+    pub(crate) fn wrap(stack: *mut IO_STACK_LOCATION) -> Self {
+        WduIoStackLocation { stack }
+    }
+
+    pub fn major(&self) -> MajorFunction {
+        MajorFunction::from(unsafe { (*self.stack).MajorFunction as u32 })
+    }
+
+    pub fn set_major(&mut self, major: MajorFunction) {
+        unsafe { (*self.stack).MajorFunction = major as u8 }
+    }
+
+    pub fn minor(&self) -> u8 {
+        unsafe { (*self.stack).MinorFunction }
+    }
+
+    pub fn set_minor(&mut self, minor: u8) {
+        unsafe { (*self.stack).MinorFunction = minor }
+    }
+
+    /// SL_* flags of the request.
+    pub fn flags(&self) -> u8 {
+        unsafe { (*self.stack).Flags }
+    }
+
+    pub fn set_flags(&mut self, flags: u8) {
+        unsafe { (*self.stack).Flags = flags }
+    }
+
+    pub fn control(&self) -> u8 {
+        unsafe { (*self.stack).Control }
+    }
+
+    pub fn file_object(&self) -> WduFileObject {
+        unsafe { WduFileObject::wrap((*self.stack).FileObject) }
+    }
+
+    pub fn set_file_object(&mut self, file_object: &WduFileObject) {
+        unsafe { (*self.stack).FileObject = file_object.as_ptr() as *mut _ }
+    }
+
+    /// Device the stack location belongs to. Not set in the next stack location until the IRP
+    /// is sent.
+    pub fn device(&self) -> WduDevice {
+        WduDevice::wrap_device(unsafe { (*self.stack).DeviceObject })
+    }
+
+    unsafe fn raw<T>(&self) -> T {
+        core::ptr::read(core::ptr::addr_of!((*self.stack).Parameters) as *const T)
+    }
+
+    unsafe fn set_raw<T>(&mut self, raw: T) {
+        core::ptr::write(
+            core::ptr::addr_of_mut!((*self.stack).Parameters) as *mut T,
+            raw,
+        )
+    }
+
+    // Type, State & ShutdownType of IRP_MN_SET_POWER and IRP_MN_QUERY_POWER
+    pub(crate) fn power_state(&self) -> (POWER_STATE_TYPE, POWER_STATE, POWER_ACTION) {
+        let raw: PowerRaw = unsafe { self.raw() };
+        (
+            raw.Type as POWER_STATE_TYPE,
+            POWER_STATE {
+                SystemState: raw.State as i32,
+            },
+            raw.ShutdownType as POWER_ACTION,
+        )
+    }
+
+    pub fn parameters(&self) -> WduStackParameters {
+        unsafe {
+            match self.major() {
+                MajorFunction::Create => {
+                    let raw: CreateRaw = self.raw();
+                    WduStackParameters::Create {
+                        security_context: raw.SecurityContext,
+                        options: raw.Options,
+                        file_attributes: raw.Attributes.FileAttributes,
+                        share_access: raw.Attributes.ShareAccess,
+                        ea_length: raw.EaLength as u32,
+                    }
+                }
+                MajorFunction::Read => {
+                    let raw: ReadWriteRaw = self.raw();
+                    WduStackParameters::Read {
+                        length: raw.Length as u32,
+                        key: raw.Key,
+                        byte_offset: raw.ByteOffset,
+                    }
+                }
+                MajorFunction::Write => {
+                    let raw: ReadWriteRaw = self.raw();
+                    WduStackParameters::Write {
+                        length: raw.Length as u32,
+                        key: raw.Key,
+                        byte_offset: raw.ByteOffset,
+                    }
+                }
+                MajorFunction::DeviceControl | MajorFunction::InternalDeviceControl => {
+                    let raw: DeviceIoControlRaw = self.raw();
+                    WduStackParameters::DeviceIoControl {
+                        output_buffer_length: raw.OutputBufferLength as u32,
+                        input_buffer_length: raw.InputBufferLength as u32,
+                        io_control_code: raw.IoControlCode as u32,
+                        type3_input_buffer: raw.Type3InputBuffer,
+                    }
+                }
+                MajorFunction::QueryInformation => {
+                    let raw: QueryFileRaw = self.raw();
+                    WduStackParameters::QueryInformation {
+                        length: raw.Length as u32,
+                        file_information_class: raw.FileInformationClass as i32,
+                    }
+                }
+                MajorFunction::SetInformation => {
+                    let raw: SetFileRaw = self.raw();
+                    WduStackParameters::SetInformation {
+                        length: raw.Length as u32,
+                        file_information_class: raw.FileInformationClass as i32,
+                        file_object: raw.FileObject,
+                        replace_if_exists: raw.Anonymous & 0xff != 0,
+                        advance_only: (raw.Anonymous >> 8) & 0xff != 0,
+                    }
+                }
+                MajorFunction::Pnp => WduStackParameters::Pnp(WduPnpIrp::new(self)),
+                MajorFunction::Power => WduStackParameters::Power(WduPowerIrp::new(self)),
+                _ => WduStackParameters::Other,
+            }
+        }
+    }
+
+    /// Sets the parameters and the major function that matches them. Meant for the next stack
+    /// location of IRPs sent to other drivers. PnP, power and `Other` parameters are ignored,
+    /// set the minor function instead.
+    pub fn set_parameters(&mut self, parameters: WduStackParameters) {
+        unsafe {
+            match parameters {
+                WduStackParameters::Create {
+                    security_context,
+                    options,
+                    file_attributes,
+                    share_access,
+                    ea_length,
+                } => {
+                    self.set_major(MajorFunction::Create);
+                    self.set_raw(CreateRaw {
+                        SecurityContext: security_context,
+                        Options: options,
+                        Attributes: CreateAttributesRaw {
+                            FileAttributes: file_attributes,
+                            ShareAccess: share_access,
+                            _align: [],
+                        },
+                        EaLength: ea_length as usize,
+                    });
+                }
+                WduStackParameters::Read {
+                    length,
+                    key,
+                    byte_offset,
+                } => {
+                    self.set_major(MajorFunction::Read);
+                    self.set_raw(ReadWriteRaw {
+                        Length: length as usize,
+                        Key: key,
+                        #[cfg(target_pointer_width = "64")]
+                        Flags: 0,
+                        ByteOffset: byte_offset,
+                    });
+                }
+                WduStackParameters::Write {
+                    length,
+                    key,
+                    byte_offset,
+                } => {
+                    self.set_major(MajorFunction::Write);
+                    self.set_raw(ReadWriteRaw {
+                        Length: length as usize,
+                        Key: key,
+                        #[cfg(target_pointer_width = "64")]
+                        Flags: 0,
+                        ByteOffset: byte_offset,
+                    });
+                }
+                WduStackParameters::DeviceIoControl {
+                    output_buffer_length,
+                    input_buffer_length,
+                    io_control_code,
+                    type3_input_buffer,
+                } => {
+                    // Keep IRP_MJ_INTERNAL_DEVICE_CONTROL if already set
+                    if self.major() != MajorFunction::InternalDeviceControl {
+                        self.set_major(MajorFunction::DeviceControl);
+                    }
+                    self.set_raw(DeviceIoControlRaw {
+                        OutputBufferLength: output_buffer_length as usize,
+                        InputBufferLength: input_buffer_length as usize,
+                        IoControlCode: io_control_code as usize,
+                        Type3InputBuffer: type3_input_buffer,
+                    });
+                }
+                WduStackParameters::QueryInformation {
+                    length,
+                    file_information_class,
+                } => {
+                    self.set_major(MajorFunction::QueryInformation);
+                    self.set_raw(QueryFileRaw {
+                        Length: length as usize,
+                        FileInformationClass: file_information_class as usize,
+                    });
+                }
+                WduStackParameters::SetInformation {
+                    length,
+                    file_information_class,
+                    file_object,
+                    replace_if_exists,
+                    advance_only,
+                } => {
+                    self.set_major(MajorFunction::SetInformation);
+                    self.set_raw(SetFileRaw {
+                        Length: length as usize,
+                        FileInformationClass: file_information_class as usize,
+                        FileObject: file_object,
+                        Anonymous: replace_if_exists as usize | (advance_only as usize) << 8,
+                    });
+                }
+                WduStackParameters::Pnp(_)
+                | WduStackParameters::Power(_)
+                | WduStackParameters::Other => (),
+            }
+        }
+    }
+}
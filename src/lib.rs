@@ -25,6 +25,7 @@ pub mod common;
 pub mod io;
 pub mod memory;
 pub mod registry;
+pub mod security;
 pub mod strings;
 pub mod sync;
 
@@ -123,6 +124,8 @@ pub fn get_system_routine_addr<T>(rtn_name: &WduUnicodeStr) -> Option<T> {
     }
 }
 
+/// Raises an exception if the range is invalid, which can't be caught from Rust. Prefer
+/// [WduUserBuffer](crate::memory::user::WduUserBuffer).
 #[inline(always)]
 pub fn probe_read(addr: *const c_void, len: usize, alignment: u32) {
     unsafe {
@@ -130,6 +133,7 @@ pub fn probe_read(addr: *const c_void, len: usize, alignment: u32) {
     }
 }
 
+/// Same as [probe_read], prefer [WduUserBuffer](crate::memory::user::WduUserBuffer).
 #[inline(always)]
 pub fn probe_write(addr: *mut c_void, len: usize, alignment: u32) {
     unsafe {
@@ -140,8 +144,15 @@ pub fn probe_write(addr: *mut c_void, len: usize, alignment: u32) {
 #[allow(dead_code)]
 pub mod nt {
     use crate::POBJECT_TYPE;
+    use core::ffi::c_void;
+    use windows_sys::{
+        core::GUID,
+        Wdk::Foundation::{DEVICE_OBJECT, DRIVER_OBJECT, MDL},
+        Win32::Foundation::{BOOLEAN, NTSTATUS, UNICODE_STRING},
+    };
 
-    #[link(name = "ntoskrnl")]
+    // Kernel libraries aren't available to the host unit tests
+    #[cfg_attr(not(test), link(name = "ntoskrnl"))]
     extern "system" {
         pub(crate) static CmKeyObjectType: *const POBJECT_TYPE;
         pub(crate) static IoFileObjectType: *const POBJECT_TYPE;
@@ -158,6 +169,47 @@ pub mod nt {
 
         // TODO: figure out how to do #if (NTDDI_VERSION >= NTDDI_THRESHOLD)
         pub(crate) static ExDesktopObjectType: *const POBJECT_TYPE;
+
+        pub(crate) static MmUserProbeAddress: usize;
+    }
+
+    // SEH wrappers built by build.rs from src/memory/seh.c, they return the exception code
+    extern "system" {
+        pub(crate) fn WduCopyFromUser(
+            destination: *mut c_void,
+            source: *const c_void,
+            length: usize,
+            alignment: u32,
+        ) -> NTSTATUS;
+
+        pub(crate) fn WduCopyToUser(
+            destination: *mut c_void,
+            source: *const c_void,
+            length: usize,
+            alignment: u32,
+        ) -> NTSTATUS;
+
+        pub(crate) fn WduProbeAndLockPages(
+            mdl: *mut MDL,
+            access_mode: i8,
+            operation: i32,
+        ) -> NTSTATUS;
+    }
+
+    // Static library, not exported by ntoskrnl
+    #[cfg_attr(not(test), link(name = "wdmsec"))]
+    extern "system" {
+        pub(crate) fn IoCreateDeviceSecure(
+            driver_object: *const DRIVER_OBJECT,
+            device_extension_size: u32,
+            device_name: *const UNICODE_STRING,
+            device_type: u32,
+            device_characteristics: u32,
+            exclusive: BOOLEAN,
+            default_sddl_string: *const UNICODE_STRING,
+            device_class_guid: *const GUID,
+            device_object: *mut *mut DEVICE_OBJECT,
+        ) -> NTSTATUS;
     }
 }
 
@@ -221,3 +273,8 @@ pub(crate) fn dereference(object: *const c_void) {
 pub fn current_irql() -> u8 {
     unsafe { KeGetCurrentIrql() }
 }
+
+/// Equivalent to the `NT_SUCCESS` macro
+pub fn nt_success(status: NTSTATUS) -> bool {
+    status >= 0
+}
@@ -1,14 +1,17 @@
 #![allow(non_snake_case)]
-use crate::{io::irp::WduIrp, ProcessorMode};
+use crate::{io::irp::WduIrp, nt, nt_success, ProcessorMode};
 use bitflags::bitflags;
 use core::ffi::c_void;
 use snafu::Snafu;
-use windows_sys::Wdk::{
-    Foundation::MDL,
-    System::SystemServices::{
-        IoAllocateMdl, IoFreeMdl, MmCached, MmMapLockedPagesSpecifyCache, MmProbeAndLockPages,
-        MmUnlockPages,
+use windows_sys::{
+    Wdk::{
+        Foundation::MDL,
+        System::SystemServices::{
+            IoAllocateMdl, IoFreeMdl, MmCached, MmMapLockedPagesSpecifyCache, MmProbeAndLockPages,
+            MmUnlockPages,
+        },
     },
+    Win32::Foundation::NTSTATUS,
 };
 
 // We could find this constants inside "windows::Win32::Graphics::DirectDraw", but for now let's define them
@@ -19,6 +22,8 @@ const MDL_SOURCE_IS_NONPAGED_POOL: u32 = 4;
 pub enum WduMdlError {
     #[snafu(display("Unable to allocate MDL"))]
     AllocateError,
+    #[snafu(display("Unable to probe and lock the pages. Status {status}"))]
+    ProbeError { status: NTSTATUS },
 }
 
 pub type WduMdlResult<T> = Result<T, WduMdlError>;
@@ -167,7 +172,8 @@ impl WduMdl {
 
     pub fn lock(&mut self) {}
 
-    // TODO: Study how to replicate try/catch block
+    /// MmProbeAndLockPages raises an exception on invalid addresses, which can't be caught from
+    /// Rust. For user mode addresses use [WduMdl::try_probe_and_lock].
     pub fn probe_and_lock(&mut self, access_mode: ProcessorMode, lock_op: LockOperation) {
         unsafe {
             MmProbeAndLockPages(self.mdl, access_mode.into(), lock_op.into());
@@ -176,6 +182,23 @@ impl WduMdl {
         self.lock = true;
     }
 
+    /// Same as [WduMdl::probe_and_lock] but the exception is returned as
+    /// [WduMdlError::ProbeError] and the pages are left unlocked.
+    pub fn try_probe_and_lock(
+        &mut self,
+        access_mode: ProcessorMode,
+        lock_op: LockOperation,
+    ) -> WduMdlResult<()> {
+        let status =
+            unsafe { nt::WduProbeAndLockPages(self.mdl, access_mode.into(), lock_op.into()) };
+        if !nt_success(status) {
+            return Err(WduMdlError::ProbeError { status });
+        }
+
+        self.lock = true;
+        Ok(())
+    }
+
     pub fn unlock(&mut self) {
         unsafe {
             MmUnlockPages(self.mdl);
@@ -1,5 +1,6 @@
 pub mod mdl;
 pub mod pool;
+pub mod user;
 
 use windows_sys::Wdk::Foundation::{NonPagedPool, NonPagedPoolNx, POOL_TYPE};
 
@@ -134,6 +134,8 @@ unsafe impl GlobalAlloc for SimpleAlloc {
 }
 
 /// Handler for OOM conditions
+// Host unit tests link std, which has its own handler
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn alloc_error(layout: Layout) -> ! {
     panic!("allocation failed: {:?}", layout);
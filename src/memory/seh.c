@@ -0,0 +1,59 @@
+/*
+ Rust can't catch the exceptions raised by ProbeForXxx, MmProbeAndLockPages or by touching an
+ invalid user address. These wrappers run them under __try/__except and return the exception code
+ instead. Built by build.rs, only the prototypes needed are declared so no WDK headers are required.
+*/
+#include <excpt.h>
+#include <stddef.h>
+
+typedef long NTSTATUS;
+typedef struct _MDL MDL;
+
+#define STATUS_SUCCESS ((NTSTATUS)0)
+
+__declspec(dllimport) void __stdcall ProbeForRead(const volatile void *Address, size_t Length,
+                                                  unsigned long Alignment);
+__declspec(dllimport) void __stdcall ProbeForWrite(volatile void *Address, size_t Length,
+                                                   unsigned long Alignment);
+__declspec(dllimport) void __stdcall MmProbeAndLockPages(MDL *MemoryDescriptorList,
+                                                         char AccessMode, int Operation);
+
+void *__cdecl memcpy(void *Destination, const void *Source, size_t Length);
+#pragma intrinsic(memcpy)
+
+NTSTATUS __stdcall WduCopyFromUser(void *Destination, const void *Source, size_t Length,
+                                   unsigned long Alignment)
+{
+    __try {
+        ProbeForRead(Source, Length, Alignment);
+        memcpy(Destination, Source, Length);
+    } __except (EXCEPTION_EXECUTE_HANDLER) {
+        return (NTSTATUS)GetExceptionCode();
+    }
+
+    return STATUS_SUCCESS;
+}
+
+NTSTATUS __stdcall WduCopyToUser(void *Destination, const void *Source, size_t Length,
+                                 unsigned long Alignment)
+{
+    __try {
+        ProbeForWrite(Destination, Length, Alignment);
+        memcpy(Destination, Source, Length);
+    } __except (EXCEPTION_EXECUTE_HANDLER) {
+        return (NTSTATUS)GetExceptionCode();
+    }
+
+    return STATUS_SUCCESS;
+}
+
+NTSTATUS __stdcall WduProbeAndLockPages(MDL *MemoryDescriptorList, char AccessMode, int Operation)
+{
+    __try {
+        MmProbeAndLockPages(MemoryDescriptorList, AccessMode, Operation);
+    } __except (EXCEPTION_EXECUTE_HANDLER) {
+        return (NTSTATUS)GetExceptionCode();
+    }
+
+    return STATUS_SUCCESS;
+}
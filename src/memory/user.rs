@@ -0,0 +1,203 @@
+//! Access to user-mode memory that fails with an error instead of raising an exception.
+//!
+//! Rust can't catch the exceptions raised by ProbeForRead/ProbeForWrite or by touching an
+//! invalid user address, so the probe and the copy run in a small C wrapper (src/memory/seh.c)
+//! that catches them with __try/__except and returns the exception code. Everything here must
+//! run at PASSIVE_LEVEL in the context of the process that owns the buffer.
+use crate::{io::device_control::WduIoctlData, nt, nt_success};
+use alloc::vec::Vec;
+use core::{
+    ffi::c_void,
+    mem::{align_of, size_of},
+};
+use snafu::Snafu;
+use windows_sys::Win32::Foundation::NTSTATUS;
+
+#[derive(Debug, Snafu)]
+pub enum WduUserMemoryError {
+    #[snafu(display("Range {address:#x} + {length:#x} is not in user space"))]
+    InvalidRange { address: usize, length: usize },
+    #[snafu(display("Address {address:#x} is not aligned to {alignment}"))]
+    Misaligned { address: usize, alignment: usize },
+    #[snafu(display("Access to user memory failed. Status {status}"))]
+    AccessViolation { status: NTSTATUS },
+    #[snafu(display("Unable to allocate the capture buffer"))]
+    AllocationError,
+}
+
+pub type WduUserMemoryResult<T> = Result<T, WduUserMemoryError>;
+
+/// Checks `address` is aligned and the whole range is below MmUserProbeAddress. Same checks as
+/// ProbeForRead, the pages themselves are only touched when copying.
+pub fn validate_user_range(
+    address: *const c_void,
+    length: usize,
+    alignment: usize,
+) -> WduUserMemoryResult<()> {
+    let address = address as usize;
+
+    if length == 0 {
+        return Ok(());
+    }
+
+    if alignment > 1 && !address.is_multiple_of(alignment) {
+        return Err(WduUserMemoryError::Misaligned { address, alignment });
+    }
+
+    match address.checked_add(length) {
+        Some(end) if end <= unsafe { nt::MmUserProbeAddress } => Ok(()),
+        _ => Err(WduUserMemoryError::InvalidRange { address, length }),
+    }
+}
+
+/// Probes `source` with ProbeForRead and copies `length` bytes from it. Invalid ranges and
+/// faults are returned as [WduUserMemoryError::AccessViolation].
+///
+/// # Safety
+/// `destination` must be a kernel buffer valid for `length` bytes.
+pub unsafe fn copy_from_user(
+    destination: *mut c_void,
+    source: *const c_void,
+    length: usize,
+    alignment: usize,
+) -> WduUserMemoryResult<()> {
+    let status = nt::WduCopyFromUser(destination, source, length, alignment as u32);
+    if !nt_success(status) {
+        return Err(WduUserMemoryError::AccessViolation { status });
+    }
+
+    Ok(())
+}
+
+/// Probes `destination` with ProbeForWrite and copies `length` bytes to it. Invalid ranges and
+/// faults are returned as [WduUserMemoryError::AccessViolation].
+///
+/// # Safety
+/// `source` must be a kernel buffer valid for `length` bytes.
+pub unsafe fn copy_to_user(
+    destination: *mut c_void,
+    source: *const c_void,
+    length: usize,
+    alignment: usize,
+) -> WduUserMemoryResult<()> {
+    let status = nt::WduCopyToUser(destination, source, length, alignment as u32);
+    if !nt_success(status) {
+        return Err(WduUserMemoryError::AccessViolation { status });
+    }
+
+    Ok(())
+}
+
+/// Buffer of `count` elements of `T` at a user-mode address, e.g. the buffers of a METHOD_NEITHER
+/// IOCTL. Data is captured into kernel memory before using it and copied back when done, so
+/// the user can't change it under us nor make us fault.
+pub struct WduUserBuffer<T> {
+    address: *mut T,
+    count: usize,
+}
+
+impl<T: WduIoctlData> WduUserBuffer<T> {
+    /// Validates the range, fails if it's not in user space or not aligned for `T`.
+    pub fn new(address: *mut c_void, count: usize) -> WduUserMemoryResult<Self> {
+        let length = count
+            .checked_mul(size_of::<T>())
+            .ok_or(WduUserMemoryError::InvalidRange {
+                address: address as usize,
+                length: usize::MAX,
+            })?;
+
+        validate_user_range(address, length, align_of::<T>())?;
+
+        Ok(WduUserBuffer {
+            address: address as *mut T,
+            count,
+        })
+    }
+
+    /// Buffer of `length` bytes holding as many `T` as fit.
+    pub fn from_bytes(address: *mut c_void, length: usize) -> WduUserMemoryResult<Self> {
+        Self::new(address, length / size_of::<T>().max(1))
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn size(&self) -> usize {
+        self.count * size_of::<T>()
+    }
+
+    /// Copy of the first element.
+    pub fn read(&self) -> WduUserMemoryResult<T> {
+        if self.is_empty() {
+            return Err(WduUserMemoryError::InvalidRange {
+                address: self.address as usize,
+                length: 0,
+            });
+        }
+
+        let mut value = core::mem::MaybeUninit::<T>::uninit();
+        unsafe {
+            copy_from_user(
+                value.as_mut_ptr() as *mut c_void,
+                self.address as *const c_void,
+                size_of::<T>(),
+                align_of::<T>(),
+            )?;
+
+            Ok(value.assume_init())
+        }
+    }
+
+    /// Copy of the whole buffer in kernel memory.
+    pub fn capture(&self) -> WduUserMemoryResult<Vec<T>> {
+        let mut captured: Vec<T> = Vec::new();
+        captured
+            .try_reserve_exact(self.count)
+            .map_err(|_| WduUserMemoryError::AllocationError)?;
+
+        unsafe {
+            copy_from_user(
+                captured.as_mut_ptr() as *mut c_void,
+                self.address as *const c_void,
+                self.size(),
+                align_of::<T>(),
+            )?;
+            captured.set_len(self.count);
+        }
+
+        Ok(captured)
+    }
+
+    /// Writes `value` as the first element.
+    pub fn write(&self, value: &T) -> WduUserMemoryResult<()> {
+        if self.is_empty() {
+            return Err(WduUserMemoryError::InvalidRange {
+                address: self.address as usize,
+                length: 0,
+            });
+        }
+
+        self.write_slice(core::slice::from_ref(value)).map(|_| ())
+    }
+
+    /// Writes as many elements of `values` as fit, returns the number of elements written.
+    pub fn write_slice(&self, values: &[T]) -> WduUserMemoryResult<usize> {
+        let count = values.len().min(self.count);
+
+        unsafe {
+            copy_to_user(
+                self.address as *mut c_void,
+                values.as_ptr() as *const c_void,
+                count * size_of::<T>(),
+                align_of::<T>(),
+            )?;
+        }
+
+        Ok(count)
+    }
+}
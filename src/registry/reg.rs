@@ -1 +1,288 @@
-// TODO: Add registry related operations
+use crate::strings::unicode::{str::WduUnicodeStr, string::WduUnicodeString};
+use alloc::{string::String, vec::Vec};
+use snafu::Snafu;
+use windows_sys::{
+    Wdk::{
+        Foundation::OBJECT_ATTRIBUTES,
+        System::SystemServices::{
+            KeyValuePartialInformation, ZwClose, ZwOpenKey, ZwQueryValueKey,
+            KEY_VALUE_PARTIAL_INFORMATION,
+        },
+    },
+    Win32::{
+        Foundation::{
+            HANDLE, NTSTATUS, STATUS_BUFFER_OVERFLOW, STATUS_BUFFER_TOO_SMALL,
+            STATUS_OBJECT_NAME_NOT_FOUND, STATUS_SUCCESS, UNICODE_STRING,
+        },
+        System::Kernel::{OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE},
+    },
+};
+
+// Win32::System::Registry isn't a feature of the crate
+const KEY_READ: u32 = 0x20019;
+const REG_NONE: u32 = 0;
+const REG_SZ: u32 = 1;
+const REG_EXPAND_SZ: u32 = 2;
+const REG_BINARY: u32 = 3;
+const REG_DWORD: u32 = 4;
+const REG_MULTI_SZ: u32 = 7;
+const REG_QWORD: u32 = 11;
+
+#[derive(Debug, Snafu)]
+pub enum WduRegistryError {
+    #[snafu(display("The key or value doesn't exist"))]
+    NotFound,
+    #[snafu(display("Unable to open the key. Status {status}"))]
+    OpenError { status: NTSTATUS },
+    #[snafu(display("Unable to query the value. Status {status}"))]
+    QueryError { status: NTSTATUS },
+    #[snafu(display("Values of type {value_type:?} can't be read as the requested type"))]
+    TypeMismatch { value_type: WduRegType },
+    #[snafu(display("Insufficient memory"))]
+    InsufficientResources,
+}
+
+pub type WduRegistryResult<T> = Result<T, WduRegistryError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WduRegType {
+    None,
+    Sz,
+    ExpandSz,
+    Binary,
+    Dword,
+    MultiSz,
+    Qword,
+    Other(u32),
+}
+
+impl From<u32> for WduRegType {
+    fn from(value: u32) -> Self {
+        match value {
+            REG_NONE => WduRegType::None,
+            REG_SZ => WduRegType::Sz,
+            REG_EXPAND_SZ => WduRegType::ExpandSz,
+            REG_BINARY => WduRegType::Binary,
+            REG_DWORD => WduRegType::Dword,
+            REG_MULTI_SZ => WduRegType::MultiSz,
+            REG_QWORD => WduRegType::Qword,
+            other => WduRegType::Other(other),
+        }
+    }
+}
+
+/// Types that can be read from a registry value with [WduRegKey::query]. Returns None if a value
+/// of type `value_type` can't be converted.
+pub trait WduRegValue: Sized {
+    fn from_reg(value_type: WduRegType, data: &[u8]) -> Option<Self>;
+}
+
+impl WduRegValue for u32 {
+    fn from_reg(value_type: WduRegType, data: &[u8]) -> Option<Self> {
+        match value_type {
+            WduRegType::Dword => Some(u32::from_le_bytes(data.get(..4)?.try_into().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+/// Also reads `REG_DWORD` values.
+impl WduRegValue for u64 {
+    fn from_reg(value_type: WduRegType, data: &[u8]) -> Option<Self> {
+        match value_type {
+            WduRegType::Qword => Some(u64::from_le_bytes(data.get(..8)?.try_into().ok()?)),
+            WduRegType::Dword => u32::from_reg(value_type, data).map(u64::from),
+            _ => None,
+        }
+    }
+}
+
+/// `REG_DWORD` values, anything other than 0 is true.
+impl WduRegValue for bool {
+    fn from_reg(value_type: WduRegType, data: &[u8]) -> Option<Self> {
+        u32::from_reg(value_type, data).map(|value| value != 0)
+    }
+}
+
+impl WduRegValue for Vec<u8> {
+    fn from_reg(value_type: WduRegType, data: &[u8]) -> Option<Self> {
+        match value_type {
+            WduRegType::Binary => {
+                let mut value = Vec::new();
+                value.try_reserve(data.len()).ok()?;
+                value.extend_from_slice(data);
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `REG_SZ` and `REG_EXPAND_SZ` values, environment variables aren't expanded.
+impl WduRegValue for WduUnicodeString {
+    fn from_reg(value_type: WduRegType, data: &[u8]) -> Option<Self> {
+        match value_type {
+            WduRegType::Sz | WduRegType::ExpandSz => {
+                let string = wide_chars(data)?;
+                WduUnicodeString::try_from(trim_nulls(&string)).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Same as [WduUnicodeString], invalid UTF-16 fails the conversion.
+impl WduRegValue for String {
+    fn from_reg(value_type: WduRegType, data: &[u8]) -> Option<Self> {
+        match value_type {
+            WduRegType::Sz | WduRegType::ExpandSz => {
+                let string = wide_chars(data)?;
+                String::from_utf16(trim_nulls(&string)).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `REG_MULTI_SZ` values, one string per entry.
+impl WduRegValue for Vec<WduUnicodeString> {
+    fn from_reg(value_type: WduRegType, data: &[u8]) -> Option<Self> {
+        if value_type != WduRegType::MultiSz {
+            return None;
+        }
+
+        let strings = wide_chars(data)?;
+        let strings = trim_nulls(&strings);
+
+        let mut value = Vec::new();
+        if strings.is_empty() {
+            return Some(value);
+        }
+
+        for string in strings.split(|c| *c == 0) {
+            value.try_reserve(1).ok()?;
+            value.push(WduUnicodeString::try_from(string).ok()?);
+        }
+
+        Some(value)
+    }
+}
+
+// Data of string values is a sequence of UTF-16 code units, it isn't guaranteed to be aligned
+fn wide_chars(data: &[u8]) -> Option<Vec<u16>> {
+    let mut chars = Vec::new();
+    chars.try_reserve(data.len() / 2).ok()?;
+    let units = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]));
+    chars.extend(units);
+
+    Some(chars)
+}
+
+// Strings are usually stored with their null terminator, but it's not guaranteed
+fn trim_nulls(chars: &[u16]) -> &[u16] {
+    let len = chars
+        .iter()
+        .rposition(|c| *c != 0)
+        .map_or(0, |last| last + 1);
+    &chars[..len]
+}
+
+/// Registry key opened for read access. The handle is a kernel handle, it's closed when the key
+/// is dropped.
+pub struct WduRegKey {
+    handle: HANDLE,
+}
+
+impl Drop for WduRegKey {
+    fn drop(&mut self) {
+        unsafe {
+            ZwClose(self.handle);
+        }
+    }
+}
+
+impl WduRegKey {
+    /// Opens the key at `path`, e.g. `\Registry\Machine\System\CurrentControlSet\Services\Foo`.
+    pub fn open(path: &WduUnicodeStr) -> WduRegistryResult<Self> {
+        Self::open_key(0, path)
+    }
+
+    /// Opens the subkey `name` of this key.
+    pub fn open_subkey(&self, name: &WduUnicodeStr) -> WduRegistryResult<Self> {
+        Self::open_key(self.handle, name)
+    }
+
+    fn open_key(root: HANDLE, name: &WduUnicodeStr) -> WduRegistryResult<Self> {
+        let name: UNICODE_STRING = name.into();
+        let obj_attr = OBJECT_ATTRIBUTES {
+            Length: core::mem::size_of::<OBJECT_ATTRIBUTES>() as u32,
+            RootDirectory: root,
+            ObjectName: &name,
+            Attributes: (OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE) as u32,
+            SecurityDescriptor: core::ptr::null(),
+            SecurityQualityOfService: core::ptr::null(),
+        };
+
+        let mut handle: HANDLE = 0;
+        let status = unsafe { ZwOpenKey(&mut handle, KEY_READ, &obj_attr) };
+
+        match status {
+            STATUS_SUCCESS => Ok(Self { handle }),
+            STATUS_OBJECT_NAME_NOT_FOUND => Err(WduRegistryError::NotFound),
+            status => Err(WduRegistryError::OpenError { status }),
+        }
+    }
+
+    /// Reads the value `name` as a `T`, see [WduRegValue] for the supported types.
+    pub fn query<T: WduRegValue>(&self, name: &WduUnicodeStr) -> WduRegistryResult<T> {
+        let name: UNICODE_STRING = name.into();
+        let header = core::mem::offset_of!(KEY_VALUE_PARTIAL_INFORMATION, Data);
+
+        // u32 keeps the information aligned. The value can change between calls, so keep going
+        // until the buffer is big enough.
+        let mut buffer: Vec<u32> = Vec::new();
+        loop {
+            let mut length = 0;
+            let status = unsafe {
+                ZwQueryValueKey(
+                    self.handle,
+                    &name,
+                    KeyValuePartialInformation,
+                    buffer.as_mut_ptr().cast(),
+                    (buffer.len() * core::mem::size_of::<u32>()) as u32,
+                    &mut length,
+                )
+            };
+
+            match status {
+                STATUS_SUCCESS => break,
+                STATUS_BUFFER_OVERFLOW | STATUS_BUFFER_TOO_SMALL => {
+                    let len = (length as usize).div_ceil(core::mem::size_of::<u32>());
+                    buffer.clear();
+                    buffer
+                        .try_reserve(len)
+                        .map_err(|_| WduRegistryError::InsufficientResources)?;
+                    buffer.resize(len, 0);
+                }
+                STATUS_OBJECT_NAME_NOT_FOUND => return Err(WduRegistryError::NotFound),
+                status => return Err(WduRegistryError::QueryError { status }),
+            }
+        }
+
+        let info = buffer.as_ptr() as *const KEY_VALUE_PARTIAL_INFORMATION;
+        let (value_type, data) = unsafe {
+            let available = (buffer.len() * core::mem::size_of::<u32>()).saturating_sub(header);
+            let len = ((*info).DataLength as usize).min(available);
+            let data = core::ptr::addr_of!((*info).Data).cast::<u8>();
+
+            (
+                WduRegType::from((*info).Type),
+                core::slice::from_raw_parts(data, len),
+            )
+        };
+
+        T::from_reg(value_type, data).ok_or(WduRegistryError::TypeMismatch { value_type })
+    }
+}
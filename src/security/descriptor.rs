@@ -0,0 +1,501 @@
+//! Self-relative security descriptors built from Rust.
+//!
+//! Nothing here calls into the kernel, a descriptor is just the byte layout of a self-relative
+//! SECURITY_DESCRIPTOR that can be handed to any API taking a PSECURITY_DESCRIPTOR.
+use crate::security::{
+    sddl::{self, WduSddlError, WduSddlResult},
+    sid::WduSid,
+};
+use alloc::{string::String, vec::Vec};
+use bitflags::bitflags;
+use core::ffi::c_void;
+use windows_sys::Win32::Security::{
+    ACL_REVISION, ACL_REVISION_DS, CONTAINER_INHERIT_ACE, FAILED_ACCESS_ACE_FLAG, INHERITED_ACE,
+    INHERIT_ONLY_ACE, NO_PROPAGATE_INHERIT_ACE, OBJECT_INHERIT_ACE, SE_DACL_AUTO_INHERITED,
+    SE_DACL_AUTO_INHERIT_REQ, SE_DACL_DEFAULTED, SE_DACL_PRESENT, SE_DACL_PROTECTED,
+    SE_GROUP_DEFAULTED, SE_OWNER_DEFAULTED, SE_SACL_AUTO_INHERITED, SE_SACL_AUTO_INHERIT_REQ,
+    SE_SACL_DEFAULTED, SE_SACL_PRESENT, SE_SACL_PROTECTED, SE_SELF_RELATIVE,
+    SUCCESSFUL_ACCESS_ACE_FLAG,
+};
+
+// Revision, Sbz1, Control & the four offsets
+const HEADER_SIZE: usize = 20;
+// AclRevision, Sbz1, AclSize, AceCount & Sbz2
+const ACL_HEADER_SIZE: usize = 8;
+// AceType, AceFlags, AceSize & Mask
+const ACE_HEADER_SIZE: usize = 8;
+const SECURITY_DESCRIPTOR_REVISION: u8 = 1;
+// ACE types, defined in Win32::System::SystemServices which isn't a feature of the crate
+const ACCESS_ALLOWED_ACE_TYPE: u8 = 0x0;
+const ACCESS_DENIED_ACE_TYPE: u8 = 0x1;
+const SYSTEM_AUDIT_ACE_TYPE: u8 = 0x2;
+const SYSTEM_ALARM_ACE_TYPE: u8 = 0x3;
+const SYSTEM_MANDATORY_LABEL_ACE_TYPE: u8 = 0x11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WduAceType {
+    AccessAllowed,
+    AccessDenied,
+    SystemAudit,
+    SystemAlarm,
+    MandatoryLabel,
+}
+
+impl WduAceType {
+    pub fn raw(self) -> u8 {
+        match self {
+            Self::AccessAllowed => ACCESS_ALLOWED_ACE_TYPE,
+            Self::AccessDenied => ACCESS_DENIED_ACE_TYPE,
+            Self::SystemAudit => SYSTEM_AUDIT_ACE_TYPE,
+            Self::SystemAlarm => SYSTEM_ALARM_ACE_TYPE,
+            Self::MandatoryLabel => SYSTEM_MANDATORY_LABEL_ACE_TYPE,
+        }
+    }
+
+    /// None for the ACE types that aren't supported (object and callback ACEs).
+    pub fn from_raw(ace_type: u8) -> Option<Self> {
+        match ace_type {
+            ACCESS_ALLOWED_ACE_TYPE => Some(Self::AccessAllowed),
+            ACCESS_DENIED_ACE_TYPE => Some(Self::AccessDenied),
+            SYSTEM_AUDIT_ACE_TYPE => Some(Self::SystemAudit),
+            SYSTEM_ALARM_ACE_TYPE => Some(Self::SystemAlarm),
+            SYSTEM_MANDATORY_LABEL_ACE_TYPE => Some(Self::MandatoryLabel),
+            _ => None,
+        }
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WduAceFlags: u8 {
+        const ObjectInherit = OBJECT_INHERIT_ACE as u8;
+        const ContainerInherit = CONTAINER_INHERIT_ACE as u8;
+        const NoPropagateInherit = NO_PROPAGATE_INHERIT_ACE as u8;
+        const InheritOnly = INHERIT_ONLY_ACE as u8;
+        const Inherited = INHERITED_ACE as u8;
+        const SuccessfulAccess = SUCCESSFUL_ACCESS_ACE_FLAG as u8;
+        const FailedAccess = FAILED_ACCESS_ACE_FLAG as u8;
+    }
+}
+
+bitflags! {
+    /// SECURITY_DESCRIPTOR_CONTROL. `SelfRelative` is always set when serializing.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct WduSecurityDescriptorControl: u16 {
+        const OwnerDefaulted = SE_OWNER_DEFAULTED;
+        const GroupDefaulted = SE_GROUP_DEFAULTED;
+        const DaclPresent = SE_DACL_PRESENT;
+        const DaclDefaulted = SE_DACL_DEFAULTED;
+        const SaclPresent = SE_SACL_PRESENT;
+        const SaclDefaulted = SE_SACL_DEFAULTED;
+        const DaclAutoInheritReq = SE_DACL_AUTO_INHERIT_REQ;
+        const SaclAutoInheritReq = SE_SACL_AUTO_INHERIT_REQ;
+        const DaclAutoInherited = SE_DACL_AUTO_INHERITED;
+        const SaclAutoInherited = SE_SACL_AUTO_INHERITED;
+        const DaclProtected = SE_DACL_PROTECTED;
+        const SaclProtected = SE_SACL_PROTECTED;
+        const SelfRelative = SE_SELF_RELATIVE;
+    }
+}
+
+/// Access control entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WduAce {
+    ace_type: WduAceType,
+    flags: WduAceFlags,
+    mask: u32,
+    sid: WduSid,
+}
+
+impl WduAce {
+    pub fn new(ace_type: WduAceType, flags: WduAceFlags, mask: u32, sid: WduSid) -> Self {
+        WduAce {
+            ace_type,
+            flags,
+            mask,
+            sid,
+        }
+    }
+
+    pub fn ace_type(&self) -> WduAceType {
+        self.ace_type
+    }
+
+    pub fn flags(&self) -> WduAceFlags {
+        self.flags
+    }
+
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    pub fn sid(&self) -> &WduSid {
+        &self.sid
+    }
+
+    fn size(&self) -> usize {
+        ACE_HEADER_SIZE + self.sid.size()
+    }
+}
+
+/// Ordered list of ACEs, the order is kept as is when serializing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WduAcl {
+    aces: Vec<WduAce>,
+}
+
+impl WduAcl {
+    pub fn ace(mut self, ace: WduAce) -> Self {
+        self.aces.push(ace);
+        self
+    }
+
+    pub fn aces(&self) -> &[WduAce] {
+        &self.aces
+    }
+
+    fn size(&self) -> usize {
+        ACL_HEADER_SIZE + self.aces.iter().map(WduAce::size).sum::<usize>()
+    }
+}
+
+/// Security descriptor, serialized as a self-relative SECURITY_DESCRIPTOR with
+/// [to_bytes](WduSecurityDescriptor::to_bytes).
+///
+/// A DACL marked as present without ACL is a NULL DACL (`D:NO_ACCESS_CONTROL`), which grants
+/// everyone full access. An empty [WduAcl] denies everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WduSecurityDescriptor {
+    control: WduSecurityDescriptorControl,
+    owner: Option<WduSid>,
+    group: Option<WduSid>,
+    dacl: Option<WduAcl>,
+    sacl: Option<WduAcl>,
+}
+
+impl WduSecurityDescriptor {
+    pub fn from_sddl(sddl: &str) -> WduSddlResult<Self> {
+        sddl::parse(sddl)
+    }
+
+    pub fn to_sddl(&self) -> String {
+        sddl::format(self)
+    }
+
+    pub fn owner(mut self, sid: WduSid) -> Self {
+        self.owner = Some(sid);
+        self
+    }
+
+    pub fn group(mut self, sid: WduSid) -> Self {
+        self.group = Some(sid);
+        self
+    }
+
+    /// Sets the DACL, `None` sets a NULL DACL.
+    pub fn dacl(mut self, dacl: Option<WduAcl>) -> Self {
+        self.control |= WduSecurityDescriptorControl::DaclPresent;
+        self.dacl = dacl;
+        self
+    }
+
+    pub fn sacl(mut self, sacl: Option<WduAcl>) -> Self {
+        self.control |= WduSecurityDescriptorControl::SaclPresent;
+        self.sacl = sacl;
+        self
+    }
+
+    /// Control flags on top of the ones implied by the owner, group, DACL & SACL.
+    pub fn control_flags(mut self, control: WduSecurityDescriptorControl) -> Self {
+        self.control |= control;
+        self
+    }
+
+    pub fn control(&self) -> WduSecurityDescriptorControl {
+        self.control
+    }
+
+    pub fn owner_sid(&self) -> Option<&WduSid> {
+        self.owner.as_ref()
+    }
+
+    pub fn group_sid(&self) -> Option<&WduSid> {
+        self.group.as_ref()
+    }
+
+    pub fn discretionary_acl(&self) -> Option<&WduAcl> {
+        self.dacl.as_ref()
+    }
+
+    pub fn system_acl(&self) -> Option<&WduAcl> {
+        self.sacl.as_ref()
+    }
+
+    pub fn is_dacl_present(&self) -> bool {
+        self.control
+            .contains(WduSecurityDescriptorControl::DaclPresent)
+    }
+
+    pub fn is_sacl_present(&self) -> bool {
+        self.control
+            .contains(WduSecurityDescriptorControl::SaclPresent)
+    }
+
+    /// Self-relative SECURITY_DESCRIPTOR. Laid out as the kernel does: header, SACL, DACL,
+    /// owner & group.
+    pub fn to_bytes(&self) -> WduSecurityDescriptorBuffer {
+        let mut buffer = Vec::with_capacity(self.size());
+        buffer.resize(HEADER_SIZE, 0);
+
+        let sacl = self.sacl.as_ref().map(|acl| write_acl(&mut buffer, acl));
+        let dacl = self.dacl.as_ref().map(|acl| write_acl(&mut buffer, acl));
+        let owner = self
+            .owner
+            .map(|sid| write_bytes(&mut buffer, sid.as_bytes()));
+        let group = self
+            .group
+            .map(|sid| write_bytes(&mut buffer, sid.as_bytes()));
+
+        let control = self.control | WduSecurityDescriptorControl::SelfRelative;
+        buffer[0] = SECURITY_DESCRIPTOR_REVISION;
+        buffer[2..4].copy_from_slice(&control.bits().to_le_bytes());
+        buffer[4..8].copy_from_slice(&owner.unwrap_or(0).to_le_bytes());
+        buffer[8..12].copy_from_slice(&group.unwrap_or(0).to_le_bytes());
+        buffer[12..16].copy_from_slice(&sacl.unwrap_or(0).to_le_bytes());
+        buffer[16..20].copy_from_slice(&dacl.unwrap_or(0).to_le_bytes());
+
+        WduSecurityDescriptorBuffer { buffer }
+    }
+
+    /// Parses a self-relative SECURITY_DESCRIPTOR.
+    pub fn from_bytes(bytes: &[u8]) -> WduSddlResult<Self> {
+        if bytes.len() < HEADER_SIZE || bytes[0] != SECURITY_DESCRIPTOR_REVISION {
+            return Err(WduSddlError::InvalidDescriptor { offset: 0 });
+        }
+
+        let control = WduSecurityDescriptorControl::from_bits_retain(read_u16(bytes, 2));
+        if !control.contains(WduSecurityDescriptorControl::SelfRelative) {
+            return Err(WduSddlError::InvalidDescriptor { offset: 2 });
+        }
+
+        let offset_of = |at: usize| read_u32(bytes, at) as usize;
+        let mut descriptor = WduSecurityDescriptor {
+            control: control - WduSecurityDescriptorControl::SelfRelative,
+            ..Default::default()
+        };
+
+        if offset_of(4) != 0 {
+            descriptor.owner = Some(read_sid(bytes, offset_of(4))?);
+        }
+
+        if offset_of(8) != 0 {
+            descriptor.group = Some(read_sid(bytes, offset_of(8))?);
+        }
+
+        if control.contains(WduSecurityDescriptorControl::SaclPresent) && offset_of(12) != 0 {
+            descriptor.sacl = Some(read_acl(bytes, offset_of(12))?);
+        }
+
+        if control.contains(WduSecurityDescriptorControl::DaclPresent) && offset_of(16) != 0 {
+            descriptor.dacl = Some(read_acl(bytes, offset_of(16))?);
+        }
+
+        Ok(descriptor)
+    }
+
+    fn size(&self) -> usize {
+        HEADER_SIZE
+            + self.sacl.as_ref().map_or(0, WduAcl::size)
+            + self.dacl.as_ref().map_or(0, WduAcl::size)
+            + self.owner.as_ref().map_or(0, WduSid::size)
+            + self.group.as_ref().map_or(0, WduSid::size)
+    }
+}
+
+/// Serialized descriptor. Must outlive any structure its pointer is stored in, e.g.
+/// [WduObjectAttributes](crate::common::obj_attr::WduObjectAttributes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WduSecurityDescriptorBuffer {
+    buffer: Vec<u8>,
+}
+
+impl WduSecurityDescriptorBuffer {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// PSECURITY_DESCRIPTOR
+    pub fn as_ptr(&self) -> *const c_void {
+        self.buffer.as_ptr() as *const _
+    }
+}
+
+// Appends `bytes`, returns the offset they were written at
+fn write_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) -> u32 {
+    let offset = buffer.len();
+    buffer.extend_from_slice(bytes);
+    offset as u32
+}
+
+fn write_acl(buffer: &mut Vec<u8>, acl: &WduAcl) -> u32 {
+    let offset = buffer.len();
+
+    buffer.push(ACL_REVISION as u8);
+    buffer.push(0);
+    buffer.extend_from_slice(&(acl.size() as u16).to_le_bytes());
+    buffer.extend_from_slice(&(acl.aces.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(&0u16.to_le_bytes());
+
+    for ace in &acl.aces {
+        buffer.push(ace.ace_type.raw());
+        buffer.push(ace.flags.bits());
+        buffer.extend_from_slice(&(ace.size() as u16).to_le_bytes());
+        buffer.extend_from_slice(&ace.mask.to_le_bytes());
+        buffer.extend_from_slice(ace.sid.as_bytes());
+    }
+
+    offset as u32
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn read_sid(bytes: &[u8], offset: usize) -> WduSddlResult<WduSid> {
+    bytes
+        .get(offset..)
+        .and_then(|sid| WduSid::from_bytes(sid).ok())
+        .ok_or(WduSddlError::InvalidDescriptor { offset })
+}
+
+fn read_acl(bytes: &[u8], offset: usize) -> WduSddlResult<WduAcl> {
+    let invalid = WduSddlError::InvalidDescriptor { offset };
+
+    if bytes.len() < offset + ACL_HEADER_SIZE
+        || !(ACL_REVISION..=ACL_REVISION_DS).contains(&(bytes[offset] as u32))
+    {
+        return Err(invalid);
+    }
+
+    let size = read_u16(bytes, offset + 2) as usize;
+    let count = read_u16(bytes, offset + 4) as usize;
+    let acl = bytes.get(offset..offset + size).ok_or(invalid)?;
+
+    let mut aces = Vec::with_capacity(count);
+    let mut position = ACL_HEADER_SIZE;
+    for _ in 0..count {
+        let invalid = WduSddlError::InvalidDescriptor {
+            offset: offset + position,
+        };
+
+        if acl.len() < position + ACE_HEADER_SIZE {
+            return Err(invalid);
+        }
+
+        let ace_type = WduAceType::from_raw(acl[position]).ok_or(WduSddlError::Unsupported {
+            offset: offset + position,
+        })?;
+        let ace_size = read_u16(acl, position + 2) as usize;
+        let ace = acl.get(position..position + ace_size).ok_or(invalid)?;
+        let sid = read_sid(ace, ACE_HEADER_SIZE).map_err(|_| invalid)?;
+
+        aces.push(WduAce::new(
+            ace_type,
+            WduAceFlags::from_bits_retain(ace[1]),
+            read_u32(ace, 4),
+            sid,
+        ));
+        position += ace_size;
+    }
+
+    Ok(WduAcl { aces })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let descriptor =
+            WduSecurityDescriptor::default().dacl(Some(WduAcl::default().ace(WduAce::new(
+                WduAceType::AccessAllowed,
+                WduAceFlags::empty(),
+                0x10000000,
+                WduSid::WORLD,
+            ))));
+        assert_eq!(
+            descriptor,
+            WduSecurityDescriptor::from_sddl("D:(A;;GA;;;WD)").unwrap()
+        );
+
+        #[rustfmt::skip]
+        let expected = [
+            // Revision, Sbz1, Control (SE_SELF_RELATIVE | SE_DACL_PRESENT), no owner, group or
+            // SACL and the DACL right after the header
+            1, 0, 0x04, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 20, 0, 0, 0,
+            // ACL_REVISION, AclSize 28 & a single ACE
+            2, 0, 28, 0, 1, 0, 0, 0,
+            // ACCESS_ALLOWED_ACE, GENERIC_ALL & S-1-1-0
+            0, 0, 20, 0, 0, 0, 0, 0x10, 1, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0,
+        ];
+        let buffer = descriptor.to_bytes();
+        assert_eq!(buffer.as_bytes(), expected);
+        assert_eq!(buffer.len(), expected.len());
+    }
+
+    #[test]
+    fn deserialize() {
+        let descriptor = WduSecurityDescriptor::from_sddl(
+            "O:BAG:SYD:PAI(A;OICI;FA;;;SY)(D;;WDWO;;;S-1-5-21-1-2-3-1001)S:(AU;SA;GA;;;WD)",
+        )
+        .unwrap();
+        let buffer = descriptor.to_bytes();
+        assert_eq!(
+            WduSecurityDescriptor::from_bytes(buffer.as_bytes()).unwrap(),
+            descriptor
+        );
+
+        // Truncated descriptors fail instead of reading out of bounds
+        for length in 0..buffer.len() {
+            assert!(WduSecurityDescriptor::from_bytes(&buffer.as_bytes()[..length]).is_err());
+        }
+
+        let mut absolute = buffer.as_bytes().to_vec();
+        absolute[3] &= !0x80;
+        assert_eq!(
+            WduSecurityDescriptor::from_bytes(&absolute),
+            Err(WduSddlError::InvalidDescriptor { offset: 2 })
+        );
+    }
+
+    #[test]
+    fn null_dacl() {
+        let descriptor = WduSecurityDescriptor::default().dacl(None);
+        assert!(descriptor.is_dacl_present());
+
+        let buffer = descriptor.to_bytes();
+        assert_eq!(buffer.len(), HEADER_SIZE);
+        assert_eq!(
+            WduSecurityDescriptor::from_bytes(buffer.as_bytes()).unwrap(),
+            descriptor
+        );
+    }
+}
@@ -0,0 +1,4 @@
+//! Security descriptors and related types
+pub mod descriptor;
+pub mod sddl;
+pub mod sid;
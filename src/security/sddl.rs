@@ -0,0 +1,803 @@
+//! SDDL strings, both the subset accepted by IoCreateDeviceSecure ([WduSddl]) and a full parser
+//! and serializer for [WduSecurityDescriptor].
+use crate::security::{
+    descriptor::{
+        WduAce, WduAceFlags, WduAceType, WduAcl, WduSecurityDescriptor,
+        WduSecurityDescriptorControl,
+    },
+    sid::WduSid,
+};
+use alloc::string::{String, ToString};
+use core::fmt::{self, Write};
+use snafu::Snafu;
+
+#[derive(Debug, Snafu, Clone, Copy, PartialEq, Eq)]
+pub enum WduSddlError {
+    #[snafu(display("SDDL string is empty"))]
+    Empty,
+    #[snafu(display("Invalid syntax at offset {offset}"))]
+    Syntax { offset: usize },
+    #[snafu(display("Unknown access right at offset {offset}"))]
+    UnknownRight { offset: usize },
+    #[snafu(display("Unknown SID at offset {offset}"))]
+    UnknownSid { offset: usize },
+    #[snafu(display("Unsupported construct at offset {offset}"))]
+    Unsupported { offset: usize },
+    #[snafu(display("Invalid security descriptor at offset {offset}"))]
+    InvalidDescriptor { offset: usize },
+}
+
+pub type WduSddlResult<T> = Result<T, WduSddlError>;
+
+// Aliases accepted by IoCreateDeviceSecure
+const SID_ALIASES: [&[u8; 2]; 14] = [
+    b"SY", b"BA", b"BU", b"BG", b"WD", b"RC", b"IU", b"NU", b"AU", b"LS", b"NS", b"AN", b"AC",
+    b"CO",
+];
+
+const RIGHTS: [&[u8; 2]; 24] = [
+    b"GA", b"GR", b"GW", b"GX", b"RC", b"SD", b"WD", b"WO", b"RP", b"WP", b"CC", b"DC", b"LC",
+    b"SW", b"LO", b"DT", b"CR", b"FA", b"FR", b"FW", b"FX", b"KA", b"KR", b"KW",
+];
+
+const ACE_FLAGS: [&[u8; 2]; 5] = [b"OI", b"CI", b"NP", b"IO", b"ID"];
+
+/// SDDL string restricted to what IoCreateDeviceSecure accepts: a DACL (`D:`, optionally
+/// protected with `P`) made of allow or deny ACEs for SID aliases or `S-1-...` SIDs.
+///
+/// The string is checked when the value is created, a constant with a typo doesn't compile:
+///
+/// ```ignore
+/// const SDDL_SYS_ALL_ADM_ALL: WduSddl = WduSddl::new("D:P(A;;GA;;;SY)(A;;GA;;;BA)");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WduSddl(&'static str);
+
+impl WduSddl {
+    /// Kernel and system only (SDDL_DEVOBJ_KERNEL_ONLY)
+    pub const KERNEL_ONLY: WduSddl = WduSddl::new("D:P");
+    /// SDDL_DEVOBJ_SYS_ALL
+    pub const SYS_ALL: WduSddl = WduSddl::new("D:P(A;;GA;;;SY)");
+    /// SDDL_DEVOBJ_SYS_ALL_ADM_ALL
+    pub const SYS_ALL_ADM_ALL: WduSddl = WduSddl::new("D:P(A;;GA;;;SY)(A;;GA;;;BA)");
+    /// SDDL_DEVOBJ_SYS_ALL_ADM_RX
+    pub const SYS_ALL_ADM_RX: WduSddl = WduSddl::new("D:P(A;;GA;;;SY)(A;;GRGX;;;BA)");
+    /// SDDL_DEVOBJ_SYS_ALL_ADM_RWX_WORLD_R
+    pub const SYS_ALL_ADM_RWX_WORLD_R: WduSddl =
+        WduSddl::new("D:P(A;;GA;;;SY)(A;;GRGWGX;;;BA)(A;;GR;;;WD)");
+    /// SDDL_DEVOBJ_SYS_ALL_ADM_RWX_WORLD_RW_RES_R
+    pub const SYS_ALL_ADM_RWX_WORLD_RW_RES_R: WduSddl =
+        WduSddl::new("D:P(A;;GA;;;SY)(A;;GRGWGX;;;BA)(A;;GRGW;;;WD)(A;;GR;;;RC)");
+    /// SDDL_DEVOBJ_SYS_ALL_ADM_RWX_WORLD_RWX_RES_RWX
+    pub const SYS_ALL_ADM_RWX_WORLD_RWX_RES_RWX: WduSddl =
+        WduSddl::new("D:P(A;;GA;;;SY)(A;;GRGWGX;;;BA)(A;;GRGWGX;;;WD)(A;;GRGWGX;;;RC)");
+
+    /// Panics if the string is not valid, meant for constants.
+    pub const fn new(sddl: &'static str) -> Self {
+        match Self::try_new(sddl) {
+            Ok(sddl) => sddl,
+            Err(_) => panic!("Invalid device SDDL string"),
+        }
+    }
+
+    pub const fn try_new(sddl: &'static str) -> WduSddlResult<Self> {
+        match validate(sddl.as_bytes()) {
+            Ok(()) => Ok(WduSddl(sddl)),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+    pub fn to_descriptor(&self) -> WduSddlResult<WduSecurityDescriptor> {
+        parse(self.0)
+    }
+}
+
+impl fmt::Display for WduSddl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+const fn validate(sddl: &[u8]) -> WduSddlResult<()> {
+    if sddl.is_empty() {
+        return Err(WduSddlError::Empty);
+    }
+
+    if sddl.len() < 2 || sddl[1] != b':' {
+        return Err(WduSddlError::Syntax { offset: 0 });
+    }
+
+    if sddl[0] != b'D' {
+        return Err(WduSddlError::Unsupported { offset: 0 });
+    }
+
+    // DACL flags
+    let mut pos = 2;
+    while pos < sddl.len() && sddl[pos] != b'(' {
+        if sddl[pos] == b'P' {
+            pos += 1;
+        } else if pos + 1 < sddl.len() && sddl[pos] == b'A' && matches!(sddl[pos + 1], b'I' | b'R')
+        {
+            pos += 2;
+        } else if pos + 1 < sddl.len() && sddl[pos + 1] == b':' {
+            return Err(WduSddlError::Unsupported { offset: pos });
+        } else {
+            return Err(WduSddlError::Syntax { offset: pos });
+        }
+    }
+
+    while pos < sddl.len() {
+        pos = match validate_ace(sddl, pos) {
+            Ok(next) => next,
+            Err(err) => return Err(err),
+        };
+    }
+
+    Ok(())
+}
+
+// Validates the ACE starting at `pos` (the open parenthesis), returns the offset after it
+const fn validate_ace(sddl: &[u8], mut pos: usize) -> WduSddlResult<usize> {
+    // Another section (O:, G:, S:) after the DACL
+    if pos + 1 < sddl.len() && sddl[pos + 1] == b':' {
+        return Err(WduSddlError::Unsupported { offset: pos });
+    }
+
+    if sddl[pos] != b'(' {
+        return Err(WduSddlError::Syntax { offset: pos });
+    }
+    pos += 1;
+
+    // Type
+    if pos + 1 >= sddl.len() || !matches!(sddl[pos], b'A' | b'D') || sddl[pos + 1] != b';' {
+        return Err(WduSddlError::Syntax { offset: pos });
+    }
+    pos += 2;
+
+    // Flags
+    while pos < sddl.len() && sddl[pos] != b';' {
+        if !is_code(sddl, pos, &ACE_FLAGS) {
+            return Err(WduSddlError::Syntax { offset: pos });
+        }
+        pos += 2;
+    }
+    pos += 1;
+
+    // Rights, either codes or a number
+    let start = pos;
+    if pos + 1 < sddl.len() && sddl[pos] == b'0' && matches!(sddl[pos + 1], b'x' | b'X') {
+        pos += 2;
+        while pos < sddl.len() && sddl[pos].is_ascii_hexdigit() {
+            pos += 1;
+        }
+        if pos == start + 2 {
+            return Err(WduSddlError::UnknownRight { offset: start });
+        }
+    } else {
+        while pos < sddl.len() && sddl[pos] != b';' {
+            if !is_code(sddl, pos, &RIGHTS) {
+                return Err(WduSddlError::UnknownRight { offset: pos });
+            }
+            pos += 2;
+        }
+        if pos == start {
+            return Err(WduSddlError::UnknownRight { offset: start });
+        }
+    }
+
+    // Object & inherit object GUIDs don't apply to devices
+    if pos + 2 >= sddl.len() || sddl[pos] != b';' || sddl[pos + 1] != b';' || sddl[pos + 2] != b';'
+    {
+        return Err(WduSddlError::Syntax { offset: pos });
+    }
+    pos += 3;
+
+    // Trustee
+    let start = pos;
+    if pos + 1 < sddl.len() && sddl[pos] == b'S' && sddl[pos + 1] == b'-' {
+        pos += 2;
+        while pos < sddl.len() && (sddl[pos].is_ascii_digit() || sddl[pos] == b'-') {
+            pos += 1;
+        }
+        if pos == start + 2 || sddl[pos - 1] == b'-' {
+            return Err(WduSddlError::UnknownSid { offset: start });
+        }
+    } else if is_code(sddl, pos, &SID_ALIASES) {
+        pos += 2;
+    } else {
+        return Err(WduSddlError::UnknownSid { offset: start });
+    }
+
+    if pos >= sddl.len() || sddl[pos] != b')' {
+        return Err(WduSddlError::Syntax { offset: pos });
+    }
+
+    Ok(pos + 1)
+}
+
+const fn is_code<const N: usize>(sddl: &[u8], pos: usize, codes: &[&[u8; 2]; N]) -> bool {
+    if pos + 1 >= sddl.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < N {
+        if codes[i][0] == sddl[pos] && codes[i][1] == sddl[pos + 1] {
+            return true;
+        }
+        i += 1;
+    }
+
+    false
+}
+
+// SDDL aliases of the well-known SIDs. Domain relative aliases (DA, DU, ...) need the domain SID
+// and aren't supported
+const WELL_KNOWN_SIDS: [(&str, WduSid); 38] = [
+    ("WD", WduSid::WORLD),
+    ("CO", WduSid::CREATOR_OWNER),
+    ("CG", WduSid::CREATOR_GROUP),
+    ("OW", WduSid::OWNER_RIGHTS),
+    ("NU", WduSid::NETWORK),
+    ("IU", WduSid::INTERACTIVE),
+    ("SU", WduSid::SERVICE),
+    ("AN", WduSid::ANONYMOUS),
+    ("ED", WduSid::ENTERPRISE_CONTROLLERS),
+    ("PS", WduSid::PRINCIPAL_SELF),
+    ("AU", WduSid::AUTHENTICATED_USERS),
+    ("RC", WduSid::RESTRICTED_CODE),
+    ("SY", WduSid::LOCAL_SYSTEM),
+    ("LS", WduSid::LOCAL_SERVICE),
+    ("NS", WduSid::NETWORK_SERVICE),
+    ("BA", WduSid::BUILTIN_ADMINISTRATORS),
+    ("BU", WduSid::BUILTIN_USERS),
+    ("BG", WduSid::BUILTIN_GUESTS),
+    ("PU", WduSid::BUILTIN_POWER_USERS),
+    ("AO", WduSid::BUILTIN_ACCOUNT_OPERATORS),
+    ("SO", WduSid::BUILTIN_SERVER_OPERATORS),
+    ("PO", WduSid::BUILTIN_PRINT_OPERATORS),
+    ("BO", WduSid::BUILTIN_BACKUP_OPERATORS),
+    ("RE", WduSid::BUILTIN_REPLICATOR),
+    ("RU", WduSid::BUILTIN_PRE_WINDOWS_2000),
+    ("RD", WduSid::BUILTIN_REMOTE_DESKTOP_USERS),
+    ("NO", WduSid::BUILTIN_NETWORK_CONFIGURATION_OPERATORS),
+    ("MU", WduSid::BUILTIN_PERFORMANCE_MONITOR_USERS),
+    ("LU", WduSid::BUILTIN_PERFORMANCE_LOG_USERS),
+    ("IS", WduSid::BUILTIN_IIS_USERS),
+    ("CY", WduSid::BUILTIN_CRYPTO_OPERATORS),
+    ("ER", WduSid::BUILTIN_EVENT_LOG_READERS),
+    ("AC", WduSid::ALL_APP_PACKAGES),
+    ("LW", WduSid::LOW_MANDATORY_LEVEL),
+    ("ME", WduSid::MEDIUM_MANDATORY_LEVEL),
+    ("MP", WduSid::MEDIUM_PLUS_MANDATORY_LEVEL),
+    ("HI", WduSid::HIGH_MANDATORY_LEVEL),
+    ("SI", WduSid::SYSTEM_MANDATORY_LEVEL),
+];
+
+const ACE_TYPES: [(&str, WduAceType); 5] = [
+    ("A", WduAceType::AccessAllowed),
+    ("D", WduAceType::AccessDenied),
+    ("AU", WduAceType::SystemAudit),
+    ("AL", WduAceType::SystemAlarm),
+    ("ML", WduAceType::MandatoryLabel),
+];
+
+// Object, callback, resource attribute... ACEs
+const UNSUPPORTED_ACE_TYPES: [&str; 11] = [
+    "OA", "OD", "OU", "OL", "XA", "XD", "XU", "ZA", "RA", "SP", "SD",
+];
+
+const ACE_FLAG_CODES: [(&str, WduAceFlags); 7] = [
+    ("OI", WduAceFlags::ObjectInherit),
+    ("CI", WduAceFlags::ContainerInherit),
+    ("NP", WduAceFlags::NoPropagateInherit),
+    ("IO", WduAceFlags::InheritOnly),
+    ("ID", WduAceFlags::Inherited),
+    ("SA", WduAceFlags::SuccessfulAccess),
+    ("FA", WduAceFlags::FailedAccess),
+];
+
+// Written as a single code when the mask matches exactly
+const COMPOSITE_RIGHTS: [(&str, u32); 8] = [
+    ("FA", 0x001F01FF),
+    ("FR", 0x00120089),
+    ("FW", 0x00120116),
+    ("FX", 0x001200A0),
+    ("KA", 0x000F003F),
+    ("KR", 0x00020019),
+    ("KW", 0x00020006),
+    ("KX", 0x00020019),
+];
+
+const RIGHT_BITS: [(&str, u32); 17] = [
+    ("GA", 0x10000000),
+    ("GR", 0x80000000),
+    ("GW", 0x40000000),
+    ("GX", 0x20000000),
+    ("RC", 0x00020000),
+    ("SD", 0x00010000),
+    ("WD", 0x00040000),
+    ("WO", 0x00080000),
+    ("RP", 0x00000010),
+    ("WP", 0x00000020),
+    ("CC", 0x00000001),
+    ("DC", 0x00000002),
+    ("LC", 0x00000004),
+    ("SW", 0x00000008),
+    ("LO", 0x00000080),
+    ("DT", 0x00000040),
+    ("CR", 0x00000100),
+];
+
+// Rights of mandatory label ACEs
+const LABEL_RIGHTS: [(&str, u32); 3] = [("NW", 0x1), ("NR", 0x2), ("NX", 0x4)];
+
+const NULL_ACL: &str = "NO_ACCESS_CONTROL";
+
+/// Parses a full SDDL string: owner (`O:`), group (`G:`), DACL (`D:`) and SACL (`S:`) in any
+/// order. Object ACEs, conditional ACEs and domain relative aliases are not supported.
+pub(crate) fn parse(sddl: &str) -> WduSddlResult<WduSecurityDescriptor> {
+    let bytes = sddl.as_bytes();
+    if bytes.is_empty() {
+        return Err(WduSddlError::Empty);
+    }
+
+    let mut descriptor = WduSecurityDescriptor::default();
+    let mut seen = [false; 4];
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let index = match bytes[pos] {
+            b'O' => 0,
+            b'G' => 1,
+            b'D' => 2,
+            b'S' => 3,
+            _ => return Err(WduSddlError::Syntax { offset: pos }),
+        };
+
+        if bytes.get(pos + 1) != Some(&b':') || seen[index] {
+            return Err(WduSddlError::Syntax { offset: pos });
+        }
+        seen[index] = true;
+
+        let start = pos + 2;
+        let end = section_end(bytes, start);
+        let value = &sddl[start..end];
+
+        descriptor = match index {
+            0 => descriptor.owner(parse_sid(value, start)?),
+            1 => descriptor.group(parse_sid(value, start)?),
+            2 => {
+                let (control, acl) = parse_acl(value, start, false)?;
+                descriptor.dacl(acl).control_flags(control)
+            }
+            _ => {
+                let (control, acl) = parse_acl(value, start, true)?;
+                descriptor.sacl(acl).control_flags(control)
+            }
+        };
+
+        pos = end;
+    }
+
+    Ok(descriptor)
+}
+
+// Offset of the next section (`X:` outside of an ACE) or the end of the string
+fn section_end(bytes: &[u8], from: usize) -> usize {
+    let mut depth = 0;
+
+    for pos in from..bytes.len() {
+        match bytes[pos] {
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            b'O' | b'G' | b'D' | b'S' if depth == 0 && bytes.get(pos + 1) == Some(&b':') => {
+                return pos
+            }
+            _ => (),
+        }
+    }
+
+    bytes.len()
+}
+
+fn parse_acl(
+    value: &str,
+    offset: usize,
+    sacl: bool,
+) -> WduSddlResult<(WduSecurityDescriptorControl, Option<WduAcl>)> {
+    let (protected, auto_inherited, auto_inherit_req) = if sacl {
+        (
+            WduSecurityDescriptorControl::SaclProtected,
+            WduSecurityDescriptorControl::SaclAutoInherited,
+            WduSecurityDescriptorControl::SaclAutoInheritReq,
+        )
+    } else {
+        (
+            WduSecurityDescriptorControl::DaclProtected,
+            WduSecurityDescriptorControl::DaclAutoInherited,
+            WduSecurityDescriptorControl::DaclAutoInheritReq,
+        )
+    };
+
+    let bytes = value.as_bytes();
+    let mut control = WduSecurityDescriptorControl::empty();
+    let mut null = false;
+    let mut pos = 0;
+
+    while pos < bytes.len() && bytes[pos] != b'(' {
+        let rest = &bytes[pos..];
+        if rest.starts_with(NULL_ACL.as_bytes()) {
+            null = true;
+            pos += NULL_ACL.len();
+        } else if rest.starts_with(b"AI") {
+            control |= auto_inherited;
+            pos += 2;
+        } else if rest.starts_with(b"AR") {
+            control |= auto_inherit_req;
+            pos += 2;
+        } else if rest[0] == b'P' {
+            control |= protected;
+            pos += 1;
+        } else {
+            return Err(WduSddlError::Syntax {
+                offset: offset + pos,
+            });
+        }
+    }
+
+    let mut acl = WduAcl::default();
+    while pos < bytes.len() {
+        let end = match bytes[pos..].iter().position(|c| *c == b')') {
+            Some(end) if bytes[pos] == b'(' && !null => pos + end,
+            _ => {
+                return Err(WduSddlError::Syntax {
+                    offset: offset + pos,
+                })
+            }
+        };
+
+        acl = acl.ace(parse_ace(&value[pos + 1..end], offset + pos + 1)?);
+        pos = end + 1;
+    }
+
+    Ok((control, if null { None } else { Some(acl) }))
+}
+
+fn parse_ace(ace: &str, offset: usize) -> WduSddlResult<WduAce> {
+    // Field & its offset: type, flags, rights, object GUID, inherit object GUID & SID
+    let mut fields = [("", 0); 6];
+    let mut count = 0;
+    let mut start = offset;
+
+    for field in ace.split(';') {
+        if count == fields.len() {
+            // Resource attribute ACEs have a seventh field
+            return Err(WduSddlError::Unsupported { offset: start });
+        }
+        fields[count] = (field, start);
+        start += field.len() + 1;
+        count += 1;
+    }
+
+    if count != fields.len() {
+        return Err(WduSddlError::Syntax { offset: start - 1 });
+    }
+
+    let (ace_type, position) = fields[0];
+    let ace_type = match ACE_TYPES.iter().find(|(code, _)| *code == ace_type) {
+        Some((_, ace_type)) => *ace_type,
+        None if UNSUPPORTED_ACE_TYPES.contains(&ace_type) => {
+            return Err(WduSddlError::Unsupported { offset: position })
+        }
+        None => return Err(WduSddlError::Syntax { offset: position }),
+    };
+
+    let (flags, position) = fields[1];
+    let mut ace_flags = WduAceFlags::empty();
+    for (index, code) in flags.as_bytes().chunks(2).enumerate() {
+        ace_flags |= ACE_FLAG_CODES
+            .iter()
+            .find(|(name, _)| name.as_bytes() == code)
+            .map(|(_, flag)| *flag)
+            .ok_or(WduSddlError::Syntax {
+                offset: position + index * 2,
+            })?;
+    }
+
+    let mask = parse_rights(fields[2].0, fields[2].1)?;
+
+    for (guid, position) in &fields[3..5] {
+        if !guid.is_empty() {
+            return Err(WduSddlError::Unsupported { offset: *position });
+        }
+    }
+
+    let sid = parse_sid(fields[5].0, fields[5].1)?;
+
+    Ok(WduAce::new(ace_type, ace_flags, mask, sid))
+}
+
+fn parse_rights(rights: &str, offset: usize) -> WduSddlResult<u32> {
+    let unknown = WduSddlError::UnknownRight { offset };
+
+    if let Some(hex) = rights
+        .strip_prefix("0x")
+        .or_else(|| rights.strip_prefix("0X"))
+    {
+        return u32::from_str_radix(hex, 16).map_err(|_| unknown);
+    }
+
+    if rights.starts_with(|c: char| c.is_ascii_digit()) {
+        return rights.parse().map_err(|_| unknown);
+    }
+
+    if rights.is_empty() {
+        return Err(unknown);
+    }
+
+    let mut mask = 0;
+    for (index, code) in rights.as_bytes().chunks(2).enumerate() {
+        mask |= COMPOSITE_RIGHTS
+            .iter()
+            .chain(RIGHT_BITS.iter())
+            .chain(LABEL_RIGHTS.iter())
+            .find(|(name, _)| name.as_bytes() == code)
+            .map(|(_, right)| *right)
+            .ok_or(WduSddlError::UnknownRight {
+                offset: offset + index * 2,
+            })?;
+    }
+
+    Ok(mask)
+}
+
+/// SID from an SDDL alias or a `S-1-...` string.
+fn parse_sid(sid: &str, offset: usize) -> WduSddlResult<WduSid> {
+    match WELL_KNOWN_SIDS.iter().find(|(alias, _)| *alias == sid) {
+        Some((_, known)) => Ok(*known),
+        None => WduSid::try_from(sid).map_err(|_| WduSddlError::UnknownSid { offset }),
+    }
+}
+
+/// SDDL alias of a SID, or its `S-1-...` form if it has none.
+fn format_sid(sid: &WduSid) -> String {
+    match WELL_KNOWN_SIDS.iter().find(|(_, known)| known == sid) {
+        Some((alias, _)) => String::from(*alias),
+        None => sid.to_string(),
+    }
+}
+
+/// SDDL string of the descriptor, sections in the order O, G, D, S.
+pub(crate) fn format(descriptor: &WduSecurityDescriptor) -> String {
+    let mut sddl = String::new();
+    let control = descriptor.control();
+
+    if let Some(owner) = descriptor.owner_sid() {
+        sddl.push_str("O:");
+        sddl.push_str(&format_sid(owner));
+    }
+
+    if let Some(group) = descriptor.group_sid() {
+        sddl.push_str("G:");
+        sddl.push_str(&format_sid(group));
+    }
+
+    if descriptor.is_dacl_present() {
+        sddl.push_str("D:");
+        format_acl(
+            &mut sddl,
+            descriptor.discretionary_acl(),
+            control.contains(WduSecurityDescriptorControl::DaclProtected),
+            control.contains(WduSecurityDescriptorControl::DaclAutoInherited),
+            control.contains(WduSecurityDescriptorControl::DaclAutoInheritReq),
+        );
+    }
+
+    if descriptor.is_sacl_present() {
+        sddl.push_str("S:");
+        format_acl(
+            &mut sddl,
+            descriptor.system_acl(),
+            control.contains(WduSecurityDescriptorControl::SaclProtected),
+            control.contains(WduSecurityDescriptorControl::SaclAutoInherited),
+            control.contains(WduSecurityDescriptorControl::SaclAutoInheritReq),
+        );
+    }
+
+    sddl
+}
+
+fn format_acl(
+    sddl: &mut String,
+    acl: Option<&WduAcl>,
+    protected: bool,
+    auto_inherited: bool,
+    auto_inherit_req: bool,
+) {
+    if protected {
+        sddl.push('P');
+    }
+    if auto_inherited {
+        sddl.push_str("AI");
+    }
+    if auto_inherit_req {
+        sddl.push_str("AR");
+    }
+
+    let acl = match acl {
+        Some(acl) => acl,
+        None => {
+            sddl.push_str(NULL_ACL);
+            return;
+        }
+    };
+
+    for ace in acl.aces() {
+        let ace_type = ACE_TYPES
+            .iter()
+            .find(|(_, ace_type)| *ace_type == ace.ace_type())
+            .map_or("", |(code, _)| code);
+
+        sddl.push('(');
+        sddl.push_str(ace_type);
+        sddl.push(';');
+        for (code, flag) in ACE_FLAG_CODES.iter() {
+            if ace.flags().contains(*flag) {
+                sddl.push_str(code);
+            }
+        }
+        sddl.push(';');
+        format_rights(sddl, ace.mask(), ace.ace_type());
+        sddl.push_str(";;;");
+        sddl.push_str(&format_sid(ace.sid()));
+        sddl.push(')');
+    }
+}
+
+// Codes when every bit has one, hexadecimal otherwise
+fn format_rights(sddl: &mut String, mask: u32, ace_type: WduAceType) {
+    if ace_type != WduAceType::MandatoryLabel {
+        if let Some((code, _)) = COMPOSITE_RIGHTS.iter().find(|(_, right)| *right == mask) {
+            sddl.push_str(code);
+            return;
+        }
+    }
+
+    let codes: &[(&str, u32)] = if ace_type == WduAceType::MandatoryLabel {
+        &LABEL_RIGHTS
+    } else {
+        &RIGHT_BITS
+    };
+
+    let known = codes.iter().fold(0, |known, (_, right)| known | right);
+    if mask == 0 || mask & !known != 0 {
+        let _ = write!(sddl, "{mask:#x}");
+        return;
+    }
+
+    for (code, right) in codes {
+        if mask & right != 0 {
+            sddl.push_str(code);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for sddl in [
+            "O:BAG:SYD:PAI(A;OICI;FA;;;SY)(A;;0x1200a9;;;BU)(D;;WDWO;;;S-1-5-21-1-2-3-1001)\
+             S:AI(AU;SAFA;GA;;;WD)(ML;;NW;;;HI)",
+            "D:NO_ACCESS_CONTROL",
+            "O:S-1-0x123456789ABC-1",
+            "D:P(A;;GRGX;;;BA)",
+            "S:(ML;;NWNRNX;;;LW)",
+        ] {
+            let descriptor = WduSecurityDescriptor::from_sddl(sddl).unwrap();
+            assert_eq!(descriptor.to_sddl(), sddl);
+        }
+
+        // Access rights without a code are kept as a hex mask
+        let descriptor = WduSecurityDescriptor::from_sddl("D:(A;;0x1;;;AN)").unwrap();
+        assert_eq!(
+            WduSecurityDescriptor::from_sddl(&descriptor.to_sddl()).unwrap(),
+            descriptor
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let descriptor = WduSecurityDescriptor::from_sddl("O:BAD:(A;OICI;GA;;;WD)").unwrap();
+        assert_eq!(
+            descriptor.owner_sid(),
+            Some(&WduSid::BUILTIN_ADMINISTRATORS)
+        );
+        assert!(descriptor.group_sid().is_none());
+        assert!(descriptor.is_dacl_present() && !descriptor.is_sacl_present());
+
+        let ace = &descriptor.discretionary_acl().unwrap().aces()[0];
+        assert_eq!(ace.ace_type(), WduAceType::AccessAllowed);
+        assert_eq!(
+            ace.flags(),
+            WduAceFlags::ObjectInherit | WduAceFlags::ContainerInherit
+        );
+        assert_eq!(ace.mask(), 0x10000000);
+        assert_eq!(ace.sid(), &WduSid::WORLD);
+
+        let null_dacl = WduSecurityDescriptor::from_sddl("D:NO_ACCESS_CONTROL").unwrap();
+        assert!(null_dacl.is_dacl_present() && null_dacl.discretionary_acl().is_none());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            WduSecurityDescriptor::from_sddl(""),
+            Err(WduSddlError::Empty)
+        );
+        assert_eq!(
+            WduSecurityDescriptor::from_sddl("D:(OA;;GA;;;WD)"),
+            Err(WduSddlError::Unsupported { offset: 3 })
+        );
+        assert_eq!(
+            WduSecurityDescriptor::from_sddl("D:(A;;GA;;;DA)"),
+            Err(WduSddlError::UnknownSid { offset: 11 })
+        );
+        assert_eq!(
+            WduSecurityDescriptor::from_sddl("O:BAO:BA"),
+            Err(WduSddlError::Syntax { offset: 4 })
+        );
+        assert!(WduSecurityDescriptor::from_sddl("D:(A;;GA;;;WD").is_err());
+        assert!(WduSecurityDescriptor::from_sddl("D:(A;;ZZ;;;WD)").is_err());
+    }
+
+    #[test]
+    fn device_sddl() {
+        assert_eq!(
+            WduSddl::SYS_ALL_ADM_ALL.as_str(),
+            "D:P(A;;GA;;;SY)(A;;GA;;;BA)"
+        );
+        assert_eq!(
+            WduSddl::SYS_ALL_ADM_ALL.to_descriptor().unwrap().to_sddl(),
+            "D:P(A;;GA;;;SY)(A;;GA;;;BA)"
+        );
+
+        assert!(WduSddl::try_new("D:P").is_ok());
+        assert!(WduSddl::try_new("D:(A;;0x1200a9;;;WD)").is_ok());
+        assert!(WduSddl::try_new("D:P(A;;GA;;;S-1-5-32-544)").is_ok());
+        assert!(WduSddl::try_new("D:P(A;OICI;GRGW;;;AU)").is_ok());
+
+        assert_eq!(WduSddl::try_new(""), Err(WduSddlError::Empty));
+        // Missing ';' before the SID
+        assert!(WduSddl::try_new("D:P(A;;GA;;SY)").is_err());
+        assert_eq!(
+            WduSddl::try_new("D:P(A;;GA;;;SY"),
+            Err(WduSddlError::Syntax { offset: 14 })
+        );
+        assert_eq!(
+            WduSddl::try_new("D:P(A;;XX;;;SY)"),
+            Err(WduSddlError::UnknownRight { offset: 7 })
+        );
+        assert_eq!(
+            WduSddl::try_new("D:P(A;;GA;;;ZZ)"),
+            Err(WduSddlError::UnknownSid { offset: 12 })
+        );
+        assert!(WduSddl::try_new("D:P(A;;GA;;;S-1-)").is_err());
+        // Only the DACL is supported by IoCreateDeviceSecure
+        assert_eq!(
+            WduSddl::try_new("O:BAD:P"),
+            Err(WduSddlError::Unsupported { offset: 0 })
+        );
+        assert_eq!(
+            WduSddl::try_new("D:P(A;;GA;;;SY)S:(AU;;GA;;;WD)"),
+            Err(WduSddlError::Unsupported { offset: 15 })
+        );
+    }
+}
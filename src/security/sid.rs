@@ -0,0 +1,423 @@
+//! Security identifiers.
+//!
+//! [WduSid] has the same layout as the kernel SID, sized for the maximum number of
+//! sub-authorities, so it can be passed as a PSID without copying. Conversions don't call into
+//! the kernel.
+use alloc::vec::Vec;
+use core::{ffi::c_void, fmt, mem::size_of};
+use snafu::Snafu;
+
+// SID_MAX_SUB_AUTHORITIES & SID_REVISION live in Win32::System::SystemServices which isn't a
+// feature of the crate
+pub const MAX_SUB_AUTHORITIES: usize = 15;
+const SID_REVISION: u8 = 1;
+// Revision, SubAuthorityCount & IdentifierAuthority
+const SID_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Snafu, Clone, Copy, PartialEq, Eq)]
+pub enum WduSidError {
+    #[snafu(display("Invalid SID string"))]
+    InvalidString,
+    #[snafu(display("Identifier authority {authority:#x} doesn't fit in 48 bits"))]
+    InvalidAuthority { authority: u64 },
+    #[snafu(display("{count} sub-authorities, the maximum is 15"))]
+    TooManySubAuthorities { count: usize },
+    #[snafu(display("Invalid SID revision {revision}"))]
+    InvalidRevision { revision: u8 },
+    #[snafu(display("Buffer of {length} bytes is too small for the SID"))]
+    BufferTooSmall { length: usize },
+}
+
+pub type WduSidResult<T> = Result<T, WduSidError>;
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WduSid {
+    revision: u8,
+    sub_authority_count: u8,
+    // Big endian
+    identifier_authority: [u8; 6],
+    // Entries past `sub_authority_count` are always 0 so the derived traits work
+    sub_authority: [u32; MAX_SUB_AUTHORITIES],
+}
+
+impl WduSid {
+    /// S-1-0-0
+    pub const NULL: WduSid = WduSid::new(0, &[0]);
+    /// S-1-1-0, Everyone
+    pub const WORLD: WduSid = WduSid::new(1, &[0]);
+    /// S-1-2-0
+    pub const LOCAL: WduSid = WduSid::new(2, &[0]);
+    /// S-1-3-0
+    pub const CREATOR_OWNER: WduSid = WduSid::new(3, &[0]);
+    /// S-1-3-1
+    pub const CREATOR_GROUP: WduSid = WduSid::new(3, &[1]);
+    /// S-1-3-4
+    pub const OWNER_RIGHTS: WduSid = WduSid::new(3, &[4]);
+    /// S-1-5, prefix of every NT authority SID
+    pub const NT_AUTHORITY: WduSid = WduSid::new(5, &[]);
+    /// S-1-5-2
+    pub const NETWORK: WduSid = WduSid::new(5, &[2]);
+    /// S-1-5-3
+    pub const BATCH: WduSid = WduSid::new(5, &[3]);
+    /// S-1-5-4
+    pub const INTERACTIVE: WduSid = WduSid::new(5, &[4]);
+    /// S-1-5-6
+    pub const SERVICE: WduSid = WduSid::new(5, &[6]);
+    /// S-1-5-7
+    pub const ANONYMOUS: WduSid = WduSid::new(5, &[7]);
+    /// S-1-5-9
+    pub const ENTERPRISE_CONTROLLERS: WduSid = WduSid::new(5, &[9]);
+    /// S-1-5-10
+    pub const PRINCIPAL_SELF: WduSid = WduSid::new(5, &[10]);
+    /// S-1-5-11
+    pub const AUTHENTICATED_USERS: WduSid = WduSid::new(5, &[11]);
+    /// S-1-5-12
+    pub const RESTRICTED_CODE: WduSid = WduSid::new(5, &[12]);
+    /// S-1-5-18
+    pub const LOCAL_SYSTEM: WduSid = WduSid::new(5, &[18]);
+    /// S-1-5-19
+    pub const LOCAL_SERVICE: WduSid = WduSid::new(5, &[19]);
+    /// S-1-5-20
+    pub const NETWORK_SERVICE: WduSid = WduSid::new(5, &[20]);
+    /// S-1-5-21, prefix of domain and local account SIDs
+    pub const NT_NON_UNIQUE: WduSid = WduSid::new(5, &[21]);
+    /// S-1-5-32, prefix of the builtin groups
+    pub const BUILTIN_DOMAIN: WduSid = WduSid::new(5, &[32]);
+    /// S-1-5-32-544
+    pub const BUILTIN_ADMINISTRATORS: WduSid = WduSid::new(5, &[32, 544]);
+    /// S-1-5-32-545
+    pub const BUILTIN_USERS: WduSid = WduSid::new(5, &[32, 545]);
+    /// S-1-5-32-546
+    pub const BUILTIN_GUESTS: WduSid = WduSid::new(5, &[32, 546]);
+    /// S-1-5-32-547
+    pub const BUILTIN_POWER_USERS: WduSid = WduSid::new(5, &[32, 547]);
+    /// S-1-5-32-548
+    pub const BUILTIN_ACCOUNT_OPERATORS: WduSid = WduSid::new(5, &[32, 548]);
+    /// S-1-5-32-549
+    pub const BUILTIN_SERVER_OPERATORS: WduSid = WduSid::new(5, &[32, 549]);
+    /// S-1-5-32-550
+    pub const BUILTIN_PRINT_OPERATORS: WduSid = WduSid::new(5, &[32, 550]);
+    /// S-1-5-32-551
+    pub const BUILTIN_BACKUP_OPERATORS: WduSid = WduSid::new(5, &[32, 551]);
+    /// S-1-5-32-552
+    pub const BUILTIN_REPLICATOR: WduSid = WduSid::new(5, &[32, 552]);
+    /// S-1-5-32-554
+    pub const BUILTIN_PRE_WINDOWS_2000: WduSid = WduSid::new(5, &[32, 554]);
+    /// S-1-5-32-555
+    pub const BUILTIN_REMOTE_DESKTOP_USERS: WduSid = WduSid::new(5, &[32, 555]);
+    /// S-1-5-32-556
+    pub const BUILTIN_NETWORK_CONFIGURATION_OPERATORS: WduSid = WduSid::new(5, &[32, 556]);
+    /// S-1-5-32-558
+    pub const BUILTIN_PERFORMANCE_MONITOR_USERS: WduSid = WduSid::new(5, &[32, 558]);
+    /// S-1-5-32-559
+    pub const BUILTIN_PERFORMANCE_LOG_USERS: WduSid = WduSid::new(5, &[32, 559]);
+    /// S-1-5-32-568
+    pub const BUILTIN_IIS_USERS: WduSid = WduSid::new(5, &[32, 568]);
+    /// S-1-5-32-569
+    pub const BUILTIN_CRYPTO_OPERATORS: WduSid = WduSid::new(5, &[32, 569]);
+    /// S-1-5-32-573
+    pub const BUILTIN_EVENT_LOG_READERS: WduSid = WduSid::new(5, &[32, 573]);
+    /// S-1-15-2-1
+    pub const ALL_APP_PACKAGES: WduSid = WduSid::new(15, &[2, 1]);
+    /// S-1-16-0
+    pub const UNTRUSTED_MANDATORY_LEVEL: WduSid = WduSid::new(16, &[0]);
+    /// S-1-16-4096
+    pub const LOW_MANDATORY_LEVEL: WduSid = WduSid::new(16, &[4096]);
+    /// S-1-16-8192
+    pub const MEDIUM_MANDATORY_LEVEL: WduSid = WduSid::new(16, &[8192]);
+    /// S-1-16-8448
+    pub const MEDIUM_PLUS_MANDATORY_LEVEL: WduSid = WduSid::new(16, &[8448]);
+    /// S-1-16-12288
+    pub const HIGH_MANDATORY_LEVEL: WduSid = WduSid::new(16, &[12288]);
+    /// S-1-16-16384
+    pub const SYSTEM_MANDATORY_LEVEL: WduSid = WduSid::new(16, &[16384]);
+    /// S-1-16-20480
+    pub const PROTECTED_PROCESS_MANDATORY_LEVEL: WduSid = WduSid::new(16, &[20480]);
+
+    /// Panics if the authority doesn't fit in 48 bits or there are too many sub-authorities,
+    /// meant for constants.
+    pub const fn new(authority: u64, sub_authorities: &[u32]) -> Self {
+        match Self::try_new(authority, sub_authorities) {
+            Ok(sid) => sid,
+            Err(_) => panic!("Invalid SID"),
+        }
+    }
+
+    pub const fn try_new(authority: u64, sub_authorities: &[u32]) -> WduSidResult<Self> {
+        if authority >= 1 << 48 {
+            return Err(WduSidError::InvalidAuthority { authority });
+        }
+
+        if sub_authorities.len() > MAX_SUB_AUTHORITIES {
+            return Err(WduSidError::TooManySubAuthorities {
+                count: sub_authorities.len(),
+            });
+        }
+
+        let bytes = authority.to_be_bytes();
+        let mut sid = WduSid {
+            revision: SID_REVISION,
+            sub_authority_count: sub_authorities.len() as u8,
+            identifier_authority: [bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]],
+            sub_authority: [0; MAX_SUB_AUTHORITIES],
+        };
+
+        let mut i = 0;
+        while i < sub_authorities.len() {
+            sid.sub_authority[i] = sub_authorities[i];
+            i += 1;
+        }
+
+        Ok(sid)
+    }
+
+    /// Parses the binary form at the start of `bytes`, extra bytes are ignored.
+    pub fn from_bytes(bytes: &[u8]) -> WduSidResult<Self> {
+        let too_small = WduSidError::BufferTooSmall {
+            length: bytes.len(),
+        };
+
+        let header = bytes.get(..SID_HEADER_SIZE).ok_or(too_small)?;
+        if header[0] != SID_REVISION {
+            return Err(WduSidError::InvalidRevision {
+                revision: header[0],
+            });
+        }
+
+        let count = header[1] as usize;
+        if count > MAX_SUB_AUTHORITIES {
+            return Err(WduSidError::TooManySubAuthorities { count });
+        }
+
+        let sub_authorities = bytes
+            .get(SID_HEADER_SIZE..SID_HEADER_SIZE + count * size_of::<u32>())
+            .ok_or(too_small)?;
+
+        let mut sid = WduSid {
+            revision: SID_REVISION,
+            sub_authority_count: count as u8,
+            identifier_authority: [0; 6],
+            sub_authority: [0; MAX_SUB_AUTHORITIES],
+        };
+        sid.identifier_authority.copy_from_slice(&header[2..]);
+        for (sub_authority, bytes) in sid
+            .sub_authority
+            .iter_mut()
+            .zip(sub_authorities.chunks_exact(size_of::<u32>()))
+        {
+            *sub_authority = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+
+        Ok(sid)
+    }
+
+    /// Copies the SID at `sid`, e.g. one returned by the kernel.
+    ///
+    /// # Safety
+    /// `sid` must point to a valid SID.
+    pub unsafe fn from_ptr(sid: *const c_void) -> WduSidResult<Self> {
+        let header = core::slice::from_raw_parts(sid as *const u8, SID_HEADER_SIZE);
+        let count = (header[1] as usize).min(MAX_SUB_AUTHORITIES);
+
+        Self::from_bytes(core::slice::from_raw_parts(
+            sid as *const u8,
+            SID_HEADER_SIZE + count * size_of::<u32>(),
+        ))
+    }
+
+    pub fn identifier_authority(&self) -> u64 {
+        self.identifier_authority
+            .iter()
+            .fold(0, |authority, byte| authority << 8 | *byte as u64)
+    }
+
+    pub fn sub_authorities(&self) -> &[u32] {
+        &self.sub_authority[..self.sub_authority_count as usize]
+    }
+
+    /// Last sub-authority, the relative identifier of account SIDs.
+    pub fn rid(&self) -> Option<u32> {
+        self.sub_authorities().last().copied()
+    }
+
+    /// Size of the binary form, GetLengthSid.
+    pub fn size(&self) -> usize {
+        SID_HEADER_SIZE + self.sub_authority_count as usize * size_of::<u32>()
+    }
+
+    /// Binary form, only the used sub-authorities.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, self.size()) }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    /// PSID, valid as long as the value isn't moved or dropped.
+    pub fn as_ptr(&self) -> *const c_void {
+        self as *const Self as *const _
+    }
+
+    /// Same identifier authority and `prefix` sub-authorities are the first ones of this SID,
+    /// e.g. every builtin group starts with [WduSid::BUILTIN_DOMAIN].
+    pub fn starts_with(&self, prefix: &WduSid) -> bool {
+        self.identifier_authority == prefix.identifier_authority
+            && self.sub_authorities().starts_with(prefix.sub_authorities())
+    }
+
+    /// Same domain, all the sub-authorities but the RID are equal (EqualPrefixSid).
+    pub fn is_same_domain(&self, other: &WduSid) -> bool {
+        self.sub_authority_count == other.sub_authority_count
+            && self.sub_authority_count > 0
+            && self.identifier_authority == other.identifier_authority
+            && self.sub_authorities()[..self.sub_authority_count as usize - 1]
+                == other.sub_authorities()[..other.sub_authority_count as usize - 1]
+    }
+}
+
+impl TryFrom<&str> for WduSid {
+    type Error = WduSidError;
+
+    /// Parses the `S-1-...` form. The identifier authority can be decimal or hexadecimal
+    /// (`0x...`), as ConvertStringSidToSid accepts.
+    fn try_from(sid: &str) -> WduSidResult<Self> {
+        let mut parts = sid
+            .strip_prefix("S-1-")
+            .ok_or(WduSidError::InvalidString)?
+            .split('-');
+
+        let authority = parts.next().ok_or(WduSidError::InvalidString)?;
+        let authority = match authority
+            .strip_prefix("0x")
+            .or_else(|| authority.strip_prefix("0X"))
+        {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => authority.parse(),
+        }
+        .map_err(|_| WduSidError::InvalidString)?;
+
+        let mut sub_authorities = [0; MAX_SUB_AUTHORITIES];
+        let mut count = 0;
+        for part in parts {
+            if count == MAX_SUB_AUTHORITIES {
+                return Err(WduSidError::TooManySubAuthorities { count: count + 1 });
+            }
+            sub_authorities[count] = part.parse().map_err(|_| WduSidError::InvalidString)?;
+            count += 1;
+        }
+
+        Self::try_new(authority, &sub_authorities[..count])
+    }
+}
+
+impl TryFrom<&[u8]> for WduSid {
+    type Error = WduSidError;
+
+    fn try_from(bytes: &[u8]) -> WduSidResult<Self> {
+        Self::from_bytes(bytes)
+    }
+}
+
+/// `S-1-...` form, the identifier authority is written in hexadecimal when it doesn't fit in 32
+/// bits.
+impl fmt::Display for WduSid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let authority = self.identifier_authority();
+        if authority < 1 << 32 {
+            write!(f, "S-1-{authority}")?;
+        } else {
+            write!(f, "S-1-0x{authority:012X}")?;
+        }
+
+        for sub_authority in self.sub_authorities() {
+            write!(f, "-{sub_authority}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for WduSid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WduSid({self})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn parse() {
+        let admins = WduSid::try_from("S-1-5-32-544").unwrap();
+        assert_eq!(admins, WduSid::BUILTIN_ADMINISTRATORS);
+        assert_eq!(admins.identifier_authority(), 5);
+        assert_eq!(admins.sub_authorities(), [32, 544]);
+        assert_eq!(admins.rid(), Some(544));
+
+        let large_authority = WduSid::try_from("S-1-0x123456789ABC-1").unwrap();
+        assert_eq!(large_authority.identifier_authority(), 0x123456789ABC);
+
+        assert!(WduSid::try_from("S-1-5-").is_err());
+        assert_eq!(
+            WduSid::try_from("S-2-5-18"),
+            Err(WduSidError::InvalidString)
+        );
+        assert!(WduSid::try_from("S-1-5-1-2-3-4-5-6-7-8-9-10-11-12-13-14-15-16").is_err());
+        assert!(WduSid::try_from("S-1-281474976710656-1").is_err());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(WduSid::LOCAL_SYSTEM.to_string(), "S-1-5-18");
+        assert_eq!(WduSid::BUILTIN_ADMINISTRATORS.to_string(), "S-1-5-32-544");
+
+        for sid in [
+            "S-1-5-21-1004336348-1177238915-682003330-1001",
+            "S-1-0x123456789ABC-1",
+            "S-1-16-12288",
+        ] {
+            assert_eq!(WduSid::try_from(sid).unwrap().to_string(), sid);
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let admins = WduSid::BUILTIN_ADMINISTRATORS;
+        assert_eq!(admins.size(), 16);
+        assert_eq!(
+            admins.as_bytes(),
+            [1, 2, 0, 0, 0, 0, 0, 5, 32, 0, 0, 0, 0x20, 2, 0, 0]
+        );
+        assert_eq!(WduSid::from_bytes(admins.as_bytes()).unwrap(), admins);
+        assert_eq!(
+            unsafe { WduSid::from_ptr(admins.as_ptr()) }.unwrap(),
+            admins
+        );
+
+        assert_eq!(
+            WduSid::from_bytes(&admins.as_bytes()[..12]),
+            Err(WduSidError::BufferTooSmall { length: 12 })
+        );
+        assert_eq!(
+            WduSid::from_bytes(&[2, 0, 0, 0, 0, 0, 0, 5]),
+            Err(WduSidError::InvalidRevision { revision: 2 })
+        );
+    }
+
+    #[test]
+    fn domain() {
+        let user = WduSid::try_from("S-1-5-21-1004336348-1177238915-682003330-1001").unwrap();
+        let other_user = WduSid::try_from("S-1-5-21-1004336348-1177238915-682003330-500").unwrap();
+
+        assert!(user.starts_with(&WduSid::NT_NON_UNIQUE));
+        assert!(user.starts_with(&WduSid::NT_AUTHORITY));
+        assert!(!user.starts_with(&WduSid::BUILTIN_DOMAIN));
+        assert!(WduSid::BUILTIN_ADMINISTRATORS.starts_with(&WduSid::BUILTIN_DOMAIN));
+        assert!(user.is_same_domain(&other_user) && user != other_user);
+        assert!(!user.is_same_domain(&WduSid::BUILTIN_ADMINISTRATORS));
+    }
+}
@@ -3,13 +3,13 @@ use crate::{
     dereference, inner_getters_ptr, inner_getters_value, ref_by_handle, ProcessorMode, WduResult,
 };
 use windows_sys::Wdk::Foundation::POBJECT_TYPE;
-use windows_sys::Win32::Foundation::{HANDLE, STATUS_SUCCESS};
+use windows_sys::Win32::Foundation::{HANDLE, NTSTATUS, STATUS_SUCCESS};
 use windows_sys::{
     Wdk::{
         Foundation::KEVENT,
         System::SystemServices::{
-            KeClearEvent, KeInitializeEvent, KePulseEvent, KeReadStateEvent, KeResetEvent,
-            KeSetEvent,
+            Executive, KeClearEvent, KeInitializeEvent, KePulseEvent, KeReadStateEvent,
+            KeResetEvent, KeSetEvent, KeWaitForSingleObject,
         },
     },
     Win32::System::Kernel::EVENT_TYPE,
@@ -45,6 +45,10 @@ impl Default for WduEvent {
 
 inner_getters_ptr!(WduEvent, event, KEVENT);
 
+// A KEVENT is meant to be signaled from any thread
+unsafe impl Send for WduEvent {}
+unsafe impl Sync for WduEvent {}
+
 // TODO: Consider ZwEvent related functions
 impl WduEvent {
     #[cfg(feature = "const_new")]
@@ -60,6 +64,11 @@ impl WduEvent {
         }
     }
 
+    // Event storage owned by someone else (e.g. a KEVENT inside a structure)
+    pub(crate) fn wrap(event: *mut KEVENT) -> Self {
+        WduEvent { event }
+    }
+
     pub fn init(&mut self, event_type: WduEventType, state: bool) {
         unsafe {
             KeInitializeEvent(self.as_mut_ptr(), event_type.into(), u8::from(state));
@@ -88,6 +97,23 @@ impl WduEvent {
         unsafe { KePulseEvent(self.as_mut_ptr(), increment, u8::from(wait)) }
     }
 
+    /// Waits until the event is signaled. `None` waits forever.
+    pub fn wait(&self, mode: ProcessorMode, alertable: bool, timeout: Option<i64>) -> NTSTATUS {
+        let timeout = timeout
+            .as_ref()
+            .map_or(core::ptr::null(), |timeout| timeout as *const _);
+
+        unsafe {
+            KeWaitForSingleObject(
+                self.as_ptr() as *const _,
+                Executive,
+                mode.into(),
+                u8::from(alertable),
+                timeout,
+            )
+        }
+    }
+
     pub fn ref_by_handle(
         handle: HANDLE,
         access_mask: u32,
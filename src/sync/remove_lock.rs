@@ -24,6 +24,10 @@ impl Default for WduRemoveLock {
 
 inner_getters_value!(WduRemoveLock, lock, IO_REMOVE_LOCK);
 
+// An IO_REMOVE_LOCK is meant to be acquired & released from any thread
+unsafe impl Send for WduRemoveLock {}
+unsafe impl Sync for WduRemoveLock {}
+
 impl WduRemoveLock {
     #[cfg(feature = "const_new")]
     pub const fn const_new() -> Self {
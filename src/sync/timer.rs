@@ -1,12 +1,12 @@
-use crate::common::dpc::WduDpc;
+use crate::common::{child::WduChild, dpc::WduDpc};
 use crate::inner_getters_value;
 use windows_sys::{
     Wdk::{
         Foundation::{PEX_TIMER, PIO_TIMER},
         System::SystemServices::{
-            ExCancelTimer, ExDeleteTimer, ExSetTimer, KeCancelTimer, KeInitializeTimer,
-            KeInitializeTimerEx, KeReadStateTimer, KeSetCoalescableTimer, KeSetTimer, KeSetTimerEx,
-            EX_TIMER_HIGH_RESOLUTION, EX_TIMER_NO_WAKE, KTIMER,
+            ExCancelTimer, ExDeleteTimer, ExSetTimer, KeCancelTimer, KeFlushQueuedDpcs,
+            KeInitializeTimer, KeInitializeTimerEx, KeReadStateTimer, KeSetCoalescableTimer,
+            KeSetTimer, KeSetTimerEx, EX_TIMER_HIGH_RESOLUTION, EX_TIMER_NO_WAKE, KTIMER,
         },
     },
     Win32::System::Kernel::{NotificationTimer, SynchronizationTimer, TIMER_TYPE},
@@ -137,6 +137,14 @@ impl WduTimer {
     }
 }
 
+// Cancelling doesn't wait for a DPC that already started running
+impl WduChild for WduTimer {
+    fn teardown(&mut self) {
+        self.cancel();
+        unsafe { KeFlushQueuedDpcs() };
+    }
+}
+
 impl WduExTimer {
     // Has to return Option, if ExAllocateTimer fails will return nullptr then return none
     pub fn init() -> Self {
@@ -170,4 +178,12 @@ impl WduExTimer {
     }
 }
 
+impl WduChild for WduExTimer {
+    fn teardown(&mut self) {
+        if self.get() != 0 {
+            self.delete(true, true);
+        }
+    }
+}
+
 // TODO: Create pub function for ExSetTimerResolution & ExQueryTimerResolution
@@ -0,0 +1,247 @@
+#![no_std]
+#![allow(internal_features)]
+#![feature(lang_items)]
+extern crate alloc;
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use kernel_log::KernelLogger;
+use log::LevelFilter;
+use widestring::{utf16str, Utf16Str};
+
+use win_drvutils_rs::{
+    bug_check,
+    common::{
+        child::{WduChild, WduChildError},
+        context::WduContextError,
+        driver::WduDriver,
+    },
+    io::device::{WduDevice, WduDeviceError},
+    memory::pool::SimpleAlloc,
+    registry::reg::WduRegistryError,
+    strings::unicode::str::WduUnicodeStr,
+};
+
+use windows_sys::{Wdk::Foundation::DRIVER_OBJECT, Win32::Foundation::UNICODE_STRING};
+
+#[global_allocator]
+static mut GLOBAL: SimpleAlloc = SimpleAlloc::const_new();
+
+#[export_name = "_fltused"]
+static _FLTUSED: i32 = 0;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    bug_check(info, None, None, None, None);
+    loop {}
+}
+
+#[lang = "eh_personality"]
+extern "C" fn eh_personality() {}
+
+#[no_mangle]
+extern "system" fn __CxxFrameHandler3(_: *mut u8, _: *mut u8, _: *mut u8, _: *mut u8) -> i32 {
+    unimplemented!()
+}
+
+static DROPS: AtomicU32 = AtomicU32::new(0);
+
+struct Extension {
+    value: u32,
+}
+
+impl Drop for Extension {
+    fn drop(&mut self) {
+        DROPS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+static CLEANUPS: AtomicU32 = AtomicU32::new(0);
+
+struct DriverContext {
+    value: u32,
+}
+
+struct First;
+struct Second;
+
+// Contexts are cleaned up in reverse order
+fn first_cleanup(_: &mut First) {
+    assert!(CLEANUPS.fetch_add(1, Ordering::SeqCst) == 1);
+}
+
+fn second_cleanup(_: &mut Second) {
+    assert!(CLEANUPS.fetch_add(1, Ordering::SeqCst) == 0);
+}
+
+static TEARDOWNS: AtomicU32 = AtomicU32::new(0);
+
+// Records the order in which the children are torn down
+struct Child {
+    order: u32,
+}
+
+impl WduChild for Child {
+    fn teardown(&mut self) {
+        assert!(TEARDOWNS.fetch_add(1, Ordering::SeqCst) == self.order);
+    }
+}
+
+// Driver-wide context, dropped after the unload routine
+struct TestContext {
+    value: u32,
+}
+
+const MISSING_VALUE_UTF16: &Utf16Str = utf16str!("WduMissingValue");
+
+#[repr(align(64))]
+#[derive(Default)]
+struct OverAligned;
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn DriverEntry(
+    driver_object: *mut DRIVER_OBJECT,
+    reg_path: *const UNICODE_STRING,
+) -> i32 {
+    KernelLogger::init(LevelFilter::Info).expect("Failed to initialize logger");
+    unsafe {
+        GLOBAL.init();
+    }
+
+    let driver = WduDriver::with_context(driver_object, reg_path, TestContext { value: 0xC0FFEE })
+        .unload(driver_unload)
+        .build()
+        .expect("Failed to build the driver");
+
+    test_device_extension(&driver);
+    test_typed_contexts(&driver);
+    test_children(&driver);
+    assert!(driver.context().value == 0xC0FFEE);
+    test_registry(&driver, reg_path);
+
+    0
+}
+
+fn test_device_extension(driver: &WduDriver<TestContext>) {
+    let mut device = WduDevice::default()
+        .build_with(driver, None, Extension { value: 0x1337 })
+        .expect("Failed to create the device");
+
+    assert!(device.extension::<Extension>().unwrap().value == 0x1337);
+    // Wrong type
+    assert!(device.extension::<u32>().is_none());
+    assert!(device.extension_ptr::<()>().is_none());
+
+    unsafe { device.extension_mut::<Extension>() }
+        .unwrap()
+        .value = 0xdead;
+    assert!(device.extension::<Extension>().unwrap().value == 0xdead);
+
+    // Devices not created by the library have no extension
+    assert!(WduDevice::default().extension::<Extension>().is_none());
+
+    let unit = WduDevice::default()
+        .build::<()>(driver, None)
+        .expect("Failed to create the device");
+    assert!(unit.extension::<()>().is_some());
+    assert!(unit.extension::<Extension>().is_none());
+    unit.delete();
+    assert!(DROPS.load(Ordering::SeqCst) == 0);
+
+    assert!(matches!(
+        WduDevice::default().build::<OverAligned>(driver, None),
+        Err(WduDeviceError::ExtensionAlignment { alignment: 64 })
+    ));
+}
+
+fn test_typed_contexts(driver: &WduDriver<TestContext>) {
+    // Stored in the driver extension, the handlers see the same contexts
+    driver
+        .allocate_context(DriverContext { value: 0x1337 })
+        .expect("Failed to allocate the driver context");
+    assert!(matches!(
+        driver.allocate_context(DriverContext { value: 0 }),
+        Err(WduContextError::AlreadyAttached)
+    ));
+    assert!(driver.typed_context::<u32>().is_none());
+
+    let device = WduDevice::default()
+        .build::<()>(driver, None)
+        .expect("Failed to create the device");
+    assert!(device.typed_context::<First>().is_none());
+
+    device
+        .allocate_context_with_cleanup(First, first_cleanup)
+        .expect("Failed to allocate the device context");
+    device
+        .allocate_context_with_cleanup(Second, second_cleanup)
+        .expect("Failed to allocate the device context");
+    device.allocate_context(0u32).unwrap();
+
+    *unsafe { device.typed_context_mut::<u32>() }.unwrap() = 0xdead;
+    assert!(*device.typed_context::<u32>().unwrap() == 0xdead);
+    assert!(device.typed_context::<u64>().is_none());
+
+    device.delete();
+    assert!(CLEANUPS.load(Ordering::SeqCst) == 2);
+
+    // Devices not created by the library can't hold contexts
+    assert!(matches!(
+        WduDevice::default().allocate_context(0u32),
+        Err(WduContextError::Unsupported)
+    ));
+}
+
+fn test_children(driver: &WduDriver<TestContext>) {
+    let device = WduDevice::default()
+        .build::<()>(driver, None)
+        .expect("Failed to create the device");
+
+    device.add_child(Child { order: 2 }).unwrap();
+    device.add_child(Child { order: 1 }).unwrap();
+    // Returned in its final location
+    let child = device.add_child(Child { order: 0 }).unwrap();
+    assert!(child.order == 0);
+
+    device.delete();
+    assert!(TEARDOWNS.load(Ordering::SeqCst) == 3);
+
+    assert!(matches!(
+        WduDevice::default().add_child(Child { order: 0 }),
+        Err(WduChildError::Unsupported)
+    ));
+
+    // Torn down after the unload routine
+    driver.add_child(Child { order: 3 }).unwrap();
+}
+
+fn test_registry(driver: &WduDriver<TestContext>, reg_path: *const UNICODE_STRING) {
+    // Owned copy, the path passed to DriverEntry isn't valid once it returns
+    assert!(*driver.registry_path() == unsafe { *reg_path });
+    assert!(driver.registry_path().as_slice().as_ptr() != unsafe { (*reg_path).Buffer });
+
+    // The test service doesn't need a Parameters key
+    let params = match driver.parameters() {
+        Ok(params) => params,
+        Err(WduRegistryError::NotFound) => return,
+        Err(err) => panic!("Failed to open Parameters: {:?}", err),
+    };
+
+    let missing = WduUnicodeStr::from_slice(MISSING_VALUE_UTF16.as_slice());
+    assert!(matches!(
+        params.query::<u32>(&missing),
+        Err(WduRegistryError::NotFound)
+    ));
+}
+
+fn driver_unload(driver: &WduDriver<TestContext>) {
+    assert!(TEARDOWNS.load(Ordering::SeqCst) == 3);
+
+    assert!(driver.context().value == 0xC0FFEE);
+
+    assert!(driver.typed_context::<DriverContext>().unwrap().value == 0x1337);
+
+    driver.device().delete();
+    // Dropped by delete
+    assert!(DROPS.load(Ordering::SeqCst) == 1);
+}